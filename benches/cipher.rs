@@ -0,0 +1,64 @@
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+#[cfg(feature = "parallel")]
+use tokio_chacha20::cipher::ParallelismConfig;
+use tokio_chacha20::cipher::StreamCipher;
+
+const BLOCK_SIZE: usize = 64;
+
+fn stream_cipher() -> StreamCipher {
+    let key = [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e,
+        0x0f, 0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d,
+        0x1e, 0x1f,
+    ];
+    let nonce = [
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x4a, 0x00, 0x00, 0x00, 0x00,
+    ];
+    StreamCipher::new(key, nonce)
+}
+
+fn encrypt_round(cipher: impl Fn() -> StreamCipher, buf: &mut [u8]) {
+    let mut cipher = cipher();
+    cipher.encrypt(buf);
+    black_box(buf);
+}
+
+fn bench_encrypt(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encrypt");
+    for blocks in [1, 2, 4, 8, 256, 320, 384, 512, 768, 1024, 2048] {
+        // Leave the last block partial
+        let mut buf = vec![0; BLOCK_SIZE * (blocks - 1) + 1];
+        group.throughput(Throughput::Bytes(buf.len() as u64));
+
+        group.bench_function(BenchmarkId::new("auto", blocks), |b| {
+            b.iter(|| encrypt_round(stream_cipher, &mut buf))
+        });
+        #[cfg(feature = "parallel")]
+        {
+            let serial = || {
+                stream_cipher().with_parallelism(ParallelismConfig {
+                    blocks_threshold: usize::MAX,
+                    ..Default::default()
+                })
+            };
+            group.bench_function(BenchmarkId::new("serial", blocks), |b| {
+                b.iter(|| encrypt_round(serial, &mut buf))
+            });
+            let par = || {
+                stream_cipher().with_parallelism(ParallelismConfig {
+                    blocks_threshold: 0,
+                    ..Default::default()
+                })
+            };
+            group.bench_function(BenchmarkId::new("par", blocks), |b| {
+                b.iter(|| encrypt_round(par, &mut buf))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_encrypt);
+criterion_main!(benches);
@@ -0,0 +1,20 @@
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio_chacha20::mac::poly1305_mac;
+
+fn bench_mac(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mac");
+    let key = core::array::from_fn(|i| i as u8);
+    for len in [16, 64, 1024, 16 * 1024, 64 * 1024] {
+        let msg = vec![0; len];
+        group.throughput(Throughput::Bytes(len as u64));
+        group.bench_function(BenchmarkId::from_parameter(len), |b| {
+            b.iter(|| poly1305_mac(key, black_box(&msg)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_mac);
+criterion_main!(benches);
@@ -0,0 +1,49 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_chacha20::stream::{ReadHalf, WriteHalf};
+
+const TOTAL_BYTES: usize = 4 * 1024 * 1024;
+const PIPE_BYTES: usize = 64 * 1024;
+
+/// Send `TOTAL_BYTES` through an encrypting writer in writes of `chunk` bytes and decrypt them on the other end
+async fn round_trip(key: [u8; 32], data: &'static [u8], chunk: usize) {
+    let (client, server) = tokio::io::duplex(PIPE_BYTES);
+    let mut w = WriteHalf::new(key, client);
+    let mut r = ReadHalf::new(key, server);
+
+    let write = tokio::spawn(async move {
+        for c in data.chunks(chunk) {
+            w.write_all(c).await.unwrap();
+        }
+        w.shutdown().await.unwrap();
+    });
+    let mut buf = vec![0; chunk];
+    let mut read = 0;
+    loop {
+        let n = r.read(&mut buf).await.unwrap();
+        if n == 0 {
+            break;
+        }
+        read += n;
+    }
+    write.await.unwrap();
+    assert_eq!(read, data.len());
+}
+
+fn bench_round_trip(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let key = [0x42; 32];
+    let data: &'static [u8] = vec![0; TOTAL_BYTES].leak();
+
+    let mut group = c.benchmark_group("round_trip");
+    group.throughput(Throughput::Bytes(TOTAL_BYTES as u64));
+    for chunk in [1024, 16 * 1024, 64 * 1024, 256 * 1024] {
+        group.bench_function(BenchmarkId::from_parameter(chunk), |b| {
+            b.iter(|| rt.block_on(round_trip(key, data, chunk)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_round_trip);
+criterion_main!(benches);
@@ -0,0 +1,33 @@
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use tokio_chacha20::codec::ChaCha20Codec;
+use tokio_util::codec::Decoder;
+
+const KEY: [u8; 32] = [0x42; 32];
+
+fuzz_target!(|input: (Vec<u8>, Vec<u8>)| {
+    let (wire, splits) = input;
+
+    // Feed the frames in chunks of the arbitrary sizes until the first error
+    let mut codec = ChaCha20Codec::new(KEY);
+    let mut src = BytesMut::new();
+    let mut pos: usize = 0;
+    for size in splits.iter().map(|s| *s as usize + 1).chain([usize::MAX]) {
+        let end = pos.saturating_add(size).min(wire.len());
+        src.extend_from_slice(&wire[pos..end]);
+        pos = end;
+        loop {
+            match codec.decode(&mut src) {
+                Ok(Some(_)) => (),
+                Ok(None) => break,
+                Err(_) => return,
+            }
+        }
+        if pos == wire.len() {
+            break;
+        }
+    }
+    let _ = codec.decode_eof(&mut src);
+});
@@ -0,0 +1,39 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tokio_chacha20::cursor::{DecryptCursor, TaggedDecryptCursor};
+
+const KEY: [u8; 32] = [0x42; 32];
+
+fuzz_target!(|input: (bool, Vec<u8>, Vec<u8>)| {
+    let (x, mut ciphertext, splits) = input;
+
+    // Feed the ciphertext in chunks of the arbitrary sizes
+    let mut de = match x {
+        true => DecryptCursor::new_x(KEY),
+        false => DecryptCursor::new(KEY),
+    }
+    .with_aad(b"aad");
+    let mut tagged = match x {
+        true => TaggedDecryptCursor::new_x(KEY, ciphertext.len() / 2),
+        false => TaggedDecryptCursor::new(KEY, ciphertext.len() / 2),
+    };
+    let mut pos: usize = 0;
+    let sizes = splits.iter().map(|s| *s as usize + 1);
+    for size in sizes.chain(std::iter::repeat(usize::MAX)) {
+        if pos == ciphertext.len() {
+            break;
+        }
+        let end = pos.saturating_add(size).min(ciphertext.len());
+        tagged.consume(&ciphertext[pos..end]).unwrap();
+        if let Some(i) = de.decrypt(&mut ciphertext[pos..end]).unwrap() {
+            assert!(i <= end - pos);
+        }
+        let _ = de.tag();
+        let _ = de.remaining_nonce_size().unwrap();
+        pos = end;
+    }
+    let _ = tagged.is_complete();
+    let _ = tagged.verify();
+    assert_eq!(de.bytes_consumed(), ciphertext.len() as u64);
+});
@@ -0,0 +1,67 @@
+#![no_main]
+
+use std::io::{self, BufRead, Read};
+
+use libfuzzer_sys::fuzz_target;
+use tokio_chacha20::sync::{BufReadHalf, ReadHalf};
+
+const KEY: [u8; 32] = [0x42; 32];
+
+/// Hand out `data` in reads of the arbitrary sizes
+struct Chunked<'a> {
+    data: &'a [u8],
+    splits: &'a [u8],
+    next: usize,
+}
+impl Read for Chunked<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = match self.splits.is_empty() {
+            true => usize::MAX,
+            false => self.splits[self.next % self.splits.len()] as usize + 1,
+        };
+        self.next += 1;
+        let n = size.min(buf.len()).min(self.data.len());
+        buf[..n].copy_from_slice(&self.data[..n]);
+        self.data = &self.data[n..];
+        Ok(n)
+    }
+}
+
+fuzz_target!(|input: (bool, Vec<u8>, Vec<u8>)| {
+    let (x, ciphertext, splits) = input;
+    let chunked = || Chunked {
+        data: &ciphertext,
+        splits: &splits,
+        next: 0,
+    };
+
+    let mut r = match x {
+        true => ReadHalf::new_x(KEY, chunked()),
+        false => ReadHalf::new(KEY, chunked()),
+    };
+    let mut plaintext = vec![];
+    // A truncated nonce is a valid outcome for arbitrary input
+    let res = r.read_to_end(&mut plaintext);
+
+    let mut r = match x {
+        true => BufReadHalf::new_x(KEY, chunked()),
+        false => BufReadHalf::new(KEY, chunked()),
+    }
+    .with_buf_capacity(7);
+    let mut buf_plaintext = vec![];
+    let buf_res = loop {
+        let buf = match r.fill_buf() {
+            Ok(buf) => buf,
+            Err(e) => break Err(e),
+        };
+        if buf.is_empty() {
+            break Ok(());
+        }
+        buf_plaintext.extend_from_slice(buf);
+        let n = buf.len();
+        r.consume(n);
+    };
+    if res.is_ok() && buf_res.is_ok() {
+        assert_eq!(plaintext, buf_plaintext);
+    }
+});
@@ -0,0 +1,92 @@
+use std::path::PathBuf;
+
+use clap::{Parser, ValueEnum};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio_chacha20::{
+    config::{Config, ConfigBuilder},
+    stream::{ReadHalf, ReadState, WriteHalf, WriteState},
+};
+
+/// Holds the base64 key unless `--key-file` is given
+const KEY_ENV: &str = "TOKIO_CHACHA20_KEY";
+
+/// Encrypt or decrypt data in the wire format of `tokio_chacha20`
+///
+/// The key is read from `--key-file` or else the environment variable `TOKIO_CHACHA20_KEY`,
+/// never from the command line where `ps` and the shell history would show it.
+#[derive(Debug, Parser)]
+struct Cli {
+    mode: Mode,
+    /// File holding the base64 key without padding
+    #[arg(short, long)]
+    key_file: Option<PathBuf>,
+    /// Use a 24-byte XChaCha20 nonce instead of a 12-byte ChaCha20 nonce
+    #[arg(short, long)]
+    x: bool,
+    /// Encrypt under a subkey of the key and the nonce so that the key itself never touches the data
+    ///
+    /// Both ends have to pass it, as the ciphertext does not decrypt under the key alone.
+    #[arg(long)]
+    subkey: bool,
+    /// Read from the file instead of stdin
+    #[arg(short, long)]
+    input: Option<PathBuf>,
+    /// Write to the file instead of stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Mode {
+    Encrypt,
+    Decrypt,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let config = match &cli.key_file {
+        Some(path) => ConfigBuilder::from_file(path)?.build()?,
+        None => Config::from_env(KEY_ENV)?,
+    };
+    let key = *config.key();
+
+    let r: Box<dyn AsyncRead + Unpin> = match &cli.input {
+        Some(path) => Box::new(tokio::fs::File::open(path).await?),
+        None => Box::new(tokio::io::stdin()),
+    };
+    let w: Box<dyn AsyncWrite + Unpin> = match &cli.output {
+        Some(path) => Box::new(tokio::fs::File::create(path).await?),
+        None => Box::new(tokio::io::stdout()),
+    };
+
+    match cli.mode {
+        Mode::Encrypt => {
+            let mut r = r;
+            let mut state = match cli.x {
+                true => WriteState::new_x(key),
+                false => WriteState::new(key),
+            };
+            if cli.subkey {
+                state = state.with_subkey();
+            }
+            let mut w = WriteHalf::from_state(state, w);
+            tokio::io::copy(&mut r, &mut w).await?;
+            w.shutdown().await?;
+        }
+        Mode::Decrypt => {
+            let mut state = match cli.x {
+                true => ReadState::new_x(key),
+                false => ReadState::new(key),
+            };
+            if cli.subkey {
+                state = state.with_subkey();
+            }
+            let mut r = ReadHalf::from_state(state, r);
+            let mut w = w;
+            tokio::io::copy(&mut r, &mut w).await?;
+            w.shutdown().await?;
+        }
+    }
+    Ok(())
+}
@@ -1,36 +1,326 @@
+#[cfg(feature = "parallel")]
+use std::sync::{mpsc, Arc, Mutex};
+
 use arrayvec::ArrayVec;
+#[cfg(feature = "parallel")]
 use rayon::prelude::*;
 
 use crate::{KEY_BYTES, NONCE_BYTES, X_NONCE_BYTES};
 
+#[cfg(feature = "simd")]
+mod simd;
+
 const CONSTANT: &[u8; 16] = b"expand 32-byte k";
-const BLOCK_SIZE: usize = 64;
+/// Keystream bytes of one ChaCha20 block
+pub const BLOCK_SIZE: usize = 64;
+const INITIAL_COUNTER: u32 = 1;
+/// Keystream bytes under one nonce before the block counter wraps
+pub const MAX_KEYSTREAM_BYTES: u64 = (u32::MAX - INITIAL_COUNTER + 1) as u64 * BLOCK_SIZE as u64;
+/// Rounds of the standard ChaCha20
+pub const ROUNDS: usize = 20;
+/// Blocks computed side by side without SIMD
+const INTERLEAVED_BLOCKS: usize = 4;
+#[cfg(feature = "parallel")]
 const PAR_OUTER_CHUNK_SIZE: usize = 64;
+#[cfg(feature = "parallel")]
 const PAR_BLOCKS_THRESHOLD: usize = 320;
+/// Smallest buffer timed by [`ParallelismConfig::calibrated`], split into two rayon tasks
+#[cfg(feature = "parallel")]
+const CALIBRATION_MIN_BLOCKS: usize = 2 * PAR_OUTER_CHUNK_SIZE;
+/// Largest buffer timed by [`ParallelismConfig::calibrated`] and the threshold if rayon never wins
+#[cfg(feature = "parallel")]
+const CALIBRATION_MAX_BLOCKS: usize = 4096;
+/// Timings per buffer size of which the fastest counts
+#[cfg(feature = "parallel")]
+const CALIBRATION_ROUNDS: usize = 3;
+/// Rayon has to be this many percent faster to count as a win over timing noise
+#[cfg(feature = "parallel")]
+const CALIBRATION_MARGIN_PERCENT: u32 = 10;
+
+/// How [`StreamCipher`] spreads a large buffer over rayon
+#[cfg(feature = "parallel")]
+#[derive(Debug, Clone)]
+pub struct ParallelismConfig {
+    /// Buffers spanning more blocks than this are encrypted in parallel
+    ///
+    /// [`ParallelismConfig::calibrated`] times it on this machine.
+    pub blocks_threshold: usize,
+    /// Amount of blocks encrypted by one rayon task
+    pub chunk_blocks: usize,
+    /// Run on this pool instead of the global rayon pool
+    pub thread_pool: Option<Arc<rayon::ThreadPool>>,
+    /// Keep computing this many blocks of keystream ahead in the background; `0` turns it off
+    ///
+    /// The keystream of the next call is then ready while the caller is waiting on I/O.
+    pub prefetch_blocks: usize,
+}
+#[cfg(feature = "parallel")]
+impl Default for ParallelismConfig {
+    fn default() -> Self {
+        Self {
+            blocks_threshold: PAR_BLOCKS_THRESHOLD,
+            chunk_blocks: PAR_OUTER_CHUNK_SIZE,
+            thread_pool: None,
+            prefetch_blocks: 0,
+        }
+    }
+}
+#[cfg(feature = "parallel")]
+impl PartialEq for ParallelismConfig {
+    fn eq(&self, other: &Self) -> bool {
+        let same_pool = match (&self.thread_pool, &other.thread_pool) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (None, None) => true,
+            _ => false,
+        };
+        self.blocks_threshold == other.blocks_threshold
+            && self.chunk_blocks == other.chunk_blocks
+            && same_pool
+            && self.prefetch_blocks == other.prefetch_blocks
+    }
+}
+#[cfg(feature = "parallel")]
+impl Eq for ParallelismConfig {}
+
+#[cfg(feature = "parallel")]
+impl ParallelismConfig {
+    /// The defaults with [`Self::blocks_threshold`] set to the serial/parallel crossover on this machine
+    ///
+    /// Buffers of doubling sizes are encrypted both ways on the global rayon pool;
+    /// the threshold sits just below the first size where rayon wins.
+    /// A single-threaded pool never wins, so it gets `usize::MAX`.
+    ///
+    /// The timing takes a few milliseconds, so run it once at startup and share the result.
+    /// On a rayon thread it would race its own pool, so the defaults come back untimed.
+    pub fn calibrated() -> Self {
+        let blocks_threshold = match rayon::current_thread_index() {
+            Some(_) => PAR_BLOCKS_THRESHOLD,
+            None => calibrate_blocks_threshold(),
+        };
+        Self {
+            blocks_threshold,
+            ..Default::default()
+        }
+    }
+}
+#[cfg(feature = "parallel")]
+fn calibrate_blocks_threshold() -> usize {
+    if rayon::current_num_threads() == 1 {
+        return usize::MAX;
+    }
+    let block = ChaCha20::new([0; KEY_BYTES], [0; NONCE_BYTES], INITIAL_COUNTER);
+    let mut buf = vec![0; CALIBRATION_MAX_BLOCKS * BLOCK_SIZE];
+    let fastest = |f: &mut dyn FnMut()| {
+        (0..CALIBRATION_ROUNDS)
+            .map(|_| {
+                let start = std::time::Instant::now();
+                f();
+                start.elapsed()
+            })
+            .min()
+            .unwrap()
+    };
+
+    // Wake the rayon threads up so that their start-up is not timed
+    par_xor_full_blocks(&block, PAR_OUTER_CHUNK_SIZE, &mut buf);
+
+    let mut blocks = CALIBRATION_MIN_BLOCKS;
+    while blocks <= CALIBRATION_MAX_BLOCKS {
+        let buf = &mut buf[..blocks * BLOCK_SIZE];
+        let serial = fastest(&mut || xor_full_blocks(&block, 0, buf));
+        let par = fastest(&mut || par_xor_full_blocks(&block, PAR_OUTER_CHUNK_SIZE, buf));
+        if par * (100 + CALIBRATION_MARGIN_PERCENT) < serial * 100 {
+            return blocks - 1;
+        }
+        blocks *= 2;
+    }
+    CALIBRATION_MAX_BLOCKS
+}
+
+/// Keystream computed ahead by [`ParallelismConfig::prefetch_blocks`]
+///
+/// Only a cache: clones start empty and equality ignores it.
+#[cfg(feature = "parallel")]
+#[derive(Debug, Default)]
+struct Prefetch {
+    /// Keystream from the block with the counter `.0` on, of which `.2` bytes are used up
+    ready: Option<(u32, Keystream, usize)>,
+    /// Keystream from the block with the counter `.0` on still being computed
+    ///
+    /// The mutex is never locked and only keeps the cipher `Sync`.
+    pending: Option<(u32, Mutex<mpsc::Receiver<Keystream>>)>,
+}
+#[cfg(feature = "parallel")]
+impl Prefetch {
+    /// XOR the prefetched keystream into the leading whole blocks of `buf` and return the amount of them
+    ///
+    /// Never waits: if the keystream is still being computed, the caller computes its own
+    /// and whatever the job has left is picked up by a later call.
+    fn xor<const R: usize>(&mut self, block: &ChaCha20<R>, buf: &mut [u8]) -> usize {
+        if self.ready.is_none() {
+            if let Some((counter, job)) = &mut self.pending {
+                match job.get_mut().unwrap().try_recv() {
+                    Ok(ks) => {
+                        self.ready = Some((*counter, ks, 0));
+                        self.pending = None;
+                    }
+                    Err(mpsc::TryRecvError::Empty) => return 0,
+                    // A dropped job leaves nothing to use
+                    Err(mpsc::TryRecvError::Disconnected) => self.pending = None,
+                }
+            }
+        }
+        let Some((counter, ks, used)) = &mut self.ready else {
+            return 0;
+        };
+        // Blocks computed by the caller in the meantime, e.g. the one a partial write ended in
+        let behind = block.counter().wrapping_sub(*counter) as usize;
+        if (ks.0.len() - *used) / BLOCK_SIZE < behind {
+            // The stream has been moved elsewhere
+            self.ready = None;
+            self.pending = None;
+            return 0;
+        }
+        *counter = block.counter();
+        *used += behind * BLOCK_SIZE;
+
+        let blocks = (buf.len() / BLOCK_SIZE).min((ks.0.len() - *used) / BLOCK_SIZE);
+        xor(&mut buf[..blocks * BLOCK_SIZE], &ks.0[*used..]);
+        *counter = counter.wrapping_add(blocks as u32);
+        *used += blocks * BLOCK_SIZE;
+        if *used == ks.0.len() {
+            self.ready = None;
+        }
+        blocks
+    }
+
+    /// Start computing the blocks following whatever is ready if nothing is in flight
+    fn refill<const R: usize>(&mut self, block: &ChaCha20<R>, par: &ParallelismConfig) {
+        if self.pending.is_some() {
+            return;
+        }
+        let counter = match &self.ready {
+            Some((counter, ks, used)) => {
+                counter.wrapping_add(((ks.0.len() - used) / BLOCK_SIZE) as u32)
+            }
+            None => block.counter(),
+        };
+        let mut block = block.clone();
+        block.set_counter(counter);
+        let blocks = par.prefetch_blocks;
+        let (tx, rx) = mpsc::sync_channel(1);
+        let job = move || {
+            let mut ks = Keystream(vec![0; blocks * BLOCK_SIZE]);
+            xor_full_blocks(&block, 0, &mut ks.0);
+            let _ = tx.send(ks);
+        };
+        match &par.thread_pool {
+            Some(pool) => pool.spawn(job),
+            None => rayon::spawn(job),
+        }
+        self.pending = Some((counter, Mutex::new(rx)));
+    }
+}
+
+/// Prefetched keystream, wiped wherever it is dropped, the channel included
+#[cfg(feature = "parallel")]
+struct Keystream(Vec<u8>);
+#[cfg(feature = "parallel")]
+impl core::fmt::Debug for Keystream {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("Keystream(<redacted>)")
+    }
+}
+#[cfg(feature = "parallel")]
+impl Drop for Keystream {
+    fn drop(&mut self) {
+        crate::key::zeroize(&mut self.0);
+    }
+}
+
+#[cfg(feature = "parallel")]
+impl Clone for Prefetch {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+#[cfg(feature = "parallel")]
+impl PartialEq for Prefetch {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+#[cfg(feature = "parallel")]
+impl Eq for Prefetch {}
 
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct StreamCipher {
-    block: ChaCha20,
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StreamCipher<const R: usize = ROUNDS> {
+    block: ChaCha20<R>,
     leftover: Option<(State, usize)>,
+    /// Derives the subkey of XChaCha20 on rekey
+    h_nonce: Option<[u8; 16]>,
+    #[cfg(feature = "parallel")]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    par: ParallelismConfig,
+    /// Boxed to keep the cipher small when prefetching is off
+    #[cfg(feature = "parallel")]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    prefetch: Option<Box<Prefetch>>,
 }
 impl StreamCipher {
     pub fn new(key: [u8; KEY_BYTES], nonce: [u8; NONCE_BYTES]) -> Self {
-        let block = ChaCha20::new(key, nonce, 1);
+        Self::with_rounds(key, nonce)
+    }
+    pub fn new_x(key: [u8; KEY_BYTES], nonce: [u8; X_NONCE_BYTES]) -> Self {
+        Self::with_rounds_x(key, nonce)
+    }
+}
+impl<const R: usize> StreamCipher<R> {
+    /// [`StreamCipher::new`] with `R` rounds, e.g. `StreamCipher::<12>::with_rounds`
+    pub fn with_rounds(key: [u8; KEY_BYTES], nonce: [u8; NONCE_BYTES]) -> Self {
+        let block = ChaCha20::with_rounds(key, nonce, INITIAL_COUNTER);
         Self {
             block,
             leftover: None,
+            h_nonce: None,
+            #[cfg(feature = "parallel")]
+            par: ParallelismConfig::default(),
+            #[cfg(feature = "parallel")]
+            prefetch: None,
         }
     }
-    pub fn new_x(key: [u8; KEY_BYTES], nonce: [u8; X_NONCE_BYTES]) -> Self {
-        let subkey = hchacha20(key, nonce[..16].try_into().unwrap());
-        Self::new(subkey, chacha20_nonce_from_xnonce(nonce))
+    /// [`StreamCipher::new_x`] with `R` rounds
+    ///
+    /// The subkey is always derived with the 20 rounds of HChaCha20.
+    pub fn with_rounds_x(key: [u8; KEY_BYTES], nonce: [u8; X_NONCE_BYTES]) -> Self {
+        let h_nonce = nonce[..16].try_into().unwrap();
+        let subkey = hchacha20(key, h_nonce);
+        let mut cipher = Self::with_rounds(subkey, chacha20_nonce_from_xnonce(nonce));
+        cipher.h_nonce = Some(h_nonce);
+        cipher
+    }
+
+    #[cfg(feature = "parallel")]
+    pub fn with_parallelism(mut self, par: ParallelismConfig) -> Self {
+        self.set_parallelism(par);
+        self
+    }
+    #[cfg(feature = "parallel")]
+    pub fn set_parallelism(&mut self, par: ParallelismConfig) {
+        assert!(par.chunk_blocks > 0);
+        self.par = par;
+        self.prefetch = None;
     }
 
     pub fn encrypt(&mut self, buf: &mut [u8]) {
-        let par = match PAR_BLOCKS_THRESHOLD < buf.chunks(BLOCK_SIZE).count() {
+        #[cfg(feature = "parallel")]
+        let par = match self.par.blocks_threshold < buf.chunks(BLOCK_SIZE).count() {
             true => ParOrNot::Parallel,
             false => ParOrNot::Serial,
         };
+        #[cfg(not(feature = "parallel"))]
+        let par = ParOrNot::Serial;
         self.encrypt_(buf, par)
     }
 
@@ -45,7 +335,7 @@ impl StreamCipher {
             pos += size;
 
             let next = next + size;
-            if next != state.byte_vec().len() {
+            if next != BLOCK_SIZE {
                 self.leftover = Some((state, next));
                 return;
             }
@@ -54,33 +344,33 @@ impl StreamCipher {
 
         let buf = &mut buf[pos..];
 
-        // Milk the blocks
-        let xor_full_block = |(i, c): (usize, &mut [u8])| {
-            let state = self.block.next_nth_block(i as u32);
-            let size = xor(c, &state.byte_vec());
-            assert_eq!(size, state.byte_vec().len());
-            assert_eq!(size, c.len());
+        // Take what has been computed ahead
+        #[cfg(feature = "parallel")]
+        let buf = {
+            let blocks = match &mut self.prefetch {
+                Some(prefetch) => prefetch.xor(&self.block, buf),
+                None => 0,
+            };
+            self.block.increment_counter(blocks as u32);
+            &mut buf[blocks * BLOCK_SIZE..]
         };
+
+        // Milk the blocks
+        let block = &self.block;
         match par {
+            #[cfg(feature = "parallel")]
             ParOrNot::Parallel => {
                 // buf.par_chunks_exact_mut(BLOCK_SIZE)
                 //     .enumerate()
                 //     .for_each(xor_full_block);
 
-                buf.par_chunks_mut(BLOCK_SIZE * PAR_OUTER_CHUNK_SIZE)
-                    .enumerate()
-                    .for_each(|(i, c)| {
-                        c.chunks_exact_mut(BLOCK_SIZE)
-                            .enumerate()
-                            .map(|(j, c)| (j + i * PAR_OUTER_CHUNK_SIZE, c))
-                            .for_each(xor_full_block);
-                    });
-            }
-            ParOrNot::Serial => {
-                buf.chunks_exact_mut(BLOCK_SIZE)
-                    .enumerate()
-                    .for_each(xor_full_block);
+                let chunk_blocks = self.par.chunk_blocks;
+                match &self.par.thread_pool {
+                    Some(pool) => pool.install(|| par_xor_full_blocks(block, chunk_blocks, buf)),
+                    None => par_xor_full_blocks(block, chunk_blocks, buf),
+                }
             }
+            ParOrNot::Serial => xor_full_blocks(block, 0, buf),
         }
 
         // Last `buf` chuck
@@ -93,30 +383,239 @@ impl StreamCipher {
         }
         self.block
             .increment_counter(buf.chunks(BLOCK_SIZE).count() as u32);
+        #[cfg(feature = "parallel")]
+        if self.par.prefetch_blocks != 0 {
+            let prefetch = self.prefetch.get_or_insert_default();
+            prefetch.refill(&self.block, &self.par);
+        }
+    }
+
+    /// Move the keystream to the byte offset `pos` from the start of the stream
+    pub fn seek(&mut self, pos: u64) {
+        let blocks = pos / BLOCK_SIZE as u64;
+        let next = (pos % BLOCK_SIZE as u64) as usize;
+        self.block
+            .set_counter(INITIAL_COUNTER.wrapping_add(blocks as u32));
+        self.leftover = None;
+        if next != 0 {
+            let state = self.block.next_nth_block(0);
+            self.leftover = Some((state, next));
+            self.block.increment_counter(1);
+        }
+    }
+
+    /// Discard the next `n` keystream bytes
+    pub fn skip(&mut self, n: u64) {
+        self.seek(self.position() + n);
     }
 
-    pub fn block(&self) -> &ChaCha20 {
+    /// Switch to `key` keeping the nonce and the keystream position
+    pub fn rekey(&mut self, key: [u8; KEY_BYTES]) {
+        let pos = self.position();
+        let key = match self.h_nonce {
+            Some(h_nonce) => hchacha20(key, h_nonce),
+            None => key,
+        };
+        self.block = ChaCha20::with_rounds(key, self.block.nonce(), INITIAL_COUNTER);
+        #[cfg(feature = "parallel")]
+        {
+            self.prefetch = None;
+        }
+        self.seek(pos);
+    }
+
+    /// Offset of the next keystream byte from the start of the stream
+    ///
+    /// The inverse of [`Self::seek`].
+    pub fn position(&self) -> u64 {
+        let blocks = self.block.counter().wrapping_sub(INITIAL_COUNTER) as u64;
+        let unused = match &self.leftover {
+            Some((_, next)) => (BLOCK_SIZE - next) as u64,
+            None => 0,
+        };
+        blocks * BLOCK_SIZE as u64 - unused
+    }
+
+    /// Keystream bytes left before the block counter wraps
+    pub fn remaining(&self) -> u64 {
+        MAX_KEYSTREAM_BYTES - self.position()
+    }
+
+    pub fn block(&self) -> &ChaCha20<R> {
         &self.block
     }
 }
 
+/// Implementation of the block function picked for this CPU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// 16 blocks at a time with AVX-512F
+    Avx512,
+    /// 8 blocks at a time with AVX2
+    Avx2,
+    /// 4 blocks at a time with SSE2
+    Sse2,
+    /// 4 blocks at a time with NEON
+    Neon,
+    /// One block at a time
+    Scalar,
+}
+impl Backend {
+    /// Detected once on first use
+    pub fn current() -> Self {
+        #[cfg(feature = "simd")]
+        {
+            static BACKEND: std::sync::OnceLock<Backend> = std::sync::OnceLock::new();
+            *BACKEND.get_or_init(simd::detect)
+        }
+        #[cfg(not(feature = "simd"))]
+        Backend::Scalar
+    }
+}
+
 enum ParOrNot {
+    #[cfg(feature = "parallel")]
     Parallel,
     Serial,
 }
 
+/// XOR the keystream starting from the `n`th next block into the whole blocks of `buf`
+fn xor_full_blocks<const R: usize>(block: &ChaCha20<R>, mut n: usize, buf: &mut [u8]) {
+    #[cfg(feature = "simd")]
+    let buf = simd::xor_chunks(Backend::current(), block, &mut n, buf);
+    let mut chunks = buf.chunks_exact_mut(INTERLEAVED_BLOCKS * BLOCK_SIZE);
+    for c in &mut chunks {
+        xor_interleaved_blocks(block, n as u32, c.try_into().unwrap());
+        n += INTERLEAVED_BLOCKS;
+    }
+    for c in chunks.into_remainder().chunks_exact_mut(BLOCK_SIZE) {
+        xor_block(block, n as u32, c.try_into().unwrap());
+        n += 1;
+    }
+}
+
+/// [`xor_full_blocks`] over rayon tasks of `chunk_blocks` blocks each
+#[cfg(feature = "parallel")]
+fn par_xor_full_blocks<const R: usize>(block: &ChaCha20<R>, chunk_blocks: usize, buf: &mut [u8]) {
+    buf.par_chunks_mut(BLOCK_SIZE * chunk_blocks)
+        .enumerate()
+        .for_each(|(i, c)| xor_full_blocks(block, i * chunk_blocks, c));
+}
+
+/// XOR the keystream of the `n`th next block into `buf` word by word
+fn xor_block<const R: usize>(block: &ChaCha20<R>, n: u32, buf: &mut [u8; BLOCK_SIZE]) {
+    let initial = block.next_nth_state(n);
+    let mut x = initial;
+    x.rounds::<R>();
+    for ((b, x), initial) in buf
+        .chunks_exact_mut(size_of::<u32>())
+        .zip(x.vec())
+        .zip(initial.vec())
+    {
+        xor_word(b, x.wrapping_add(*initial));
+    }
+}
+
+/// XOR the keystream of the [`INTERLEAVED_BLOCKS`] blocks starting from the `n`th next block into `buf`
+///
+/// The same word of every block sits side by side so that the rounds auto-vectorize.
+fn xor_interleaved_blocks<const R: usize>(
+    block: &ChaCha20<R>,
+    n: u32,
+    buf: &mut [u8; INTERLEAVED_BLOCKS * BLOCK_SIZE],
+) {
+    let state = block.next_nth_state(n);
+    let mut x: [[u32; INTERLEAVED_BLOCKS]; 16] = core::array::from_fn(|i| [state.vec()[i]; _]);
+    for (lane, counter) in x[12].iter_mut().enumerate() {
+        *counter = counter.wrapping_add(lane as u32);
+    }
+    let initial = x;
+
+    for _ in 0..R / 2 {
+        interleaved_quarter_round(&mut x, 0, 4, 8, 12);
+        interleaved_quarter_round(&mut x, 1, 5, 9, 13);
+        interleaved_quarter_round(&mut x, 2, 6, 10, 14);
+        interleaved_quarter_round(&mut x, 3, 7, 11, 15);
+        interleaved_quarter_round(&mut x, 0, 5, 10, 15);
+        interleaved_quarter_round(&mut x, 1, 6, 11, 12);
+        interleaved_quarter_round(&mut x, 2, 7, 8, 13);
+        interleaved_quarter_round(&mut x, 3, 4, 9, 14);
+    }
+
+    for (lane, c) in buf.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+        for ((x, initial), b) in x
+            .iter()
+            .zip(&initial)
+            .zip(c.chunks_exact_mut(size_of::<u32>()))
+        {
+            xor_word(b, x[lane].wrapping_add(initial[lane]));
+        }
+    }
+}
+
+#[inline(always)]
+fn interleaved_quarter_round(
+    x: &mut [[u32; INTERLEAVED_BLOCKS]; 16],
+    a: usize,
+    b: usize,
+    c: usize,
+    d: usize,
+) {
+    let (mut a_v, mut b_v, mut c_v, mut d_v) = (x[a], x[b], x[c], x[d]);
+    // 1
+    add_xor_rotate(&mut a_v, &b_v, &mut d_v, 16);
+    // 2
+    add_xor_rotate(&mut c_v, &d_v, &mut b_v, 12);
+    // 3
+    add_xor_rotate(&mut a_v, &b_v, &mut d_v, 8);
+    // 4
+    add_xor_rotate(&mut c_v, &d_v, &mut b_v, 7);
+    (x[a], x[b], x[c], x[d]) = (a_v, b_v, c_v, d_v);
+}
+
+/// `a += b; d ^= a; d <<<= n` on every block
+#[inline(always)]
+fn add_xor_rotate(
+    a: &mut [u32; INTERLEAVED_BLOCKS],
+    b: &[u32; INTERLEAVED_BLOCKS],
+    d: &mut [u32; INTERLEAVED_BLOCKS],
+    n: u32,
+) {
+    a.iter_mut()
+        .zip(b)
+        .for_each(|(a, b)| *a = a.wrapping_add(*b));
+    d.iter_mut()
+        .zip(a.iter())
+        .for_each(|(d, a)| *d = (*d ^ a).rotate_left(n));
+}
+
 fn xor(buf: &mut [u8], other: &[u8]) -> usize {
     let size = buf.len().min(other.len());
 
-    let vec = other.iter().take(size).copied();
-    buf.iter_mut()
-        .take(size)
-        .zip(vec)
-        .for_each(|(b, s)| *b ^= s);
+    // Whole words first and then the byte tail
+    let mut buf_words = buf[..size].chunks_exact_mut(size_of::<u64>());
+    let mut other_words = other[..size].chunks_exact(size_of::<u64>());
+    for (b, o) in (&mut buf_words).zip(&mut other_words) {
+        let x =
+            u64::from_ne_bytes(b.try_into().unwrap()) ^ u64::from_ne_bytes(o.try_into().unwrap());
+        b.copy_from_slice(&x.to_ne_bytes());
+    }
+    buf_words
+        .into_remainder()
+        .iter_mut()
+        .zip(other_words.remainder())
+        .for_each(|(b, o)| *b ^= o);
 
     size
 }
 
+/// XOR the keystream `word` into the 4 bytes of `buf`
+#[inline(always)]
+fn xor_word(buf: &mut [u8], word: u32) {
+    let x = u32::from_le_bytes(buf.try_into().unwrap()) ^ word;
+    buf.copy_from_slice(&x.to_le_bytes());
+}
+
 pub(crate) fn chacha20_nonce_from_xnonce(nonce: [u8; X_NONCE_BYTES]) -> [u8; NONCE_BYTES] {
     let mut chacha20_nonce = [0; NONCE_BYTES];
     chacha20_nonce[4..].copy_from_slice(&nonce[16..]);
@@ -165,15 +664,32 @@ fn test_h_cha_cha_20() {
     );
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct ChaCha20 {
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChaCha20<const R: usize = ROUNDS> {
     constant: [u32; 4],
     nonce: [u32; 3],
     key: [u32; 8],
     counter: u32,
 }
+impl<const R: usize> core::fmt::Debug for ChaCha20<R> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ChaCha20")
+            .field("nonce", &self.nonce)
+            .field("key", &"<redacted>")
+            .field("counter", &self.counter)
+            .finish()
+    }
+}
 impl ChaCha20 {
     pub fn new(key: [u8; KEY_BYTES], nonce: [u8; NONCE_BYTES], counter: u32) -> Self {
+        Self::with_rounds(key, nonce, counter)
+    }
+}
+impl<const R: usize> ChaCha20<R> {
+    /// [`ChaCha20::new`] with `R` rounds, e.g. 8 or 12 for the reduced-round variants
+    pub fn with_rounds(key: [u8; KEY_BYTES], nonce: [u8; NONCE_BYTES], counter: u32) -> Self {
+        const { assert!(R.is_multiple_of(2)) };
         let constant = [
             u32::from_le_bytes(CONSTANT[0..4].try_into().unwrap()),
             u32::from_le_bytes(CONSTANT[4..8].try_into().unwrap()),
@@ -222,13 +738,21 @@ impl ChaCha20 {
         let mut state = self.next_nth_state(n);
         let mut working_state = state;
 
-        working_state.inner_block_10_rounds();
+        working_state.rounds::<R>();
 
         state.add(working_state.vec());
 
         state
     }
 
+    pub fn counter(&self) -> u32 {
+        self.counter
+    }
+
+    pub fn set_counter(&mut self, counter: u32) {
+        self.counter = counter;
+    }
+
     pub fn increment_counter(&mut self, n: u32) {
         self.counter = self.counter.wrapping_add(n);
     }
@@ -245,6 +769,7 @@ impl ChaCha20 {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct State {
     vec: [u32; 16],
 }
@@ -258,8 +783,11 @@ impl State {
     }
 
     pub fn byte_vec(&self) -> [u8; 64] {
-        let vec: ArrayVec<u8, 64> = self.vec.iter().flat_map(|n| n.to_le_bytes()).collect();
-        vec.as_slice().try_into().unwrap()
+        let mut bytes = [0; 64];
+        for (b, word) in bytes.chunks_exact_mut(size_of::<u32>()).zip(&self.vec) {
+            b.copy_from_slice(&word.to_le_bytes());
+        }
+        bytes
     }
 
     pub fn quarter_round(&mut self, a: usize, b: usize, c: usize, d: usize) {
@@ -293,7 +821,11 @@ impl State {
     }
 
     pub fn inner_block_10_rounds(&mut self) {
-        for _ in 0..10 {
+        self.rounds::<ROUNDS>();
+    }
+    /// Run `R` rounds as `R / 2` double rounds
+    pub fn rounds<const R: usize>(&mut self) {
+        for _ in 0..R / 2 {
             self.inner_block();
         }
     }
@@ -449,15 +981,82 @@ mod tests {
         cipher.encrypt(&mut buf[..BLOCK_SIZE]);
         cipher.encrypt(&mut buf[BLOCK_SIZE..]);
         assert_eq!(buf, ciphertext);
+
+        let mut cipher = StreamCipher::new(key, nonce);
+        for pos in [BLOCK_SIZE + 3, 0, BLOCK_SIZE, 7] {
+            let mut buf = *plaintext;
+            cipher.seek(pos as u64);
+            assert_eq!(cipher.position(), pos as u64);
+            cipher.encrypt(&mut buf[pos..]);
+            assert_eq!(buf[pos..], ciphertext[pos..]);
+            assert_eq!(cipher.position(), plaintext.len() as u64);
+        }
+
+        let mut buf = *plaintext;
+        let mut cipher = StreamCipher::new(key, nonce);
+        cipher.encrypt(&mut buf[..3]);
+        cipher.skip(BLOCK_SIZE as u64);
+        cipher.encrypt(&mut buf[BLOCK_SIZE + 3..]);
+        assert_eq!(buf[BLOCK_SIZE + 3..], ciphertext[BLOCK_SIZE + 3..]);
     }
-}
 
-#[cfg(test)]
-mod benches {
-    use std::hint::black_box;
+    #[test]
+    fn test_reduced_rounds() {
+        fn keystream<const R: usize>() -> [u8; 64] {
+            ChaCha20::<R>::with_rounds([0; KEY_BYTES], [0; NONCE_BYTES], 0)
+                .next_nth_block(0)
+                .byte_vec()
+        }
+        assert_eq!(
+            keystream::<8>()[..16],
+            [
+                0x3e, 0x00, 0xef, 0x2f, 0x89, 0x5f, 0x40, 0xd6, 0x7f, 0x5b, 0xb8, 0xe8, 0x1f, 0x09,
+                0xa5, 0xa1,
+            ]
+        );
+        assert_eq!(
+            keystream::<12>()[..16],
+            [
+                0x9b, 0xf4, 0x9a, 0x6a, 0x07, 0x55, 0xf9, 0x53, 0x81, 0x1f, 0xce, 0x12, 0x5f, 0x26,
+                0x83, 0xd5,
+            ]
+        );
+        assert_eq!(
+            keystream::<ROUNDS>()[..16],
+            [
+                0x76, 0xb8, 0xe0, 0xad, 0xa0, 0xf1, 0x3d, 0x90, 0x40, 0x5d, 0x6a, 0xe5, 0x53, 0x86,
+                0xbd, 0x28,
+            ]
+        );
+
+        // Every backend honors the rounds
+        let mut cipher = StreamCipher::<8>::with_rounds([0; KEY_BYTES], [0; NONCE_BYTES]);
+        let block = cipher.block().clone();
+        let mut buf = [0; 64 * BLOCK_SIZE + 1];
+        cipher.encrypt(&mut buf);
+        for (i, c) in buf.chunks(BLOCK_SIZE).enumerate() {
+            let block = block.next_nth_block(i as u32).byte_vec();
+            assert_eq!(c, &block[..c.len()]);
+        }
+    }
 
-    use test::Bencher;
+    #[test]
+    fn test_interleaved_blocks() {
+        let key = core::array::from_fn(|i| i as u8);
+        let nonce = [0, 0, 0, 0, 0, 0, 0, 0x4a, 0, 0, 0, 0];
+
+        // Wrap the counter within the interleaved blocks
+        let block = ChaCha20::new(key, nonce, u32::MAX - 1);
+        let mut buf = [0; INTERLEAVED_BLOCKS * BLOCK_SIZE];
+        xor_interleaved_blocks(&block, 1, &mut buf);
+        for (i, c) in buf.chunks_exact(BLOCK_SIZE).enumerate() {
+            assert_eq!(c, block.next_nth_block(i as u32 + 1).byte_vec());
+        }
+    }
+}
 
+#[cfg(all(test, feature = "parallel"))]
+mod parallel_tests {
     use super::*;
 
     fn stream_cipher() -> StreamCipher {
@@ -475,13 +1074,6 @@ mod benches {
     fn encrypt_round(buf: &mut [u8], par: ParOrNot) {
         let mut cipher = stream_cipher();
         cipher.encrypt_(buf, par);
-        black_box(buf);
-    }
-
-    fn encrypt_round_auto(buf: &mut [u8]) {
-        let mut cipher = stream_cipher();
-        cipher.encrypt(buf);
-        black_box(buf);
     }
 
     #[test]
@@ -493,245 +1085,111 @@ mod benches {
         assert_eq!(buf_s, buf_p);
     }
 
-    #[bench]
-    fn bench_encrypt_0001_block(b: &mut Bencher) {
-        let mut buf = [0];
-        b.iter(|| {
-            encrypt_round_auto(&mut buf);
-        });
-    }
-    #[bench]
-    fn bench_encrypt_0001_block_serial(b: &mut Bencher) {
-        let mut buf = [0];
-        b.iter(|| {
-            encrypt_round(&mut buf, ParOrNot::Serial);
-        });
-    }
-    #[bench]
-    fn bench_encrypt_0001_block_par(b: &mut Bencher) {
-        let mut buf = [0];
-        b.iter(|| {
-            encrypt_round(&mut buf, ParOrNot::Parallel);
-        });
-    }
-
-    #[bench]
-    fn bench_encrypt_0002_blocks(b: &mut Bencher) {
-        let mut buf = [0; BLOCK_SIZE + 1];
-        b.iter(|| {
-            encrypt_round_auto(&mut buf);
-        });
-    }
-    #[bench]
-    fn bench_encrypt_0002_blocks_serial(b: &mut Bencher) {
-        let mut buf = [0; BLOCK_SIZE + 1];
-        b.iter(|| {
-            encrypt_round(&mut buf, ParOrNot::Serial);
-        });
-    }
-    #[bench]
-    fn bench_encrypt_0002_blocks_par(b: &mut Bencher) {
-        let mut buf = [0; BLOCK_SIZE + 1];
-        b.iter(|| {
-            encrypt_round(&mut buf, ParOrNot::Parallel);
-        });
-    }
-
-    #[bench]
-    fn bench_encrypt_0004_blocks(b: &mut Bencher) {
-        let mut buf = [0; BLOCK_SIZE * 3 + 1];
-        b.iter(|| {
-            encrypt_round_auto(&mut buf);
-        });
-    }
-    #[bench]
-    fn bench_encrypt_0004_blocks_serial(b: &mut Bencher) {
-        let mut buf = [0; BLOCK_SIZE * 3 + 1];
-        b.iter(|| {
-            encrypt_round(&mut buf, ParOrNot::Serial);
-        });
-    }
-    #[bench]
-    fn bench_encrypt_0004_blocks_par(b: &mut Bencher) {
-        let mut buf = [0; BLOCK_SIZE * 3 + 1];
-        b.iter(|| {
-            encrypt_round(&mut buf, ParOrNot::Parallel);
-        });
-    }
-
-    #[bench]
-    fn bench_encrypt_0008_blocks(b: &mut Bencher) {
-        let mut buf = [0; BLOCK_SIZE * 7 + 1];
-        b.iter(|| {
-            encrypt_round_auto(&mut buf);
-        });
-    }
-    #[bench]
-    fn bench_encrypt_0008_blocks_serial(b: &mut Bencher) {
-        let mut buf = [0; BLOCK_SIZE * 7 + 1];
-        b.iter(|| {
-            encrypt_round(&mut buf, ParOrNot::Serial);
-        });
-    }
-    #[bench]
-    fn bench_encrypt_0008_blocks_par(b: &mut Bencher) {
-        let mut buf = [0; BLOCK_SIZE * 7 + 1];
-        b.iter(|| {
-            encrypt_round(&mut buf, ParOrNot::Parallel);
-        });
-    }
-
-    #[bench]
-    fn bench_encrypt_0256_blocks(b: &mut Bencher) {
-        let mut buf = [0; BLOCK_SIZE * 255 + 1];
-        b.iter(|| {
-            encrypt_round_auto(&mut buf);
-        });
-    }
-    #[bench]
-    fn bench_encrypt_0256_blocks_serial(b: &mut Bencher) {
-        let mut buf = [0; BLOCK_SIZE * 255 + 1];
-        b.iter(|| {
-            encrypt_round(&mut buf, ParOrNot::Serial);
-        });
-    }
-    #[bench]
-    fn bench_encrypt_0256_blocks_par(b: &mut Bencher) {
-        let mut buf = [0; BLOCK_SIZE * 255 + 1];
-        b.iter(|| {
-            encrypt_round(&mut buf, ParOrNot::Parallel);
-        });
-    }
-
-    #[bench]
-    fn bench_encrypt_0320_blocks(b: &mut Bencher) {
-        let mut buf = [0; BLOCK_SIZE * 319 + 1];
-        b.iter(|| {
-            encrypt_round_auto(&mut buf);
-        });
-    }
-    #[bench]
-    fn bench_encrypt_0320_blocks_serial(b: &mut Bencher) {
-        let mut buf = [0; BLOCK_SIZE * 319 + 1];
-        b.iter(|| {
-            encrypt_round(&mut buf, ParOrNot::Serial);
-        });
-    }
-    #[bench]
-    fn bench_encrypt_0320_blocks_par(b: &mut Bencher) {
-        let mut buf = [0; BLOCK_SIZE * 319 + 1];
-        b.iter(|| {
-            encrypt_round(&mut buf, ParOrNot::Parallel);
-        });
-    }
-
-    #[bench]
-    fn bench_encrypt_0384_blocks(b: &mut Bencher) {
-        let mut buf = [0; BLOCK_SIZE * 383 + 1];
-        b.iter(|| {
-            encrypt_round_auto(&mut buf);
-        });
-    }
-    #[bench]
-    fn bench_encrypt_0384_blocks_serial(b: &mut Bencher) {
-        let mut buf = [0; BLOCK_SIZE * 383 + 1];
-        b.iter(|| {
-            encrypt_round(&mut buf, ParOrNot::Serial);
-        });
-    }
-    #[bench]
-    fn bench_encrypt_0384_blocks_par(b: &mut Bencher) {
-        let mut buf = [0; BLOCK_SIZE * 383 + 1];
-        b.iter(|| {
-            encrypt_round(&mut buf, ParOrNot::Parallel);
-        });
-    }
-
-    #[bench]
-    fn bench_encrypt_0512_blocks(b: &mut Bencher) {
-        let mut buf = [0; BLOCK_SIZE * 511 + 1];
-        b.iter(|| {
-            encrypt_round_auto(&mut buf);
-        });
-    }
-    #[bench]
-    fn bench_encrypt_0512_blocks_serial(b: &mut Bencher) {
-        let mut buf = [0; BLOCK_SIZE * 511 + 1];
-        b.iter(|| {
-            encrypt_round(&mut buf, ParOrNot::Serial);
-        });
-    }
-    #[bench]
-    fn bench_encrypt_0512_blocks_par(b: &mut Bencher) {
-        let mut buf = [0; BLOCK_SIZE * 511 + 1];
-        b.iter(|| {
-            encrypt_round(&mut buf, ParOrNot::Parallel);
-        });
-    }
-
-    #[bench]
-    fn bench_encrypt_0768_blocks(b: &mut Bencher) {
-        let mut buf = [0; BLOCK_SIZE * 767 + 1];
-        b.iter(|| {
-            encrypt_round_auto(&mut buf);
-        });
-    }
-    #[bench]
-    fn bench_encrypt_0768_blocks_serial(b: &mut Bencher) {
-        let mut buf = [0; BLOCK_SIZE * 767 + 1];
-        b.iter(|| {
-            encrypt_round(&mut buf, ParOrNot::Serial);
-        });
-    }
-    #[bench]
-    fn bench_encrypt_0768_blocks_par(b: &mut Bencher) {
-        let mut buf = [0; BLOCK_SIZE * 767 + 1];
-        b.iter(|| {
-            encrypt_round(&mut buf, ParOrNot::Parallel);
-        });
-    }
-
-    #[bench]
-    fn bench_encrypt_1024_blocks(b: &mut Bencher) {
-        let mut buf = [0; BLOCK_SIZE * 1023 + 1];
-        b.iter(|| {
-            encrypt_round_auto(&mut buf);
-        });
-    }
-    #[bench]
-    fn bench_encrypt_1024_blocks_serial(b: &mut Bencher) {
-        let mut buf = [0; BLOCK_SIZE * 1023 + 1];
-        b.iter(|| {
-            encrypt_round(&mut buf, ParOrNot::Serial);
-        });
-    }
-    #[bench]
-    fn bench_encrypt_1024_blocks_par(b: &mut Bencher) {
-        let mut buf = [0; BLOCK_SIZE * 1023 + 1];
-        b.iter(|| {
-            encrypt_round(&mut buf, ParOrNot::Parallel);
-        });
-    }
-
-    #[bench]
-    fn bench_encrypt_2048_blocks(b: &mut Bencher) {
-        let mut buf = [0; BLOCK_SIZE * 2047 + 1];
-        b.iter(|| {
-            encrypt_round_auto(&mut buf);
-        });
-    }
-    #[bench]
-    fn bench_encrypt_2048_blocks_serial(b: &mut Bencher) {
-        let mut buf = [0; BLOCK_SIZE * 2047 + 1];
-        b.iter(|| {
-            encrypt_round(&mut buf, ParOrNot::Serial);
-        });
-    }
-    #[bench]
-    fn bench_encrypt_2048_blocks_par(b: &mut Bencher) {
-        let mut buf = [0; BLOCK_SIZE * 2047 + 1];
-        b.iter(|| {
-            encrypt_round(&mut buf, ParOrNot::Parallel);
-        });
+    #[test]
+    fn test_parallelism_config() {
+        let mut buf_s = [0; 4096];
+        encrypt_round(&mut buf_s, ParOrNot::Serial);
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(2)
+            .build()
+            .unwrap();
+        let par = ParallelismConfig {
+            blocks_threshold: 0,
+            chunk_blocks: 3,
+            thread_pool: Some(Arc::new(pool)),
+            prefetch_blocks: 0,
+        };
+        let mut buf_p = [0; 4096];
+        let mut cipher = stream_cipher().with_parallelism(par);
+        cipher.encrypt(&mut buf_p[..100]);
+        cipher.encrypt(&mut buf_p[100..]);
+        assert_eq!(buf_s, buf_p);
+    }
+
+    #[test]
+    fn test_calibrated() {
+        let threshold = ParallelismConfig::calibrated().blocks_threshold;
+        assert!(
+            (CALIBRATION_MIN_BLOCKS - 1..=CALIBRATION_MAX_BLOCKS).contains(&threshold)
+                || threshold == usize::MAX
+        );
+        assert_eq!(
+            ParallelismConfig::default().blocks_threshold,
+            PAR_BLOCKS_THRESHOLD
+        );
+
+        // No timing on the pool it would time
+        let on_pool = rayon::scope(|_| ParallelismConfig::calibrated());
+        assert_eq!(on_pool, ParallelismConfig::default());
+    }
+
+    #[test]
+    fn test_prefetch() {
+        let mut expected = [0; 8192];
+        encrypt_round(&mut expected, ParOrNot::Serial);
+
+        let par = ParallelismConfig {
+            prefetch_blocks: 5,
+            ..Default::default()
+        };
+        let mut cipher = stream_cipher().with_parallelism(par);
+        let mut buf = [0; 8192];
+        let mut pos = 0;
+        for len in [1, 63, 64, 200, 640, 7, 1000, 129] {
+            cipher.encrypt(&mut buf[pos..pos + len]);
+            pos += len;
+        }
+        // Jumping around drops the stale keystream
+        cipher.seek(4000);
+        cipher.encrypt(&mut buf[4000..4321]);
+        cipher.skip(100);
+        cipher.encrypt(&mut buf[4421..]);
+        buf[pos..4000].copy_from_slice(&expected[pos..4000]);
+        buf[4321..4421].copy_from_slice(&expected[4321..4421]);
+        assert_eq!(buf, expected);
+
+        // So does a new key at the same position
+        cipher.seek(0);
+        cipher.encrypt(&mut [0; 640]);
+        cipher.rekey([1; KEY_BYTES]);
+        let mut rekeyed = stream_cipher();
+        rekeyed.rekey([1; KEY_BYTES]);
+        rekeyed.seek(640);
+        let (mut a, mut b) = ([0; 640], [0; 640]);
+        cipher.encrypt(&mut a);
+        rekeyed.encrypt(&mut b);
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn test_prefetch_partial_blocks() {
+        let mut expected = [0; 400];
+        encrypt_round(&mut expected, ParOrNot::Serial);
+
+        let par = ParallelismConfig {
+            prefetch_blocks: 8,
+            ..Default::default()
+        };
+        let mut cipher = stream_cipher().with_parallelism(par);
+        let finish_job = |cipher: &mut StreamCipher| {
+            let prefetch = cipher.prefetch.as_mut().unwrap();
+            let (counter, job) = prefetch.pending.take().unwrap();
+            let ks = job.into_inner().unwrap().recv().unwrap();
+            prefetch.ready = Some((counter, ks, 0));
+        };
+        let used =
+            |cipher: &StreamCipher| cipher.prefetch.as_ref().unwrap().ready.as_ref().unwrap().2;
+
+        let mut buf = [0; 400];
+        cipher.encrypt(&mut buf[..100]);
+        finish_job(&mut cipher);
+        // The rest of the partial block, one prefetched block and the start of another
+        cipher.encrypt(&mut buf[100..200]);
+        assert_eq!(used(&cipher), BLOCK_SIZE);
+        // The block computed inline for the partial write is skipped
+        cipher.encrypt(&mut buf[200..400]);
+        assert_eq!(used(&cipher), 4 * BLOCK_SIZE);
+        assert_eq!(buf, expected);
     }
 }
@@ -0,0 +1,298 @@
+//! SIMD backends of the block function
+//!
+//! Each vector holds the same state word of `LANES` consecutive blocks.
+
+use super::{xor_word, Backend, ChaCha20, BLOCK_SIZE};
+
+/// Generate a backend over the vector type and the intrinsics of one instruction set
+macro_rules! backend {
+    (
+        $name:ident, $arch:ident, $feature:literal, $lanes:literal, $vec:ty, $word:ty, $counters:expr,
+        $set1:ident, $add:ident, $xor:ident, $or:ident, $slli:ident, $srli:ident, $storeu:ident $(,)?
+    ) => {
+        pub mod $name {
+            use core::arch::$arch::*;
+
+            use super::{xor_word, ChaCha20, BLOCK_SIZE};
+
+            /// Blocks computed by one call to [`xor_blocks`]
+            pub const LANES: usize = $lanes;
+
+            macro_rules! rotate_left {
+                ($v:expr, $n:literal) => {
+                    $or($slli::<$n>($v), $srli::<{ 32 - $n }>($v))
+                };
+            }
+
+            #[inline]
+            #[target_feature(enable = $feature)]
+            fn quarter_round(x: &mut [$vec; 16], a: usize, b: usize, c: usize, d: usize) {
+                // 1
+                x[a] = $add(x[a], x[b]);
+                x[d] = $xor(x[d], x[a]);
+                x[d] = rotate_left!(x[d], 16);
+                // 2
+                x[c] = $add(x[c], x[d]);
+                x[b] = $xor(x[b], x[c]);
+                x[b] = rotate_left!(x[b], 12);
+                // 3
+                x[a] = $add(x[a], x[b]);
+                x[d] = $xor(x[d], x[a]);
+                x[d] = rotate_left!(x[d], 8);
+                // 4
+                x[c] = $add(x[c], x[d]);
+                x[b] = $xor(x[b], x[c]);
+                x[b] = rotate_left!(x[b], 7);
+            }
+
+            /// XOR the keystream of the [`LANES`] blocks starting from the `n`th next block into `buf`
+            ///
+            /// # Safety
+            ///
+            #[doc = concat!("The CPU must support `", $feature, "`.")]
+            #[target_feature(enable = $feature)]
+            pub unsafe fn xor_blocks<const R: usize>(
+                block: &ChaCha20<R>,
+                n: u32,
+                buf: &mut [u8; LANES * BLOCK_SIZE],
+            ) {
+                let state = block.next_nth_state(n);
+                let mut x: [$vec; 16] = core::array::from_fn(|i| $set1(state.vec()[i] as $word));
+                x[12] = $add(x[12], $counters);
+                let initial = x;
+
+                for _ in 0..R / 2 {
+                    quarter_round(&mut x, 0, 4, 8, 12);
+                    quarter_round(&mut x, 1, 5, 9, 13);
+                    quarter_round(&mut x, 2, 6, 10, 14);
+                    quarter_round(&mut x, 3, 7, 11, 15);
+                    quarter_round(&mut x, 0, 5, 10, 15);
+                    quarter_round(&mut x, 1, 6, 11, 12);
+                    quarter_round(&mut x, 2, 7, 8, 13);
+                    quarter_round(&mut x, 3, 4, 9, 14);
+                }
+
+                // Transpose words back into blocks
+                let mut words = [[0_u32; LANES]; 16];
+                for (w, (x, initial)) in words.iter_mut().zip(x.iter().zip(&initial)) {
+                    $storeu(w.as_mut_ptr().cast(), $add(*x, *initial));
+                }
+                for (lane, c) in buf.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+                    for (w, b) in words.iter().zip(c.chunks_exact_mut(size_of::<u32>())) {
+                        xor_word(b, w[lane]);
+                    }
+                }
+            }
+
+            /// XOR the keystream into the leading [`LANES`]-block chunks of `buf` and return the rest
+            ///
+            /// # Safety
+            ///
+            #[doc = concat!("The CPU must support `", $feature, "`.")]
+            pub unsafe fn xor_chunks<'a, const R: usize>(
+                block: &ChaCha20<R>,
+                n: &mut usize,
+                buf: &'a mut [u8],
+            ) -> &'a mut [u8] {
+                let mut chunks = buf.chunks_exact_mut(LANES * BLOCK_SIZE);
+                for c in &mut chunks {
+                    xor_blocks(block, *n as u32, c.try_into().unwrap());
+                    *n += LANES;
+                }
+                chunks.into_remainder()
+            }
+        }
+    };
+}
+
+#[cfg(all(feature = "avx512", target_arch = "x86_64"))]
+backend!(
+    avx512,
+    x86_64,
+    "avx512f",
+    16,
+    __m512i,
+    i32,
+    _mm512_setr_epi32(0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15),
+    _mm512_set1_epi32,
+    _mm512_add_epi32,
+    _mm512_xor_si512,
+    _mm512_or_si512,
+    _mm512_slli_epi32,
+    _mm512_srli_epi32,
+    _mm512_storeu_si512,
+);
+#[cfg(target_arch = "x86_64")]
+backend!(
+    avx2,
+    x86_64,
+    "avx2",
+    8,
+    __m256i,
+    i32,
+    _mm256_setr_epi32(0, 1, 2, 3, 4, 5, 6, 7),
+    _mm256_set1_epi32,
+    _mm256_add_epi32,
+    _mm256_xor_si256,
+    _mm256_or_si256,
+    _mm256_slli_epi32,
+    _mm256_srli_epi32,
+    _mm256_storeu_si256,
+);
+// Part of the x86_64 baseline
+#[cfg(target_arch = "x86_64")]
+backend!(
+    sse2,
+    x86_64,
+    "sse2",
+    4,
+    __m128i,
+    i32,
+    _mm_setr_epi32(0, 1, 2, 3),
+    _mm_set1_epi32,
+    _mm_add_epi32,
+    _mm_xor_si128,
+    _mm_or_si128,
+    _mm_slli_epi32,
+    _mm_srli_epi32,
+    _mm_storeu_si128,
+);
+
+// Part of the aarch64 baseline
+#[cfg(target_arch = "aarch64")]
+backend!(
+    neon,
+    aarch64,
+    "neon",
+    4,
+    uint32x4_t,
+    u32,
+    vld1q_u32([0, 1, 2, 3].as_ptr()),
+    vdupq_n_u32,
+    vaddq_u32,
+    veorq_u32,
+    vorrq_u32,
+    vshlq_n_u32,
+    vshrq_n_u32,
+    vst1q_u32,
+);
+
+/// The fastest backend supported by the CPU
+pub fn detect() -> Backend {
+    #[cfg(all(feature = "avx512", target_arch = "x86_64"))]
+    if std::is_x86_feature_detected!("avx512f") {
+        return Backend::Avx512;
+    }
+    #[cfg(target_arch = "x86_64")]
+    if std::is_x86_feature_detected!("avx2") {
+        return Backend::Avx2;
+    }
+    #[cfg(target_arch = "x86_64")]
+    return Backend::Sse2;
+    #[cfg(target_arch = "aarch64")]
+    return Backend::Neon;
+    #[allow(unreachable_code)]
+    Backend::Scalar
+}
+
+/// XOR the keystream into the leading chunks of `buf` that `backend` takes in parallel and return the rest
+///
+/// `backend` must be supported by the CPU.
+pub fn xor_chunks<'a, const R: usize>(
+    backend: Backend,
+    block: &ChaCha20<R>,
+    n: &mut usize,
+    buf: &'a mut [u8],
+) -> &'a mut [u8] {
+    // SAFETY: `backend` is supported and so are the narrower ones on the same architecture
+    unsafe {
+        match backend {
+            #[cfg(all(feature = "avx512", target_arch = "x86_64"))]
+            Backend::Avx512 => {
+                let buf = avx512::xor_chunks(block, n, buf);
+                let buf = avx2::xor_chunks(block, n, buf);
+                sse2::xor_chunks(block, n, buf)
+            }
+            #[cfg(target_arch = "x86_64")]
+            Backend::Avx2 => {
+                let buf = avx2::xor_chunks(block, n, buf);
+                sse2::xor_chunks(block, n, buf)
+            }
+            #[cfg(target_arch = "x86_64")]
+            Backend::Sse2 => sse2::xor_chunks(block, n, buf),
+            #[cfg(target_arch = "aarch64")]
+            Backend::Neon => neon::xor_chunks(block, n, buf),
+            _ => buf,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block() -> ChaCha20 {
+        let key = core::array::from_fn(|i| i as u8);
+        let nonce = [0, 0, 0, 0, 0, 0, 0, 0x4a, 0, 0, 0, 0];
+
+        // Wrap the counter within the lanes
+        ChaCha20::new(key, nonce, u32::MAX - 2)
+    }
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(Backend::current(), detect());
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        assert_ne!(Backend::current(), Backend::Scalar);
+    }
+
+    #[cfg(all(feature = "avx512", target_arch = "x86_64"))]
+    #[test]
+    fn test_avx512() {
+        if detect() != Backend::Avx512 {
+            return;
+        }
+        let block = block();
+        let mut buf = [0; avx512::LANES * BLOCK_SIZE];
+        unsafe { avx512::xor_blocks(&block, 1, &mut buf) };
+        for (i, c) in buf.chunks_exact(BLOCK_SIZE).enumerate() {
+            assert_eq!(c, block.next_nth_block(i as u32 + 1).byte_vec());
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_avx2() {
+        if !matches!(detect(), Backend::Avx512 | Backend::Avx2) {
+            return;
+        }
+        let block = block();
+        let mut buf = [0; avx2::LANES * BLOCK_SIZE];
+        unsafe { avx2::xor_blocks(&block, 1, &mut buf) };
+        for (i, c) in buf.chunks_exact(BLOCK_SIZE).enumerate() {
+            assert_eq!(c, block.next_nth_block(i as u32 + 1).byte_vec());
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_sse2() {
+        let block = block();
+        let mut buf = [0; sse2::LANES * BLOCK_SIZE];
+        unsafe { sse2::xor_blocks(&block, 1, &mut buf) };
+        for (i, c) in buf.chunks_exact(BLOCK_SIZE).enumerate() {
+            assert_eq!(c, block.next_nth_block(i as u32 + 1).byte_vec());
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    #[test]
+    fn test_neon() {
+        let block = block();
+        let mut buf = [0; neon::LANES * BLOCK_SIZE];
+        unsafe { neon::xor_blocks(&block, 1, &mut buf) };
+        for (i, c) in buf.chunks_exact(BLOCK_SIZE).enumerate() {
+            assert_eq!(c, block.next_nth_block(i as u32 + 1).byte_vec());
+        }
+    }
+}
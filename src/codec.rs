@@ -0,0 +1,224 @@
+use std::{io, sync::Arc};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{
+    datagram::{nonce, open, seal, TAG_BYTES},
+    key::SecretKey,
+    stream::Error,
+    KEY_BYTES, NONCE_BYTES,
+};
+
+const LEN_BYTES: usize = size_of::<u32>();
+pub const MAX_FRAME_BYTES: usize = 8 * 1024 * 1024;
+
+/// Length-prefixed encrypted and authenticated frames
+///
+/// Wire format of each direction: `iv || (len || ciphertext || tag)*`
+///
+/// - `iv`: Random per-direction value sent once before the first frame
+/// - `len`: Big-endian `u32` length of `ciphertext`, authenticated as AAD
+/// - The nonce of each frame is `iv` XOR the frame index
+#[derive(Debug, Clone)]
+pub struct ChaCha20Codec {
+    key: Arc<SecretKey>,
+    encoder: Option<FrameCounter>,
+    decoder: Option<FrameCounter>,
+}
+impl ChaCha20Codec {
+    pub fn new(key: [u8; KEY_BYTES]) -> Self {
+        Self::from_shared(Arc::new(key.into()))
+    }
+    /// Keep `key` in one place for all the codecs on it, e.g. [`Config::shared_key`](crate::config::Config::shared_key)
+    pub fn from_shared(key: Arc<SecretKey>) -> Self {
+        Self {
+            key,
+            encoder: None,
+            decoder: None,
+        }
+    }
+}
+impl Encoder<Bytes> for ChaCha20Codec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        if MAX_FRAME_BYTES < item.len() {
+            return Err(Error::FrameTooLarge(item.len()).into());
+        }
+        let encoder = self.encoder.get_or_insert_with(|| {
+            let iv: [u8; NONCE_BYTES] = rand::random();
+            dst.put_slice(&iv);
+            FrameCounter::new(iv)
+        });
+        let nonce = encoder.next_nonce();
+
+        let len = (item.len() as u32).to_be_bytes();
+        dst.reserve(LEN_BYTES + item.len() + TAG_BYTES);
+        dst.put_slice(&len);
+        let start = dst.len();
+        dst.put_slice(&item);
+        let tag = seal(*self.key.expose_secret(), nonce, &len, &mut dst[start..]);
+        dst.put_slice(&tag);
+        Ok(())
+    }
+}
+impl Decoder for ChaCha20Codec {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let decoder = match &mut self.decoder {
+            Some(decoder) => decoder,
+            None => {
+                if src.len() < NONCE_BYTES {
+                    src.reserve(NONCE_BYTES - src.len());
+                    return Ok(None);
+                }
+                let iv = src[..NONCE_BYTES].try_into().unwrap();
+                src.advance(NONCE_BYTES);
+                self.decoder.insert(FrameCounter::new(iv))
+            }
+        };
+
+        if src.len() < LEN_BYTES {
+            return Ok(None);
+        }
+        let len: [u8; LEN_BYTES] = src[..LEN_BYTES].try_into().unwrap();
+        let payload_len = u32::from_be_bytes(len) as usize;
+        if MAX_FRAME_BYTES < payload_len {
+            return Err(Error::ReceivedFrameTooLarge(payload_len).into());
+        }
+        let frame_len = LEN_BYTES + payload_len + TAG_BYTES;
+        if src.len() < frame_len {
+            src.reserve(frame_len - src.len());
+            return Ok(None);
+        }
+
+        let mut frame = src.split_to(frame_len);
+        frame.advance(LEN_BYTES);
+        let tag = frame.split_off(payload_len);
+        let nonce = decoder.next_nonce();
+        open(
+            *self.key.expose_secret(),
+            nonce,
+            &len,
+            &mut frame,
+            tag[..].try_into().unwrap(),
+        )
+        .map_err(|_| Error::TagMismatch)?;
+        Ok(Some(frame))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if let Some(frame) = self.decode(src)? {
+            return Ok(Some(frame));
+        }
+        if src.is_empty() {
+            return Ok(None);
+        }
+        if self.decoder.is_none() {
+            return Err(Error::NonceTruncated.into());
+        }
+        let payload_len = match src.get(..LEN_BYTES) {
+            Some(len) => u32::from_be_bytes(len.try_into().unwrap()) as usize,
+            None => return Err(Error::PayloadTruncated.into()),
+        };
+        match src.len() < LEN_BYTES + payload_len {
+            true => Err(Error::PayloadTruncated.into()),
+            false => Err(Error::TagTruncated.into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct FrameCounter {
+    iv: [u8; NONCE_BYTES],
+    next: u64,
+}
+impl FrameCounter {
+    pub fn new(iv: [u8; NONCE_BYTES]) -> Self {
+        Self { iv, next: 0 }
+    }
+
+    pub fn next_nonce(&mut self) -> [u8; NONCE_BYTES] {
+        let counter = self.next;
+        self.next = counter.checked_add(1).expect("frame counter exhausted");
+        nonce(self.iv, counter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{SinkExt, StreamExt};
+    use tokio_util::codec::Framed;
+
+    use crate::config::tests::create_random_config;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_framed() {
+        let config = create_random_config();
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = Framed::new(client, ChaCha20Codec::new(*config.key()));
+        let mut server = Framed::new(
+            server,
+            ChaCha20Codec::from_shared(config.shared_key().clone()),
+        );
+
+        for i in 0..64 {
+            let data = Bytes::from(vec![i as u8; i * 7]);
+            client.send(data.clone()).await.unwrap();
+            let frame = server.next().await.unwrap().unwrap();
+            assert_eq!(frame, data);
+
+            server.send(data.clone()).await.unwrap();
+            let frame = client.next().await.unwrap().unwrap();
+            assert_eq!(frame, data);
+        }
+    }
+
+    #[test]
+    fn test_tampered() {
+        let config = create_random_config();
+        let mut en = ChaCha20Codec::new(*config.key());
+        let mut de = ChaCha20Codec::new(*config.key());
+
+        let mut buf = BytesMut::new();
+        en.encode(Bytes::from_static(b"Hello world!"), &mut buf)
+            .unwrap();
+        let last = buf.len() - 1;
+        buf[last] ^= 1;
+        assert!(de.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_truncated() {
+        let config = create_random_config();
+        let mut en = ChaCha20Codec::new(*config.key());
+        let mut wire = BytesMut::new();
+        en.encode(Bytes::from_static(b"Hello world!"), &mut wire)
+            .unwrap();
+
+        let payload_end = wire.len() - TAG_BYTES;
+        let cases = [
+            (0, None),
+            (NONCE_BYTES - 1, Some(Error::NonceTruncated)),
+            (NONCE_BYTES, None),
+            (NONCE_BYTES + 1, Some(Error::PayloadTruncated)),
+            (payload_end - 1, Some(Error::PayloadTruncated)),
+            (payload_end, Some(Error::TagTruncated)),
+            (wire.len() - 1, Some(Error::TagTruncated)),
+        ];
+        for (len, expected) in cases {
+            let mut de = ChaCha20Codec::new(*config.key());
+            let mut buf = BytesMut::from(&wire[..len]);
+            match de.decode_eof(&mut buf) {
+                Ok(frame) => assert_eq!((frame, expected), (None, None)),
+                Err(e) => assert_eq!(Error::from_io(&e), expected.as_ref()),
+            }
+        }
+    }
+}
@@ -1,63 +1,919 @@
-use std::{hash::Hash, sync::Arc};
+use std::{
+    fmt,
+    hash::Hash,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use base64::prelude::*;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::KEY_BYTES;
+use crate::{
+    cursor::{DecryptCursor, EncryptCursor, TaggedDecryptCursor},
+    key::{zeroize, KeyId, KeyProvider, SecretKey},
+    mac::constant_time_eq,
+    KEY_BYTES,
+};
+
+mod source;
+pub use source::{EnvKeySource, FetchKey, FileKeySource, KeySource};
 
 pub type ConfigKey = Arc<[u8]>;
 
+const SUBKEY_CONTEXT: &str = "tokio_chacha20 subkey";
+/// `info` of the subkeys of nonce-prefixed streams salted with the nonce
+pub(crate) const STREAM_SUBKEY_INFO: &[u8] = b"stream";
+
+/// A key for bulk encryption derived from the long-term `key` with BLAKE3 in key derivation mode
+///
+/// - `salt`: Should be unique per derived key, e.g. the nonce of a connection
+/// - `info`: Separates the purposes of the derived keys
+pub fn derive_subkey(key: &[u8; KEY_BYTES], salt: &[u8], info: &[u8]) -> [u8; KEY_BYTES] {
+    let mut hasher = blake3::Hasher::new_derive_key(SUBKEY_CONTEXT);
+    hasher.update(key);
+    // The length keeps `salt` and `info` apart
+    hasher.update(&(salt.len() as u64).to_le_bytes());
+    hasher.update(salt);
+    hasher.update(info);
+    *hasher.finalize().as_bytes()
+}
+
+const SHARED_SECRET_CONTEXT: &str = "tokio_chacha20 shared secret";
+
+/// A ChaCha20 key from the output of a Diffie-Hellman exchange such as X25519
+///
+/// The raw DH output is not uniformly random, so do not pass it to [`crate::cipher::StreamCipher::new`] directly.
+/// `context`: Names the application and the purpose of the key, e.g. `"my-app 2024 tunnel"`
+pub fn derive_key_from_shared_secret(
+    mut shared: [u8; KEY_BYTES],
+    context: &str,
+) -> [u8; KEY_BYTES] {
+    let mut hasher = blake3::Hasher::new_derive_key(SHARED_SECRET_CONTEXT);
+    // The length keeps `context` and `shared` apart
+    hasher.update(&(context.len() as u64).to_le_bytes());
+    hasher.update(context.as_bytes());
+    hasher.update(&shared);
+    zeroize(&mut shared);
+    *hasher.finalize().as_bytes()
+}
+
+/// The end of a duplex connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+const CLIENT_TO_SERVER_INFO: &[u8] = b"client to server";
+const SERVER_TO_CLIENT_INFO: &[u8] = b"server to client";
+
+/// The `(rx, tx)` keys of `role` derived from the shared `key`, one per direction
+///
+/// The keystreams of the two directions never collide, even under equal nonces.
+pub fn directional_keys(key: &[u8; KEY_BYTES], role: Role) -> ([u8; KEY_BYTES], [u8; KEY_BYTES]) {
+    let client_to_server = derive_subkey(key, &[], CLIENT_TO_SERVER_INFO);
+    let server_to_client = derive_subkey(key, &[], SERVER_TO_CLIENT_INFO);
+    match role {
+        Role::Client => (server_to_client, client_to_server),
+        Role::Server => (client_to_server, server_to_client),
+    }
+}
+
+/// The source of the key; `Debug` is redacted and the secret is zeroed on drop
+///
+/// Deserialized from the base64 key or `{ "passphrase": .., "kdf": .. }`.
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
-pub struct ConfigBuilder(pub String);
+#[serde(transparent)]
+pub struct ConfigBuilder(BuilderSource);
+#[derive(Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(untagged)]
+enum BuilderSource {
+    /// The base64 key
+    Key(String),
+    /// A human-memorable secret stretched by Argon2id
+    #[cfg(feature = "argon2")]
+    Passphrase { passphrase: String, kdf: String },
+}
+impl fmt::Debug for BuilderSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Key(_) => f.write_str("Key(<redacted>)"),
+            #[cfg(feature = "argon2")]
+            Self::Passphrase { kdf, .. } => f
+                .debug_struct("Passphrase")
+                .field("passphrase", &"<redacted>")
+                .field("kdf", kdf)
+                .finish(),
+        }
+    }
+}
+impl Drop for BuilderSource {
+    fn drop(&mut self) {
+        match self {
+            Self::Key(key) => zeroize_str(key),
+            #[cfg(feature = "argon2")]
+            Self::Passphrase { passphrase, .. } => zeroize_str(passphrase),
+        }
+    }
+}
 impl ConfigBuilder {
-    pub fn build(&self) -> Result<Config, ConfigBuildError> {
-        let key = BASE64_STANDARD_NO_PAD
-            .decode(&self.0)
-            .map_err(|e| ConfigBuildError {
-                source: e,
-                key: self.0.clone(),
-            })?;
-        Ok(Config::new(key.into()))
+    /// Take over the base64 key without copying it
+    pub fn key(key: String) -> Self {
+        Self(BuilderSource::Key(key))
+    }
+
+    /// Load from `path` by its extension
+    ///
+    /// - `.json` and `.toml`: A [`ConfigBuilder`] or a table with the base64 `key`;
+    ///   requires the `config-file` feature
+    /// - Otherwise: The base64 key alone, surrounding whitespace ignored
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, ConfigLoadError> {
+        let path = path.as_ref();
+        let mut contents = std::fs::read_to_string(path).map_err(|source| ConfigLoadError::Io {
+            path: path.into(),
+            source,
+        })?;
+        let builder = Self::parse_file(path, &contents);
+        zeroize_str(&mut contents);
+        builder
+    }
+    fn parse_file(path: &Path, contents: &str) -> Result<Self, ConfigLoadError> {
+        // The messages of the format errors do not quote the contents
+        let parse_error = |message: String| ConfigLoadError::Parse {
+            path: path.into(),
+            message,
+        };
+        match path.extension().and_then(|e| e.to_str()) {
+            #[cfg(feature = "config-file")]
+            Some("json") => serde_json::from_str::<ConfigFile>(contents)
+                .map(Self::from)
+                .map_err(|e| parse_error(e.to_string())),
+            #[cfg(feature = "config-file")]
+            Some("toml") => toml::from_str::<ConfigFile>(contents)
+                .map(Self::from)
+                .map_err(|e| parse_error(e.message().into())),
+            #[cfg(not(feature = "config-file"))]
+            Some("json" | "toml") => Err(parse_error("requires the `config-file` feature".into())),
+            _ => Ok(Self::key(contents.trim().into())),
+        }
+    }
+
+    /// A passphrase stretched with a fresh random salt and the default Argon2id parameters
+    ///
+    /// The KDF string is the PHC string of the salt and the parameters without the hash,
+    /// e.g. `$argon2id$v=19$m=19456,t=2,p=1$<base64 salt>`.
+    #[cfg(feature = "argon2")]
+    pub fn passphrase(passphrase: String) -> Self {
+        use argon2::password_hash::SaltString;
+
+        let salt: [u8; argon2::RECOMMENDED_SALT_LEN] = rand::random();
+        let salt = SaltString::encode_b64(&salt).expect("salt length is recommended");
+        let params = argon2::Params::DEFAULT;
+        let kdf = format!(
+            "${}$v={}$m={},t={},p={}${}",
+            argon2::Algorithm::Argon2id,
+            u32::from(argon2::Version::V0x13),
+            params.m_cost(),
+            params.t_cost(),
+            params.p_cost(),
+            salt.as_str(),
+        );
+        Self::passphrase_with_kdf(passphrase, kdf)
+    }
+    /// A passphrase stretched as `kdf` says; see [`Self::passphrase`] for the format
+    #[cfg(feature = "argon2")]
+    pub fn passphrase_with_kdf(passphrase: String, kdf: String) -> Self {
+        Self(BuilderSource::Passphrase { passphrase, kdf })
+    }
+
+    /// The secret is zeroed once the key is derived
+    pub fn build(self) -> Result<Config, ConfigBuildError> {
+        match &self.0 {
+            BuilderSource::Key(key) => {
+                let mut key = BASE64_STANDARD_NO_PAD.decode(key)?;
+                let config = Config::hash_key(&key);
+                zeroize(&mut key);
+                Ok(config)
+            }
+            #[cfg(feature = "argon2")]
+            BuilderSource::Passphrase { passphrase, kdf } => {
+                let mut key = stretch_passphrase(passphrase, kdf)?;
+                let config = Config::from_raw_key(key);
+                zeroize(&mut key);
+                Ok(config)
+            }
+        }
     }
 }
+/// Does not carry the key so that it does not end up in logs
 #[derive(Debug, Error)]
-#[error("{source}, key = `{key}`")]
-pub struct ConfigBuildError {
-    #[source]
-    pub source: base64::DecodeError,
-    pub key: String,
+pub enum ConfigBuildError {
+    #[error("{0}")]
+    Base64(#[from] base64::DecodeError),
+    #[cfg(feature = "argon2")]
+    #[error("invalid KDF string: {0}")]
+    Kdf(argon2::password_hash::Error),
+    #[cfg(feature = "argon2")]
+    #[error("Argon2id: {0}")]
+    Argon2(argon2::Error),
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+/// Does not carry the key or the contents of the file so that they do not end up in logs
+#[derive(Debug, Error)]
+pub enum ConfigLoadError {
+    #[error("environment variable `{0}` is not set")]
+    EnvNotPresent(String),
+    #[error("environment variable `{0}` is not valid unicode")]
+    EnvNotUnicode(String),
+    #[error("{}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("{}: {message}", path.display())]
+    Parse { path: PathBuf, message: String },
+    #[error(transparent)]
+    Build(#[from] ConfigBuildError),
+}
+
+#[cfg(feature = "config-file")]
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ConfigFile {
+    Key { key: String },
+    Builder(ConfigBuilder),
+}
+#[cfg(feature = "config-file")]
+impl From<ConfigFile> for ConfigBuilder {
+    fn from(file: ConfigFile) -> Self {
+        match file {
+            ConfigFile::Key { key } => Self::key(key),
+            ConfigFile::Builder(builder) => builder,
+        }
+    }
+}
+
+fn zeroize_str(s: &mut str) {
+    // SAFETY: Zeros are valid UTF-8
+    zeroize(unsafe { s.as_bytes_mut() });
+}
+
+#[cfg(feature = "argon2")]
+fn stretch_passphrase(passphrase: &str, kdf: &str) -> Result<[u8; KEY_BYTES], ConfigBuildError> {
+    use argon2::password_hash::PasswordHash;
+
+    let hash = PasswordHash::new(kdf).map_err(ConfigBuildError::Kdf)?;
+    let algorithm = argon2::Algorithm::try_from(hash.algorithm).map_err(ConfigBuildError::Kdf)?;
+    if algorithm != argon2::Algorithm::Argon2id {
+        return Err(ConfigBuildError::Kdf(
+            argon2::password_hash::Error::Algorithm,
+        ));
+    }
+    let version = match hash.version {
+        Some(v) => argon2::Version::try_from(v).map_err(ConfigBuildError::Argon2)?,
+        None => argon2::Version::default(),
+    };
+    let params = argon2::Params::try_from(&hash).map_err(ConfigBuildError::Kdf)?;
+    let salt = hash.salt.ok_or(ConfigBuildError::Kdf(
+        argon2::password_hash::Error::SaltInvalid(
+            argon2::password_hash::errors::InvalidValue::Malformed,
+        ),
+    ))?;
+    let mut salt_buf = [0; argon2::password_hash::Salt::MAX_LENGTH];
+    let salt = salt
+        .decode_b64(&mut salt_buf)
+        .map_err(ConfigBuildError::Kdf)?;
+
+    let mut key = [0; KEY_BYTES];
+    argon2::Argon2::new(algorithm, version, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(ConfigBuildError::Argon2)?;
+    Ok(key)
+}
+
+/// Compared in constant time, hashed by [`Config::fingerprint`], and redacted in `Debug`
+///
+/// Not serialized unless on purpose with [`expose`]; log [`Config::fingerprint_view`] instead.
+/// Deserialized from a [`ConfigBuilder`] or the raw form written by [`expose`].
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Config {
-    key: [u8; KEY_BYTES],
+    /// Shared by clones and handed out by [`KeyProvider::key_for`] without copying
+    key: Arc<SecretKey>,
+    /// Sent by writers so that readers can pick the key
+    key_id: KeyId,
+    /// Keys besides `key` still accepted from writers, e.g. during a rotation
+    accepted: Vec<AcceptedKey>,
+}
+impl<'de> Deserialize<'de> for Config {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match SerdeConfig::deserialize(deserializer)? {
+            SerdeConfig::Raw { raw } => Ok(raw.into()),
+            SerdeConfig::Builder(builder) => builder.build().map_err(serde::de::Error::custom),
+        }
+    }
+}
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum SerdeConfig {
+    /// Tagged so that a raw key is never taken by accident
+    Raw {
+        raw: RawConfig,
+    },
+    Builder(ConfigBuilder),
+}
+#[derive(Deserialize, Serialize)]
+struct RawConfig {
+    #[serde(with = "crate::key::expose::shared")]
+    key: Arc<SecretKey>,
+    #[serde(default)]
+    key_id: KeyId,
+    #[serde(default)]
+    accepted: Vec<AcceptedKey>,
+}
+impl From<RawConfig> for Config {
+    fn from(raw: RawConfig) -> Self {
+        let RawConfig {
+            key,
+            key_id,
+            accepted,
+        } = raw;
+        Self {
+            key,
+            key_id,
+            accepted,
+        }
+    }
+}
+
+/// (De)serialize the raw keys of a [`Config`] on purpose as `{ "raw": { "key": [..], "key_id": .., "accepted": [..] } }`
+pub mod expose {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::{Config, RawConfig, SerdeConfig};
+
+    pub fn serialize<S: Serializer>(config: &Config, serializer: S) -> Result<S::Ok, S::Error> {
+        let raw = RawConfig {
+            key: config.key.clone(),
+            key_id: config.key_id,
+            accepted: config.accepted.clone(),
+        };
+        SerdeConfig::Raw { raw }.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Config, D::Error> {
+        Config::deserialize(deserializer)
+    }
+}
+
+/// What [`Config::fingerprint_view`] shows of a config, safe to log or serialize
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ConfigFingerprint {
+    pub key_id: KeyId,
+    /// Base64 of [`Config::fingerprint`]
+    pub fingerprint: String,
+    pub accepted: Vec<KeyFingerprint>,
+}
+/// An accepted key of a [`ConfigFingerprint`]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct KeyFingerprint {
+    pub key_id: KeyId,
+    pub fingerprint: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+struct AcceptedKey {
+    id: KeyId,
+    #[serde(with = "crate::key::expose::shared")]
+    key: Arc<SecretKey>,
 }
 impl Config {
     pub fn new(key: ConfigKey) -> Self {
+        Self::hash_key(&key)
+    }
+    fn hash_key(key: &[u8]) -> Self {
         let mut hasher = blake3::Hasher::new();
-        hasher.update(&key);
+        hasher.update(key);
         let key = hasher.finalize();
-        let key = *key.as_bytes();
-        Self { key }
+        Self::from_raw_key(*key.as_bytes())
+    }
+
+    /// Build from the base64 key in the environment variable `var`, surrounding whitespace ignored
+    pub fn from_env(var: &str) -> Result<Self, ConfigLoadError> {
+        let mut key = std::env::var(var).map_err(|e| match e {
+            std::env::VarError::NotPresent => ConfigLoadError::EnvNotPresent(var.into()),
+            std::env::VarError::NotUnicode(_) => ConfigLoadError::EnvNotUnicode(var.into()),
+        })?;
+        let builder = ConfigBuilder::key(key.trim().into());
+        zeroize_str(&mut key);
+        Ok(builder.build()?)
+    }
+
+    /// Use `key` verbatim instead of hashing it like [`Self::new`]
+    ///
+    /// `key` must already be uniformly random, e.g. shared with another ChaCha20 tool.
+    pub fn from_raw_key(key: [u8; KEY_BYTES]) -> Self {
+        Self::from_shared_key(Arc::new(SecretKey::new(key)))
+    }
+
+    /// Use a raw `key` held elsewhere too, e.g. by the acceptors of other listeners
+    ///
+    /// The key is zeroed once the last holder drops it.
+    pub fn from_shared_key(key: Arc<SecretKey>) -> Self {
+        Self {
+            key,
+            key_id: 0,
+            accepted: Vec::new(),
+        }
+    }
+
+    /// A config from a fresh random key
+    pub fn random() -> Self {
+        let key: [u8; KEY_BYTES] = rand::random();
+        Self::new(key.into())
+    }
+
+    /// The same config for the same `seed`, for reproducible tests only
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn from_seed(seed: u64) -> Self {
+        Self::from_raw_key(blake3::derive_key(
+            "tokio_chacha20 test seed",
+            &seed.to_le_bytes(),
+        ))
     }
 
     pub fn key(&self) -> &[u8; KEY_BYTES] {
+        self.key.expose_secret()
+    }
+
+    pub fn shared_key(&self) -> &Arc<SecretKey> {
         &self.key
     }
+
+    /// Announce the key as `id` by [`Self::encrypt_cursor`]
+    pub fn with_key_id(mut self, id: KeyId) -> Self {
+        self.key_id = id;
+        self
+    }
+
+    pub fn key_id(&self) -> KeyId {
+        self.key_id
+    }
+
+    /// Also accept writers still on the key of `other`
+    ///
+    /// To rotate across a fleet, first roll out the new config accepting the old one,
+    /// then the new config alone once no writer uses the old key anymore.
+    ///
+    /// # Panics
+    ///
+    /// If the key ID of `other` is already taken by a different key.
+    pub fn with_accepted(mut self, other: &Config) -> Self {
+        let accepted = AcceptedKey {
+            id: other.key_id,
+            key: other.key.clone(),
+        };
+        match self.key_by_id(accepted.id) {
+            Some(key) => assert!(
+                constant_time_eq(accepted.key.expose_secret(), key),
+                "key ID {} is taken by a different key",
+                accepted.id
+            ),
+            None => self.accepted.push(accepted),
+        }
+        self
+    }
+
+    /// The key announced as `id` if it is accepted
+    pub fn key_by_id(&self, id: KeyId) -> Option<&[u8; KEY_BYTES]> {
+        self.keys().find(|(i, _)| *i == id).map(|(_, key)| key)
+    }
+
+    /// The own key followed by the accepted ones
+    pub fn keys(&self) -> impl Iterator<Item = (KeyId, &[u8; KEY_BYTES])> {
+        let own = (self.key_id, self.key());
+        let accepted = self.accepted.iter().map(|a| (a.id, a.key.expose_secret()));
+        core::iter::once(own).chain(accepted)
+    }
+
+    /// Encrypt with the own key and send its key ID ahead of the nonce
+    pub fn encrypt_cursor(&self) -> EncryptCursor {
+        EncryptCursor::new(*self.key()).with_key_id(self.key_id)
+    }
+
+    /// Decrypt with whichever accepted key the writer names
+    pub fn decrypt_cursor(&self) -> DecryptCursor {
+        let keys = self.keys().map(|(id, key)| (id, *key));
+        DecryptCursor::new(*self.key()).with_keys(keys)
+    }
+
+    /// See [`derive_subkey`]
+    pub fn derive_subkey(&self, salt: &[u8], info: &[u8]) -> [u8; KEY_BYTES] {
+        derive_subkey(self.key(), salt, info)
+    }
+
+    /// A one-way digest of the key that identifies the config without revealing it
+    pub fn fingerprint(&self) -> [u8; KEY_BYTES] {
+        fingerprint(self.key())
+    }
+
+    /// The key IDs and fingerprints of the own key and the accepted ones without any key bytes
+    pub fn fingerprint_view(&self) -> ConfigFingerprint {
+        let accepted = self
+            .accepted
+            .iter()
+            .map(|a| KeyFingerprint {
+                key_id: a.id,
+                fingerprint: BASE64_STANDARD_NO_PAD.encode(fingerprint(a.key.expose_secret())),
+            })
+            .collect();
+        ConfigFingerprint {
+            key_id: self.key_id,
+            fingerprint: BASE64_STANDARD_NO_PAD.encode(self.fingerprint()),
+            accepted,
+        }
+    }
+}
+fn fingerprint(key: &[u8; KEY_BYTES]) -> [u8; KEY_BYTES] {
+    blake3::derive_key("tokio_chacha20 config fingerprint", key)
+}
+impl Hash for Config {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.fingerprint().hash(state);
+    }
+}
+
+/// The own key without a key ID, otherwise whichever accepted key is named
+impl KeyProvider for Config {
+    fn key_for(&self, _peer: SocketAddr, key_id: Option<KeyId>) -> Option<Arc<SecretKey>> {
+        let Some(id) = key_id else {
+            return Some(self.key.clone());
+        };
+        let own = (self.key_id, &self.key);
+        let accepted = self.accepted.iter().map(|a| (a.id, &a.key));
+        core::iter::once(own)
+            .chain(accepted)
+            .find(|(i, _)| *i == id)
+            .map(|(_, key)| key.clone())
+    }
+}
+
+/// Configs told apart by trial decryption of the first record of a stream
+///
+/// For writers without key IDs, e.g. while clients migrate from one key to another.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Keyring {
+    configs: Vec<Config>,
+}
+impl Keyring {
+    pub fn new(configs: impl IntoIterator<Item = Config>) -> Self {
+        Self {
+            configs: configs.into_iter().collect(),
+        }
+    }
+
+    pub fn configs(&self) -> &[Config] {
+        &self.configs
+    }
+
+    /// The config whose key authenticates `record` and its user data
+    ///
+    /// `record` is the nonce, `msg_len` bytes of ciphertext and the tag as read by [`TaggedDecryptCursor`].
+    /// Every candidate is tried so that the time taken does not tell which one matched.
+    pub fn select(&self, record: &[u8], msg_len: usize) -> Option<(&Config, Vec<u8>)> {
+        self.select_(record, |key| TaggedDecryptCursor::new(key, msg_len))
+    }
+    /// [`Self::select`] for records with an XChaCha20 nonce
+    pub fn select_x(&self, record: &[u8], msg_len: usize) -> Option<(&Config, Vec<u8>)> {
+        self.select_(record, |key| TaggedDecryptCursor::new_x(key, msg_len))
+    }
+    fn select_(
+        &self,
+        record: &[u8],
+        cursor: impl Fn([u8; KEY_BYTES]) -> TaggedDecryptCursor,
+    ) -> Option<(&Config, Vec<u8>)> {
+        let mut selected = None;
+        for config in &self.configs {
+            let mut de = cursor(*config.key());
+            let Ok(n) = de.consume(record) else {
+                continue;
+            };
+            let data = de.verify().ok().flatten().filter(|_| n == record.len());
+            if let (Some(data), None) = (data, &selected) {
+                selected = Some((config, data.to_vec()));
+            }
+        }
+        selected
+    }
 }
 
 #[cfg(test)]
 pub mod tests {
+    use crate::cursor::CursorError;
+
     use super::*;
 
     pub fn create_random_config() -> Config {
-        let key: [u8; KEY_BYTES] = rand::random();
-        Config::new(key.into())
+        Config::random()
     }
 
     #[test]
     fn test_config() {
         let _key = create_random_config();
     }
+
+    #[test]
+    fn test_eq_hash() {
+        use std::collections::HashSet;
+
+        let a = create_random_config();
+        let b = create_random_config();
+        assert_eq!(a, a.clone());
+        assert_ne!(a, b);
+        assert_ne!(a.fingerprint(), *a.key());
+        assert_ne!(a.fingerprint(), b.fingerprint());
+
+        let set = HashSet::from([a.clone(), a.clone(), b]);
+        assert_eq!(set.len(), 2);
+        assert!(set.contains(&a));
+    }
+
+    #[cfg(feature = "argon2")]
+    #[test]
+    fn test_passphrase() {
+        // Cheap parameters to keep the test fast
+        let builder: ConfigBuilder = serde_json::from_str(
+            r#"{ "passphrase": "correct horse", "kdf": "$argon2id$v=19$m=64,t=1,p=1$c2FsdHNhbHRzYWx0" }"#,
+        )
+        .unwrap();
+        assert!(!format!("{builder:?}").contains("correct horse"));
+        let config = builder.clone().build().unwrap();
+        assert_eq!(config, builder.build().unwrap());
+
+        let other_salt = ConfigBuilder::passphrase_with_kdf(
+            "correct horse".into(),
+            "$argon2id$v=19$m=64,t=1,p=1$b3RoZXJzYWx0".into(),
+        );
+        assert_ne!(config, other_salt.build().unwrap());
+
+        let argon2i = ConfigBuilder::passphrase_with_kdf(
+            "correct horse".into(),
+            "$argon2i$v=19$m=64,t=1,p=1$c2FsdHNhbHRzYWx0".into(),
+        );
+        assert!(matches!(argon2i.build(), Err(ConfigBuildError::Kdf(_))));
+
+        // A fresh salt every time
+        let a = ConfigBuilder::passphrase("correct horse".into());
+        let b = ConfigBuilder::passphrase("correct horse".into());
+        assert_ne!(a, b);
+        assert_ne!(a.build().unwrap(), b.build().unwrap());
+
+        let key: ConfigBuilder = serde_json::from_str(r#""AAAA""#).unwrap();
+        assert_eq!(key, ConfigBuilder::key("AAAA".into()));
+    }
+
+    #[test]
+    fn test_serde() {
+        let old = create_random_config().with_key_id(1);
+        let config = create_random_config().with_key_id(2).with_accepted(&old);
+
+        // No key bytes unless opted in
+        let view = config.fingerprint_view();
+        let json = serde_json::to_string(&view).unwrap();
+        for key in [config.key(), old.key()] {
+            assert!(!json.contains(&BASE64_STANDARD_NO_PAD.encode(key)));
+            assert!(!json.contains(&format!("{:?}", key).replace(' ', "")));
+        }
+        assert_eq!(
+            serde_json::from_str::<ConfigFingerprint>(&json).unwrap(),
+            view
+        );
+        let old_view = old.fingerprint_view();
+        let accepted = KeyFingerprint {
+            key_id: old_view.key_id,
+            fingerprint: old_view.fingerprint,
+        };
+        assert_eq!(view.accepted, [accepted]);
+
+        #[derive(Deserialize, Serialize)]
+        struct Persisted {
+            #[serde(with = "expose")]
+            config: Config,
+        }
+        let json = serde_json::to_string(&Persisted {
+            config: config.clone(),
+        })
+        .unwrap();
+        let persisted: Persisted = serde_json::from_str(&json).unwrap();
+        assert_eq!(persisted.config, config);
+        assert_eq!(persisted.config.keys().count(), 2);
+
+        // A bare key array is not taken for a raw key
+        let bare = serde_json::to_string(config.key()).unwrap();
+        assert!(serde_json::from_str::<Config>(&bare).is_err());
+
+        let key = BASE64_STANDARD_NO_PAD.encode([7; KEY_BYTES]);
+        let from_builder: Config = serde_json::from_str(&format!(r#""{key}""#)).unwrap();
+        assert_eq!(from_builder, ConfigBuilder::key(key).build().unwrap());
+    }
+
+    #[test]
+    fn test_zeroize_str() {
+        let mut secret = BASE64_STANDARD_NO_PAD.encode([7; KEY_BYTES]);
+        zeroize_str(&mut secret);
+        assert!(secret.bytes().all(|b| b == 0));
+    }
+
+    #[test]
+    fn test_from_seed() {
+        assert_eq!(Config::from_seed(1), Config::from_seed(1));
+        assert_ne!(Config::from_seed(1), Config::from_seed(2));
+    }
+
+    #[test]
+    fn test_from_raw_key() {
+        let key: [u8; KEY_BYTES] = rand::random();
+        assert_eq!(Config::from_raw_key(key).key(), &key);
+        assert_ne!(Config::new(key.into()).key(), &key);
+    }
+
+    #[test]
+    fn test_shared_key() {
+        let old = create_random_config().with_key_id(1);
+        let config = create_random_config().with_key_id(2).with_accepted(&old);
+        let peer = SocketAddr::from(([127, 0, 0, 1], 0));
+
+        // Every connection gets the same allocation
+        let a = config.key_for(peer, None).unwrap();
+        let b = config.clone().key_for(peer, Some(2)).unwrap();
+        assert!(Arc::ptr_eq(&a, &b));
+        assert!(Arc::ptr_eq(&a, config.shared_key()));
+        let a = config.key_for(peer, Some(1)).unwrap();
+        assert!(Arc::ptr_eq(&a, old.shared_key()));
+        assert!(config.key_for(peer, Some(3)).is_none());
+
+        let shared = Config::from_shared_key(a.clone());
+        assert_eq!(shared.key(), old.key());
+    }
+
+    #[test]
+    fn test_from_env() {
+        let key = BASE64_STANDARD_NO_PAD.encode([7; KEY_BYTES]);
+        let expected = ConfigBuilder::key(key.clone()).build().unwrap();
+        std::env::set_var("TOKIO_CHACHA20_TEST_FROM_ENV", format!("{key}\n"));
+        let config = Config::from_env("TOKIO_CHACHA20_TEST_FROM_ENV").unwrap();
+        assert_eq!(config, expected);
+        assert!(matches!(
+            Config::from_env("TOKIO_CHACHA20_TEST_UNSET"),
+            Err(ConfigLoadError::EnvNotPresent(_))
+        ));
+    }
+
+    #[test]
+    fn test_from_file() {
+        let key = BASE64_STANDARD_NO_PAD.encode([7; KEY_BYTES]);
+        let expected = ConfigBuilder::key(key.clone()).build().unwrap();
+        let dir = std::env::temp_dir().join(format!("tokio_chacha20-{}", rand::random::<u64>()));
+        std::fs::create_dir(&dir).unwrap();
+        let load = |name: &str, contents: &str| {
+            let path = dir.join(name);
+            std::fs::write(&path, contents).unwrap();
+            ConfigBuilder::from_file(path)
+        };
+
+        let builder = load("key", &format!("{key}\n")).unwrap();
+        assert_eq!(builder.build().unwrap(), expected);
+        #[cfg(feature = "config-file")]
+        {
+            let builder = load("key.json", &format!(r#"{{ "key": "{key}" }}"#)).unwrap();
+            assert_eq!(builder.build().unwrap(), expected);
+            let builder = load("bare.json", &format!(r#""{key}""#)).unwrap();
+            assert_eq!(builder.build().unwrap(), expected);
+            let builder = load("key.toml", &format!(r#"key = "{key}""#)).unwrap();
+            assert_eq!(builder.build().unwrap(), expected);
+
+            // The key does not leak through the error
+            let err = load("typo.toml", &format!(r#"kye = "{key}""#)).unwrap_err();
+            assert!(!err.to_string().contains(&key), "{err}");
+            let err = load("broken.json", &format!(r#"{{ "key": "{key}" "#)).unwrap_err();
+            assert!(!err.to_string().contains(&key), "{err}");
+        }
+        assert!(matches!(
+            ConfigBuilder::from_file(dir.join("missing")),
+            Err(ConfigLoadError::Io { .. })
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_keyring() {
+        let old = create_random_config();
+        let new = create_random_config();
+        let keyring = Keyring::new([old.clone(), new.clone()]);
+
+        let msg = b"Hello world!";
+        let record = |config: &Config| {
+            let mut en = EncryptCursor::new_x(*config.key()).with_aad(&[]);
+            let mut buf = vec![0; 1024];
+            let (_, n) = en.encrypt(msg, &mut buf).unwrap();
+            buf.truncate(n);
+            buf.extend_from_slice(&en.tag().unwrap());
+            buf
+        };
+
+        for config in [&old, &new] {
+            let record = record(config);
+            let (selected, data) = keyring.select_x(&record, msg.len()).unwrap();
+            assert_eq!(selected, config);
+            assert_eq!(data, msg);
+
+            // Neither truncated, tampered nor trailing bytes get through
+            assert!(keyring.select_x(&record[1..], msg.len()).is_none());
+            let mut tampered = record.clone();
+            *tampered.last_mut().unwrap() ^= 1;
+            assert!(keyring.select_x(&tampered, msg.len()).is_none());
+            let mut trailing = record.clone();
+            trailing.push(0);
+            assert!(keyring.select_x(&trailing, msg.len()).is_none());
+        }
+
+        let stranger = record(&create_random_config());
+        assert!(keyring.select_x(&stranger, msg.len()).is_none());
+    }
+
+    #[test]
+    fn test_directional_keys() {
+        let config = create_random_config();
+        let (client_rx, client_tx) = directional_keys(config.key(), Role::Client);
+        let (server_rx, server_tx) = directional_keys(config.key(), Role::Server);
+        assert_eq!(client_tx, server_rx);
+        assert_eq!(server_tx, client_rx);
+        assert_ne!(client_tx, client_rx);
+        assert_ne!(client_tx, *config.key());
+    }
+
+    #[test]
+    fn test_derive_key_from_shared_secret() {
+        let shared = rand::random();
+        let key = derive_key_from_shared_secret(shared, "test tunnel");
+        assert_eq!(key, derive_key_from_shared_secret(shared, "test tunnel"));
+        assert_ne!(key, shared);
+        assert_ne!(key, derive_key_from_shared_secret(shared, "test relay"));
+        assert_ne!(
+            key,
+            derive_key_from_shared_secret(rand::random(), "test tunnel")
+        );
+    }
+
+    #[test]
+    fn test_derive_subkey() {
+        let config = create_random_config();
+        let subkey = config.derive_subkey(b"salt", b"info");
+        assert_eq!(subkey, config.derive_subkey(b"salt", b"info"));
+        assert_ne!(subkey, *config.key());
+        assert_ne!(subkey, config.derive_subkey(b"salt", b"other"));
+        assert_ne!(subkey, config.derive_subkey(b"sal", b"tinfo"));
+        assert_ne!(
+            subkey,
+            create_random_config().derive_subkey(b"salt", b"info")
+        );
+    }
+
+    #[test]
+    fn test_key_rotation() {
+        let old = create_random_config().with_key_id(1);
+        let new = create_random_config().with_key_id(2);
+        let rotating = new.clone().with_accepted(&old);
+        assert_eq!(rotating.key_by_id(1), Some(old.key()));
+        assert_eq!(rotating.key_by_id(2), Some(new.key()));
+        assert_eq!(rotating.key_by_id(3), None);
+
+        let msg = b"Hello world!";
+        let encrypt = |config: &Config| {
+            let mut buf = vec![0; 1024];
+            let (_, n) = config.encrypt_cursor().encrypt(msg, &mut buf).unwrap();
+            buf.truncate(n);
+            buf
+        };
+
+        // Both old and new writers get through during the rotation
+        for writer in [&old, &new] {
+            let mut buf = encrypt(writer);
+            let i = rotating
+                .decrypt_cursor()
+                .decrypt(&mut buf)
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buf[i..], msg);
+        }
+
+        // The old writer is turned away once the rotation is over
+        let mut buf = encrypt(&old);
+        assert_eq!(
+            new.decrypt_cursor().decrypt(&mut buf),
+            Err(CursorError::UnknownKeyId(1))
+        );
+    }
 }
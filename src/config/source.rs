@@ -0,0 +1,98 @@
+use std::{future::Future, io, path::PathBuf, pin::Pin};
+
+use crate::key::{KeyId, SecretKey};
+
+use super::{Config, ConfigBuilder, ConfigLoadError};
+
+/// A future returned by [`KeySource::fetch`]
+pub type FetchKey<'a> = Pin<Box<dyn Future<Output = io::Result<SecretKey>> + Send + 'a>>;
+
+/// Retrieves keys by ID from outside the process, e.g. a cloud KMS or an OS keychain
+pub trait KeySource: Send + Sync {
+    /// The key named `id`, unwrapped if it is stored wrapped
+    ///
+    /// [`io::ErrorKind::NotFound`] if there is no such key.
+    fn fetch(&self, id: KeyId) -> FetchKey<'_>;
+}
+
+/// Keys in the environment variables `<prefix><id>`, loaded like [`Config::from_env`]
+#[derive(Debug, Clone)]
+pub struct EnvKeySource {
+    prefix: String,
+}
+impl EnvKeySource {
+    pub fn new(prefix: impl Into<String>) -> Self {
+        Self {
+            prefix: prefix.into(),
+        }
+    }
+}
+impl KeySource for EnvKeySource {
+    fn fetch(&self, id: KeyId) -> FetchKey<'_> {
+        let config = Config::from_env(&format!("{}{id}", self.prefix));
+        Box::pin(async move { Ok(SecretKey::new(*config.map_err(load_error)?.key())) })
+    }
+}
+
+/// Keys in the files `<dir>/<id>`, loaded like [`ConfigBuilder::from_file`]
+#[derive(Debug, Clone)]
+pub struct FileKeySource {
+    dir: PathBuf,
+}
+impl FileKeySource {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+impl KeySource for FileKeySource {
+    fn fetch(&self, id: KeyId) -> FetchKey<'_> {
+        let path = self.dir.join(id.to_string());
+        Box::pin(async move {
+            let config = tokio::task::spawn_blocking(move || {
+                ConfigBuilder::from_file(path).and_then(|b| Ok(b.build()?))
+            })
+            .await?
+            .map_err(load_error)?;
+            Ok(SecretKey::new(*config.key()))
+        })
+    }
+}
+
+fn load_error(e: ConfigLoadError) -> io::Error {
+    let kind = match &e {
+        ConfigLoadError::EnvNotPresent(_) => io::ErrorKind::NotFound,
+        ConfigLoadError::Io { source, .. } => source.kind(),
+        _ => io::ErrorKind::InvalidData,
+    };
+    io::Error::new(kind, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::prelude::*;
+
+    use crate::KEY_BYTES;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sources() {
+        let key = BASE64_STANDARD_NO_PAD.encode([7; KEY_BYTES]);
+        let expected = ConfigBuilder::key(key.clone()).build().unwrap();
+
+        std::env::set_var("TOKIO_CHACHA20_TEST_SOURCE_3", &key);
+        let env = EnvKeySource::new("TOKIO_CHACHA20_TEST_SOURCE_");
+        assert_eq!(env.fetch(3).await.unwrap().expose_secret(), expected.key());
+        let err = env.fetch(4).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        let dir = std::env::temp_dir().join(format!("tokio_chacha20-{}", rand::random::<u64>()));
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("3"), &key).unwrap();
+        let file = FileKeySource::new(&dir);
+        assert_eq!(file.fetch(3).await.unwrap().expose_secret(), expected.key());
+        let err = file.fetch(4).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
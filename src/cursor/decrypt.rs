@@ -1,68 +1,284 @@
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
 use std::io;
 
-use crate::{mac::poly1305_key_gen, KEY_BYTES, NONCE_BYTES};
+use crate::{
+    cipher::StreamCipher,
+    key::{KeyId, KeyVersion, SecretKey},
+    mac::{poly1305_key_gen, AeadHasher, BLOCK_BYTES},
+    KEY_BYTES, NONCE_BYTES, X_NONCE_BYTES,
+};
 
-use super::{NonceWriteCursor, WriteCursorState};
+use super::{CursorError, NonceWriteCursor, UserDataCursor, WriteCursorState};
 
+#[derive(Debug, Clone)]
 pub struct DecryptCursor {
+    key: SecretKey,
     state: Option<WriteCursorState>,
+    /// Waiting for the nonce to key the MAC
+    aad: Option<Vec<u8>>,
+    aead: Option<AeadHasher>,
+    /// Plaintext received before the nonce, the key ID and the key version included
+    prefix: Vec<u8>,
+    /// Length of the prefix without the key ID and the key version
+    prefix_len: usize,
+    /// Expect a key ID after the prefix
+    key_id: bool,
+    /// Candidates for the key ID until one is picked
+    keys: Vec<(KeyId, SecretKey)>,
+    /// Expect a key version after the key ID
+    key_version: bool,
+    consumed: u64,
+    produced: u64,
 }
 
 impl DecryptCursor {
     pub fn new(key: [u8; KEY_BYTES]) -> Self {
         let state = Some(WriteCursorState::Nonce(NonceWriteCursor::new(key)));
-        Self { state }
+        Self::from_state(key, state)
     }
     pub fn new_x(key: [u8; KEY_BYTES]) -> Self {
         let state = Some(WriteCursorState::Nonce(NonceWriteCursor::new_x(key)));
-        Self { state }
+        Self::from_state(key, state)
+    }
+    fn from_state(key: [u8; KEY_BYTES], state: Option<WriteCursorState>) -> Self {
+        Self {
+            key: key.into(),
+            state,
+            aad: None,
+            aead: None,
+            prefix: Vec::new(),
+            prefix_len: 0,
+            key_id: false,
+            keys: Vec::new(),
+            key_version: false,
+            consumed: 0,
+            produced: 0,
+        }
+    }
+
+    /// Expect `len` bytes of plaintext before the nonce
+    pub fn with_prefix_len(mut self, len: usize) -> Self {
+        self.prefix_len = len;
+        self
     }
 
-    /// Return the start index of the decrypted user data
-    pub fn decrypt(&mut self, buf: &mut [u8]) -> Option<usize> {
-        let mut pos = 0;
+    /// Expect a key ID after the prefix and switch to the key of that ID
+    pub fn with_keys(mut self, keys: impl IntoIterator<Item = (KeyId, [u8; KEY_BYTES])>) -> Self {
+        self.keys = keys.into_iter().map(|(id, key)| (id, key.into())).collect();
+        self.key_id = true;
+        self
+    }
+
+    /// Expect a key version after the key ID, authenticated ahead of the AAD of [`Self::with_aad`]
+    ///
+    /// [`CursorError::KeyVersionWithoutAad`] once the version arrives if [`Self::with_aad`] is never called.
+    pub fn with_key_version(mut self) -> Self {
+        self.key_version = true;
+        self
+    }
+
+    /// The key version once the prefix has been received
+    ///
+    /// Only trust it once the tag of [`Self::with_aad`] is verified.
+    pub fn key_version(&self) -> Option<KeyVersion> {
+        match self.key_version {
+            true => self.prefix.get(self.header_len() - 1).copied(),
+            false => None,
+        }
+    }
+
+    /// Length of the plaintext header made of the prefix, the key ID and the key version
+    ///
+    /// The `header_bytes` of [`plaintext_len_with_header`](crate::plaintext_len_with_header).
+    pub fn header_len(&self) -> usize {
+        self.prefix_len + usize::from(self.key_id) + usize::from(self.key_version)
+    }
+
+    /// The plaintext prefix received so far
+    pub fn prefix(&self) -> &[u8] {
+        &self.prefix
+    }
+
+    /// Authenticate `aad` and the ciphertext as AEAD_CHACHA20_POLY1305 from RFC 8439
+    ///
+    /// Must be called before any user data is decrypted.
+    pub fn with_aad(mut self, aad: &[u8]) -> Self {
+        self.aad = Some(aad.to_vec());
+        self
+    }
+
+    /// Tag over the AAD and the ciphertext so far if [`Self::with_aad`] was called
+    ///
+    /// `None` until the whole nonce has been collected.
+    pub fn tag(&self) -> Option<[u8; BLOCK_BYTES]> {
+        match (&self.aead, &self.aad) {
+            (Some(aead), _) => Some(aead.current_tag()),
+            (None, Some(aad)) => self
+                .poly1305_key()
+                .ok()
+                .map(|key| AeadHasher::new(key, aad).current_tag()),
+            (None, None) => None,
+        }
+    }
+
+    /// Switch to `key` keeping the nonce and the keystream position
+    ///
+    /// The tag of [`Self::with_aad`] keeps using the one-time key of the old key once the nonce is in.
+    pub fn rekey(&mut self, key: [u8; KEY_BYTES]) -> Result<(), CursorError> {
+        match self.state.as_mut().ok_or(CursorError::WrongState)? {
+            WriteCursorState::Nonce(c) => c.set_key(key),
+            WriteCursorState::UserData(c) => c.rekey(key),
+        }
+        self.key = key.into();
+        Ok(())
+    }
+
+    /// Restart the keystream from `nonce` without expecting it on the wire
+    ///
+    /// The byte counters carry on and the tag of [`Self::with_aad`] is dropped.
+    pub fn reset_with_nonce(&mut self, nonce: [u8; NONCE_BYTES]) {
+        self.reset(StreamCipher::new(*self.key.expose_secret(), nonce));
+    }
+    pub fn reset_with_x_nonce(&mut self, nonce: [u8; X_NONCE_BYTES]) {
+        self.reset(StreamCipher::new_x(*self.key.expose_secret(), nonce));
+    }
+    fn reset(&mut self, cipher: StreamCipher) {
+        self.state = Some(WriteCursorState::UserData(UserDataCursor::new(cipher)));
+        self.aad = None;
+        self.aead = None;
+    }
+
+    /// Return the start index of the decrypted user data or `None` if `buf` held only prefix and nonce
+    pub fn decrypt(&mut self, buf: &mut [u8]) -> Result<Option<usize>, CursorError> {
+        if self.state.is_none() {
+            return Err(CursorError::WrongState);
+        }
+        self.consumed += buf.len() as u64;
+
+        // Plaintext header
+        let header_len = self.header_len();
+        let mut pos = (header_len - self.prefix.len()).min(buf.len());
+        self.prefix.extend_from_slice(&buf[..pos]);
+        if self.prefix.len() != header_len {
+            return Ok(None);
+        }
+        // The header has just been completed
+        if pos != 0 {
+            if let Some(version) = self.key_version() {
+                let aad = self.aad.as_mut().ok_or(CursorError::KeyVersionWithoutAad)?;
+                aad.insert(0, version);
+            }
+        }
+        if self.key_id && !self.keys.is_empty() {
+            let id = self.prefix[self.prefix_len];
+            let Some((_, key)) = self.keys.iter().find(|(i, _)| *i == id) else {
+                return Err(CursorError::UnknownKeyId(id));
+            };
+            let key = *key.expose_secret();
+            self.keys = Vec::new();
+            self.rekey(key)?;
+        }
 
         // Loop for state transitions from `Nonce` to `UserData`
         loop {
-            match self.state.take().unwrap() {
+            match self.state.take().ok_or(CursorError::WrongState)? {
                 WriteCursorState::Nonce(c) => {
-                    let mut rdr: io::Cursor<&[u8]> = io::Cursor::new(buf);
-                    let c = c.collect_nonce_from(&mut rdr);
+                    let (c, n) = c.collect_nonce(&buf[pos..]);
                     self.state = Some(c);
-                    pos = rdr.position() as usize;
-                    if pos == rdr.get_ref().len() {
-                        return None;
+                    pos += n;
+                    if pos == buf.len() {
+                        return Ok(None);
                     }
                 }
                 WriteCursorState::UserData(mut c) => {
+                    if let Some(aad) = self.aad.take() {
+                        let block = c.cipher().block();
+                        let key = poly1305_key_gen(block.key(), block.nonce());
+                        self.aead = Some(AeadHasher::new(key, &aad));
+                    }
+                    if let Some(aead) = &mut self.aead {
+                        aead.update(&buf[pos..]);
+                    }
                     c.xor(&mut buf[pos..]);
                     self.state = Some(WriteCursorState::UserData(c));
-                    return Some(pos);
+                    self.produced += (buf.len() - pos) as u64;
+                    return Ok(Some(pos));
                 }
             }
         }
     }
 
-    pub fn remaining_nonce_size(&self) -> usize {
-        match self.state.as_ref().unwrap() {
-            WriteCursorState::Nonce(c) => c.remaining_nonce_size(),
-            WriteCursorState::UserData(_) => 0,
+    /// Pull ciphertext from `r` and decrypt it into `out`
+    ///
+    /// The prefix and the nonce are read through as they come, so the result only holds user data.
+    /// Return the amount of bytes written to `out`; `0` means EOF.
+    #[cfg(feature = "std")]
+    pub fn decrypt_from(&mut self, r: &mut impl io::Read, out: &mut [u8]) -> io::Result<usize> {
+        if out.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let n = match r.read(out) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if n == 0 {
+                return match self.state {
+                    Some(WriteCursorState::UserData(_)) => Ok(0),
+                    _ => Err(crate::stream::Error::NonceTruncated.into()),
+                };
+            }
+            let decrypted = self
+                .decrypt(&mut out[..n])
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            if let Some(i) = decrypted {
+                out.copy_within(i..n, 0);
+                return Ok(n - i);
+            }
+        }
+    }
+
+    /// Total bytes passed to [`Self::decrypt`] including the prefix and the nonce
+    pub fn bytes_consumed(&self) -> u64 {
+        self.consumed
+    }
+
+    /// Total user data bytes decrypted
+    pub fn bytes_produced(&self) -> u64 {
+        self.produced
+    }
+
+    /// Keystream offset of the next ciphertext byte once the nonce is in
+    pub fn keystream_position(&self) -> Option<u64> {
+        match self.state.as_ref()? {
+            WriteCursorState::Nonce(_) => None,
+            WriteCursorState::UserData(c) => Some(c.position()),
+        }
+    }
+
+    pub fn remaining_nonce_size(&self) -> Result<usize, CursorError> {
+        match self.state.as_ref().ok_or(CursorError::WrongState)? {
+            WriteCursorState::Nonce(c) => Ok(c.remaining_nonce_size()),
+            WriteCursorState::UserData(_) => Ok(0),
         }
     }
 
-    pub fn poly1305_key(&self) -> Option<[u8; KEY_BYTES]> {
+    pub fn poly1305_key(&self) -> Result<[u8; KEY_BYTES], CursorError> {
         self.poly1305_key_map_nonce(|x| x)
     }
 
     pub fn poly1305_key_map_nonce(
         &self,
         map_nonce: impl Fn([u8; NONCE_BYTES]) -> [u8; NONCE_BYTES],
-    ) -> Option<[u8; KEY_BYTES]> {
-        let WriteCursorState::UserData(c) = self.state.as_ref().unwrap() else {
-            return None;
+    ) -> Result<[u8; KEY_BYTES], CursorError> {
+        let WriteCursorState::UserData(c) = self.state.as_ref().ok_or(CursorError::WrongState)?
+        else {
+            return Err(CursorError::NonceIncomplete);
         };
         let key = c.cipher().block().key();
         let nonce = c.cipher().block().nonce();
-        Some(poly1305_key_gen(key, map_nonce(nonce)))
+        Ok(poly1305_key_gen(key, map_nonce(nonce)))
     }
 }
@@ -1,35 +1,214 @@
-use crate::{mac::poly1305_key_gen, KEY_BYTES, NONCE_BYTES};
+use alloc::vec::Vec;
 
-use super::{NonceReadCursor, ReadCursorState};
+use crate::{
+    cipher::StreamCipher,
+    key::{KeyId, KeyVersion, SecretKey},
+    mac::{poly1305_key_gen, AeadHasher, BLOCK_BYTES},
+    KEY_BYTES, NONCE_BYTES, X_NONCE_BYTES,
+};
+
+use super::{CursorError, NonceBuf, NonceReadCursor, ReadCursorState, UserDataCursor};
 
 pub struct EncryptCursor {
+    key: SecretKey,
     state: Option<ReadCursorState>,
+    /// Kept to fold in a key version set afterwards
+    aad: Option<Vec<u8>>,
+    aead: Option<AeadHasher>,
+    /// Plaintext sent before the nonce, the key ID and the key version
+    prefix: Vec<u8>,
+    /// Position in the whole plaintext header
+    prefix_pos: usize,
+    key_id: Option<KeyId>,
+    key_version: Option<KeyVersion>,
+    consumed: u64,
+    produced: u64,
 }
 
 impl EncryptCursor {
+    #[cfg(feature = "std")]
     pub fn new(key: [u8; KEY_BYTES]) -> Self {
         let state = Some(ReadCursorState::Nonce(NonceReadCursor::new(key)));
-        Self { state }
+        Self::from_state(key, state)
     }
+    #[cfg(feature = "std")]
     pub fn new_x(key: [u8; KEY_BYTES]) -> Self {
         let state = Some(ReadCursorState::Nonce(NonceReadCursor::new_x(key)));
-        Self { state }
+        Self::from_state(key, state)
+    }
+    /// `nonce`: Must be unique per `key`
+    pub fn from_nonce(key: [u8; KEY_BYTES], nonce: [u8; NONCE_BYTES]) -> Self {
+        let state = Some(ReadCursorState::Nonce(NonceReadCursor::from_nonce(
+            key, nonce,
+        )));
+        Self::from_state(key, state)
+    }
+    /// `nonce`: Must be unique per `key`
+    pub fn from_x_nonce(key: [u8; KEY_BYTES], nonce: [u8; X_NONCE_BYTES]) -> Self {
+        let state = Some(ReadCursorState::Nonce(NonceReadCursor::from_x_nonce(
+            key, nonce,
+        )));
+        Self::from_state(key, state)
+    }
+    /// `nonce`: Must be unique per `key`
+    pub fn from_nonce_buf(key: [u8; KEY_BYTES], nonce: NonceBuf) -> Self {
+        let state = Some(ReadCursorState::Nonce(NonceReadCursor::from_nonce_buf(
+            key, nonce,
+        )));
+        Self::from_state(key, state)
+    }
+
+    fn from_state(key: [u8; KEY_BYTES], state: Option<ReadCursorState>) -> Self {
+        #[cfg(feature = "nonce-tracking")]
+        if let Some(ReadCursorState::Nonce(c)) = &state {
+            crate::nonce_tracker::record(&key, c.nonce());
+        }
+        Self {
+            key: key.into(),
+            state,
+            aad: None,
+            aead: None,
+            prefix: Vec::new(),
+            prefix_pos: 0,
+            key_id: None,
+            key_version: None,
+            consumed: 0,
+            produced: 0,
+        }
+    }
+
+    /// Send `prefix` in plaintext before the nonce
+    pub fn with_prefix(mut self, prefix: &[u8]) -> Self {
+        self.prefix = prefix.to_vec();
+        self.prefix_pos = 0;
+        self
+    }
+
+    /// Send `id` after the plaintext prefix so that the reader can pick the key
+    pub fn with_key_id(mut self, id: KeyId) -> Self {
+        self.key_id = Some(id);
+        self
+    }
+
+    /// Send `version` after the key ID and authenticate it ahead of the AAD of [`Self::with_aad`]
+    ///
+    /// [`CursorError::KeyVersionWithoutAad`] on the first [`Self::encrypt`] if [`Self::with_aad`] is never called.
+    pub fn with_key_version(mut self, version: KeyVersion) -> Self {
+        self.key_version = Some(version);
+        self.init_aead();
+        self
+    }
+
+    /// Authenticate `aad` and the ciphertext as AEAD_CHACHA20_POLY1305 from RFC 8439
+    ///
+    /// Must be called before any user data is encrypted.
+    pub fn with_aad(mut self, aad: &[u8]) -> Self {
+        self.aad = Some(aad.to_vec());
+        self.init_aead();
+        self
+    }
+
+    fn init_aead(&mut self) {
+        let Some(aad) = &self.aad else {
+            return;
+        };
+        // A lost state fails the next `encrypt` anyway
+        if let Ok(key) = self.poly1305_key() {
+            let aad = match self.key_version {
+                Some(version) => [&[version], aad.as_slice()].concat(),
+                None => aad.clone(),
+            };
+            self.aead = Some(AeadHasher::new(key, &aad));
+        }
+    }
+
+    /// Length of the plaintext header made of the prefix, the key ID and the key version
+    ///
+    /// The `header_bytes` of [`ciphertext_len_with_header`](crate::ciphertext_len_with_header).
+    pub fn header_len(&self) -> usize {
+        self.prefix.len()
+            + usize::from(self.key_id.is_some())
+            + usize::from(self.key_version.is_some())
+    }
+
+    /// Tag over the AAD and the ciphertext so far if [`Self::with_aad`] was called
+    pub fn tag(&self) -> Option<[u8; BLOCK_BYTES]> {
+        self.aead.as_ref().map(|a| a.current_tag())
+    }
+
+    /// Switch to `key` keeping the nonce and the keystream position
+    ///
+    /// The tag of [`Self::with_aad`] keeps using the one-time key of the old key.
+    pub fn rekey(&mut self, key: [u8; KEY_BYTES]) -> Result<(), CursorError> {
+        match self.state.as_mut().ok_or(CursorError::WrongState)? {
+            ReadCursorState::Nonce(c) => c.set_key(key),
+            ReadCursorState::UserData(c) => c.rekey(key),
+        }
+        self.key = key.into();
+        Ok(())
+    }
+
+    /// Restart the keystream from `nonce` without sending it
+    ///
+    /// The byte counters carry on and the tag of [`Self::with_aad`] is dropped.
+    /// `nonce`: Must be unique per key
+    pub fn reset_with_nonce(&mut self, nonce: [u8; NONCE_BYTES]) {
+        #[cfg(feature = "nonce-tracking")]
+        crate::nonce_tracker::record(self.key.expose_secret(), &nonce);
+        self.reset(StreamCipher::new(*self.key.expose_secret(), nonce));
+    }
+    /// `nonce`: Must be unique per key
+    pub fn reset_with_x_nonce(&mut self, nonce: [u8; X_NONCE_BYTES]) {
+        #[cfg(feature = "nonce-tracking")]
+        crate::nonce_tracker::record(self.key.expose_secret(), &nonce);
+        self.reset(StreamCipher::new_x(*self.key.expose_secret(), nonce));
+    }
+    fn reset(&mut self, cipher: StreamCipher) {
+        self.state = Some(ReadCursorState::UserData(UserDataCursor::new(cipher)));
+        self.aad = None;
+        self.aead = None;
     }
 
     /// Return the amount of bytes read from `from` and the amount of bytes written to `to`
-    pub fn encrypt(&mut self, from: &[u8], to: &mut [u8]) -> (usize, usize) {
+    pub fn encrypt(&mut self, from: &[u8], to: &mut [u8]) -> Result<(usize, usize), CursorError> {
+        if self.state.is_none() {
+            return Err(CursorError::WrongState);
+        }
+
+        // Plaintext header
+        let header_len = self.header_len();
+        if self.prefix_pos != header_len && self.key_version.is_some() && self.aead.is_none() {
+            return Err(CursorError::KeyVersionWithoutAad);
+        }
+        let header = self
+            .prefix
+            .iter()
+            .chain(&self.key_id)
+            .chain(&self.key_version)
+            .skip(self.prefix_pos);
         let mut to_amt = 0;
+        for (to, b) in to.iter_mut().zip(header) {
+            *to = *b;
+            to_amt += 1;
+        }
+        self.prefix_pos += to_amt;
+        if self.prefix_pos != header_len {
+            self.produced += to_amt as u64;
+            return Ok((0, to_amt));
+        }
 
         // Loop for state transitions from `Nonce` to `UserData`
         loop {
-            match self.state.take().unwrap() {
+            match self.state.take().ok_or(CursorError::WrongState)? {
                 ReadCursorState::Nonce(c) => {
-                    let n = c.remaining_nonce().len().min(to.len());
-                    to[..n].copy_from_slice(&c.remaining_nonce()[..n]);
+                    let rest = &mut to[to_amt..];
+                    let n = c.remaining_nonce().len().min(rest.len());
+                    rest[..n].copy_from_slice(&c.remaining_nonce()[..n]);
                     self.state = Some(c.consume_nonce(n));
                     to_amt += n;
-                    if n == to.len() {
-                        return (0, to_amt);
+                    if to_amt == to.len() {
+                        self.produced += to_amt as u64;
+                        return Ok((0, to_amt));
                     }
                 }
                 ReadCursorState::UserData(mut c) => {
@@ -38,29 +217,90 @@ impl EncryptCursor {
                     to[..n].copy_from_slice(&from[..n]);
                     to_amt += n;
                     c.xor(&mut to[..n]);
+                    if let Some(aead) = &mut self.aead {
+                        aead.update(&to[..n]);
+                    }
                     self.state = Some(ReadCursorState::UserData(c));
-                    return (n, to_amt);
+                    self.consumed += n as u64;
+                    self.produced += to_amt as u64;
+                    return Ok((n, to_amt));
                 }
             }
         }
     }
 
-    pub fn poly1305_key(&self) -> [u8; KEY_BYTES] {
+    /// Encrypt `buf` in place without copying
+    ///
+    /// The prefix and the whole nonce must have been emitted by [`Self::encrypt`] beforehand.
+    pub fn encrypt_in_place(&mut self, buf: &mut [u8]) -> Result<(), CursorError> {
+        let header_sent = self.prefix_pos == self.header_len();
+        let c = match self.state.as_mut().ok_or(CursorError::WrongState)? {
+            ReadCursorState::UserData(c) if header_sent => c,
+            _ => return Err(CursorError::NonceIncomplete),
+        };
+        c.xor(buf);
+        if let Some(aead) = &mut self.aead {
+            aead.update(buf);
+        }
+        self.consumed += buf.len() as u64;
+        self.produced += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Fill the slices of `to` in order
+    ///
+    /// Return the amount of bytes read from `from` and the amount of bytes written across `to`
+    #[cfg(feature = "std")]
+    pub fn encrypt_vectored(
+        &mut self,
+        from: &[u8],
+        to: &mut [std::io::IoSliceMut<'_>],
+    ) -> Result<(usize, usize), CursorError> {
+        let mut from_amt = 0;
+        let mut to_amt = 0;
+        for to in to.iter_mut() {
+            let (f, t) = self.encrypt(&from[from_amt..], to)?;
+            from_amt += f;
+            to_amt += t;
+            if t < to.len() {
+                break;
+            }
+        }
+        Ok((from_amt, to_amt))
+    }
+
+    /// Total plaintext bytes read from `from`
+    pub fn bytes_consumed(&self) -> u64 {
+        self.consumed
+    }
+
+    /// Total bytes written to `to` including the prefix and the nonce
+    pub fn bytes_produced(&self) -> u64 {
+        self.produced
+    }
+
+    /// Keystream offset of the next plaintext byte once the nonce is out
+    pub fn keystream_position(&self) -> Option<u64> {
+        match self.state.as_ref()? {
+            ReadCursorState::Nonce(_) => None,
+            ReadCursorState::UserData(c) => Some(c.position()),
+        }
+    }
+
+    pub fn poly1305_key(&self) -> Result<[u8; KEY_BYTES], CursorError> {
         self.poly1305_key_map_nonce(|x| x)
     }
 
     pub fn poly1305_key_map_nonce(
         &self,
         map_nonce: impl Fn([u8; NONCE_BYTES]) -> [u8; NONCE_BYTES],
-    ) -> [u8; KEY_BYTES] {
-        let key = match self.state.as_ref().unwrap() {
-            ReadCursorState::Nonce(c) => *c.key(),
-            ReadCursorState::UserData(c) => c.cipher().block().key(),
-        };
-        let nonce = match self.state.as_ref().unwrap() {
-            ReadCursorState::Nonce(c) => c.chacha20_nonce(),
-            ReadCursorState::UserData(c) => c.cipher().block().nonce(),
+    ) -> Result<[u8; KEY_BYTES], CursorError> {
+        // XChaCha20 keys the MAC with the subkey like the keystream
+        let block = match self.state.as_ref().ok_or(CursorError::WrongState)? {
+            ReadCursorState::Nonce(c) => c.stream_cipher().block().clone(),
+            ReadCursorState::UserData(c) => c.cipher().block().clone(),
         };
-        poly1305_key_gen(key, map_nonce(nonce))
+        let (key, nonce) = (block.key(), block.nonce());
+        Ok(poly1305_key_gen(key, map_nonce(nonce)))
     }
 }
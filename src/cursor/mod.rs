@@ -1,6 +1,6 @@
+mod nonce_buf;
+pub use nonce_buf::{NonceBuf, NonceLengthError};
 mod nonce_read;
-use std::io::{self, BufRead};
-
 pub use nonce_read::{NonceReadCursor, ReadCursorState};
 mod nonce_write;
 pub use nonce_write::{NonceWriteCursor, WriteCursorState};
@@ -10,56 +10,107 @@ mod decrypt;
 pub use decrypt::DecryptCursor;
 mod encrypt;
 pub use encrypt::EncryptCursor;
+mod session;
+pub use session::Session;
+mod tagged_decrypt;
+pub use tagged_decrypt::TaggedDecryptCursor;
+
+use crate::{
+    cipher::{chacha20_nonce_from_xnonce, StreamCipher},
+    key::KeyId,
+    KEY_BYTES, NONCE_BYTES, X_NONCE_BYTES,
+};
 
-use crate::{cipher::chacha20_nonce_from_xnonce, NONCE_BYTES, X_NONCE_BYTES};
+/// Misuse of [`EncryptCursor`] and [`DecryptCursor`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CursorError {
+    /// A previous call panicked midway and left no state behind
+    WrongState,
+    /// The operation needs the whole nonce
+    NonceIncomplete,
+    /// The key ID in the prefix matches none of the keys
+    UnknownKeyId(KeyId),
+    /// A key version is on the wire without [`EncryptCursor::with_aad`] or [`DecryptCursor::with_aad`] to authenticate it
+    KeyVersionWithoutAad,
+}
+impl core::fmt::Display for CursorError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            CursorError::WrongState => write!(f, "cursor state was lost by a previous call"),
+            CursorError::NonceIncomplete => write!(f, "nonce is incomplete"),
+            CursorError::UnknownKeyId(id) => write!(f, "unknown key ID {id}"),
+            CursorError::KeyVersionWithoutAad => {
+                write!(f, "key version is not authenticated without AAD")
+            }
+        }
+    }
+}
+#[cfg(feature = "std")]
+impl std::error::Error for CursorError {}
 
+/// A nonce and the position of its next unprocessed byte
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 enum NonceCursor {
-    Nonce(io::Cursor<[u8; NONCE_BYTES]>),
-    XNonce(io::Cursor<[u8; X_NONCE_BYTES]>),
+    Nonce([u8; NONCE_BYTES], usize),
+    XNonce([u8; X_NONCE_BYTES], usize),
+}
+impl From<NonceBuf> for NonceCursor {
+    fn from(nonce: NonceBuf) -> Self {
+        match nonce {
+            NonceBuf::Nonce(nonce) => NonceCursor::Nonce(nonce, 0),
+            NonceBuf::XNonce(nonce) => NonceCursor::XNonce(nonce, 0),
+        }
+    }
 }
 impl NonceCursor {
     pub fn consume(&mut self, amt: usize) {
         match self {
-            NonceCursor::Nonce(cursor) => cursor.consume(amt),
-            NonceCursor::XNonce(cursor) => cursor.consume(amt),
+            NonceCursor::Nonce(nonce, pos) => *pos = (*pos + amt).min(nonce.len()),
+            NonceCursor::XNonce(nonce, pos) => *pos = (*pos + amt).min(nonce.len()),
         }
     }
     pub fn complete(&self) -> bool {
+        self.remaining().is_empty()
+    }
+    pub fn nonce(&self) -> &[u8] {
         match self {
-            NonceCursor::Nonce(cursor) => cursor.position() as usize == cursor.get_ref().len(),
-            NonceCursor::XNonce(cursor) => cursor.position() as usize == cursor.get_ref().len(),
+            NonceCursor::Nonce(nonce, _) => nonce,
+            NonceCursor::XNonce(nonce, _) => nonce,
         }
     }
     pub fn remaining(&self) -> &[u8] {
         match self {
-            NonceCursor::Nonce(cursor) => &cursor.get_ref()[cursor.position() as usize..],
-            NonceCursor::XNonce(cursor) => &cursor.get_ref()[cursor.position() as usize..],
+            NonceCursor::Nonce(nonce, pos) => &nonce[*pos..],
+            NonceCursor::XNonce(nonce, pos) => &nonce[*pos..],
         }
     }
     pub fn remaining_mut(&mut self) -> &mut [u8] {
         match self {
-            NonceCursor::Nonce(cursor) => {
-                let pos = cursor.position() as usize;
-                &mut cursor.get_mut()[pos..]
-            }
-            NonceCursor::XNonce(cursor) => {
-                let pos = cursor.position() as usize;
-                &mut cursor.get_mut()[pos..]
-            }
+            NonceCursor::Nonce(nonce, pos) => &mut nonce[*pos..],
+            NonceCursor::XNonce(nonce, pos) => &mut nonce[*pos..],
         }
     }
     pub fn chacha20_nonce(&self) -> [u8; NONCE_BYTES] {
         match self {
-            NonceCursor::Nonce(cursor) => *cursor.get_ref(),
-            NonceCursor::XNonce(cursor) => chacha20_nonce_from_xnonce(*cursor.get_ref()),
+            NonceCursor::Nonce(nonce, _) => *nonce,
+            NonceCursor::XNonce(nonce, _) => chacha20_nonce_from_xnonce(*nonce),
+        }
+    }
+    pub fn stream_cipher(&self, key: [u8; KEY_BYTES]) -> StreamCipher {
+        match self {
+            NonceCursor::Nonce(nonce, _) => StreamCipher::new(key, *nonce),
+            NonceCursor::XNonce(nonce, _) => StreamCipher::new_x(key, *nonce),
         }
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
-    use crate::config::tests::create_random_config;
+    use crate::{
+        config::tests::create_random_config,
+        mac::{TagMismatch, BLOCK_BYTES},
+    };
 
     use super::*;
 
@@ -73,12 +124,310 @@ mod tests {
         let mut buf = [0; 1024];
 
         for _ in 0..1024 {
-            let (_, n) = en.encrypt(msg, &mut buf);
-            let i = de.decrypt(&mut buf[..n]).unwrap();
+            let (_, n) = en.encrypt(msg, &mut buf).unwrap();
+            let i = de.decrypt(&mut buf[..n]).unwrap().unwrap();
             assert_eq!(&buf[i..n], &msg[..]);
 
             let n = en.encrypt(msg, &mut []);
-            assert_eq!(n, (0, 0));
+            assert_eq!(n, Ok((0, 0)));
+        }
+    }
+
+    #[test]
+    fn test_nonce_buf() {
+        let config = create_random_config();
+        let nonce: [u8; X_NONCE_BYTES] = rand::random();
+        assert_eq!(NonceBuf::try_from(&nonce[..1]), Err(NonceLengthError(1)));
+        assert_eq!(
+            NonceBuf::from_slice(&nonce[..NONCE_BYTES]),
+            Ok(NonceBuf::Nonce(nonce[..NONCE_BYTES].try_into().unwrap()))
+        );
+        let nonce = NonceBuf::from_slice(&nonce).unwrap();
+
+        let msg = b"Hello world!";
+        let mut en = EncryptCursor::from_nonce_buf(*config.key(), nonce);
+        let mut de = DecryptCursor::new_x(*config.key());
+        let mut buf = [0; 1024];
+        let (_, n) = en.encrypt(msg, &mut buf).unwrap();
+        assert_eq!(&buf[..X_NONCE_BYTES], nonce.as_slice());
+        let i = de.decrypt(&mut buf[..n]).unwrap().unwrap();
+        assert_eq!(&buf[i..n], msg);
+    }
+
+    #[test]
+    fn test_rekey_reset() {
+        let config = create_random_config();
+        let new_key = *create_random_config().key();
+
+        let msg = b"Hello world!";
+        let mut en = EncryptCursor::new_x(*config.key());
+        let mut de = DecryptCursor::new_x(*config.key());
+        let mut buf = [0; 1024];
+
+        let (_, n) = en.encrypt(msg, &mut buf).unwrap();
+        de.decrypt(&mut buf[..n]).unwrap().unwrap();
+
+        en.rekey(new_key).unwrap();
+        de.rekey(new_key).unwrap();
+        let (_, n) = en.encrypt(msg, &mut buf).unwrap();
+        assert_eq!(de.decrypt(&mut buf[..n]), Ok(Some(0)));
+        assert_eq!(&buf[..n], msg);
+        assert_eq!(en.keystream_position(), Some(2 * msg.len() as u64));
+
+        let nonce = rand::random();
+        en.reset_with_nonce(nonce);
+        de.reset_with_nonce(nonce);
+        let (_, n) = en.encrypt(msg, &mut buf).unwrap();
+        assert_eq!(en.keystream_position(), Some(msg.len() as u64));
+        assert_eq!(en.bytes_consumed(), 3 * msg.len() as u64);
+        let mut expected = *msg;
+        StreamCipher::new(new_key, nonce).encrypt(&mut expected);
+        assert_eq!(&buf[..n], &expected);
+        assert_eq!(de.decrypt(&mut buf[..n]), Ok(Some(0)));
+        assert_eq!(&buf[..n], msg);
+    }
+
+    #[test]
+    fn test_prefix() {
+        let config = create_random_config();
+
+        let magic = b"MAGIC";
+        let msg = b"Hello world!";
+        let mut en = EncryptCursor::new(*config.key()).with_prefix(magic);
+        let mut de = DecryptCursor::new(*config.key()).with_prefix_len(magic.len());
+        let mut buf = [0; 1024];
+
+        let (_, n) = en.encrypt(&[], &mut buf[..3]).unwrap();
+        let (_, m) = en.encrypt(msg, &mut buf[n..]).unwrap();
+        let n = n + m;
+        assert_eq!(&buf[..magic.len()], magic);
+        assert_eq!(n, magic.len() + NONCE_BYTES + msg.len());
+
+        assert_eq!(de.decrypt(&mut buf[..2]), Ok(None));
+        let i = de.decrypt(&mut buf[2..n]).unwrap().unwrap();
+        assert_eq!(de.prefix(), magic);
+        assert_eq!(&buf[2 + i..n], msg);
+    }
+
+    #[test]
+    fn test_encrypt_in_place() {
+        let config = create_random_config();
+
+        let msg = b"Hello world!";
+        let mut en = EncryptCursor::new(*config.key());
+        let mut buf = *msg;
+        assert_eq!(
+            en.encrypt_in_place(&mut buf),
+            Err(CursorError::NonceIncomplete)
+        );
+
+        let mut wire = [0; 1024];
+        let (_, n) = en.encrypt(&[], &mut wire).unwrap();
+        assert_eq!(n, NONCE_BYTES);
+        en.encrypt_in_place(&mut buf).unwrap();
+        wire[n..n + buf.len()].copy_from_slice(&buf);
+        assert_eq!(en.bytes_produced(), (n + buf.len()) as u64);
+
+        let mut de = DecryptCursor::new(*config.key());
+        let i = de.decrypt(&mut wire[..n + buf.len()]).unwrap().unwrap();
+        assert_eq!(&wire[i..n + buf.len()], msg);
+    }
+
+    #[test]
+    fn test_encrypt_vectored() {
+        let config = create_random_config();
+
+        let msg = b"Hello world!";
+        let mut en = EncryptCursor::new(*config.key());
+        let mut de = DecryptCursor::new(*config.key());
+        assert_eq!(de.poly1305_key(), Err(CursorError::NonceIncomplete));
+        let mut a = [0; 5];
+        let mut b = [0; 10];
+        let mut c = [0; 64];
+        let mut to = [
+            std::io::IoSliceMut::new(&mut a),
+            std::io::IoSliceMut::new(&mut b),
+            std::io::IoSliceMut::new(&mut c),
+        ];
+        let (f, t) = en.encrypt_vectored(msg, &mut to).unwrap();
+        assert_eq!((f, t), (msg.len(), NONCE_BYTES + msg.len()));
+        assert_eq!(en.bytes_consumed(), f as u64);
+        assert_eq!(en.bytes_produced(), t as u64);
+        assert_eq!(en.keystream_position(), Some(msg.len() as u64));
+
+        let mut buf = [a.as_slice(), &b, &c].concat();
+        assert_eq!(de.keystream_position(), None);
+        let i = de.decrypt(&mut buf[..t]).unwrap().unwrap();
+        assert_eq!(&buf[i..t], msg);
+        assert_eq!(de.bytes_consumed(), t as u64);
+        assert_eq!(de.bytes_produced(), msg.len() as u64);
+        assert_eq!(de.keystream_position(), Some(msg.len() as u64));
+    }
+
+    #[test]
+    fn test_decrypt_from() {
+        let config = create_random_config();
+
+        let msg = b"Hello world!";
+        let mut en = EncryptCursor::new_x(*config.key()).with_prefix(b"v1");
+        let mut wire = [0; 1024];
+        let (_, n) = en.encrypt(msg, &mut wire).unwrap();
+        let wire = &wire[..n];
+
+        // The nonce arrives over several reads
+        let mut de = DecryptCursor::new_x(*config.key()).with_prefix_len(2);
+        let mut r = std::io::Cursor::new(wire);
+        let mut plaintext = vec![];
+        let mut out = [0; 7];
+        loop {
+            let n = de.decrypt_from(&mut r, &mut out).unwrap();
+            if n == 0 {
+                break;
+            }
+            plaintext.extend_from_slice(&out[..n]);
+        }
+        assert_eq!(plaintext, msg);
+        assert_eq!(de.prefix(), b"v1");
+
+        let mut de = DecryptCursor::new_x(*config.key()).with_prefix_len(2);
+        let e = de
+            .decrypt_from(&mut &wire[..X_NONCE_BYTES], &mut out)
+            .unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_en_dec_aad() {
+        let config = create_random_config();
+        let key = *config.key();
+        let nonce = rand::random();
+        let aad = b"header";
+
+        let msg = b"Hello world!";
+        let mut en = EncryptCursor::from_nonce(key, nonce).with_aad(aad);
+        let mut de = DecryptCursor::new(key).with_aad(aad);
+        let mut buf = [0; 1024];
+
+        // Same output as the AEAD of the datagrams
+        let (_, n) = en.encrypt(msg, &mut buf).unwrap();
+        let tag = en.tag().unwrap();
+        let mut opened = buf[NONCE_BYTES..n].to_vec();
+        crate::datagram::open(key, nonce, aad, &mut opened, &tag).unwrap();
+        assert_eq!(&opened, msg);
+
+        assert_eq!(de.decrypt(&mut buf[..NONCE_BYTES]), Ok(None));
+        assert_eq!(de.tag(), en.with_aad(aad).tag());
+        let i = de.decrypt(&mut buf[NONCE_BYTES..n]).unwrap().unwrap();
+        assert_eq!(&buf[NONCE_BYTES + i..n], msg);
+        assert_eq!(de.tag(), Some(tag));
+    }
+
+    #[test]
+    fn test_key_id_after_prefix() {
+        let old = create_random_config();
+        let new = create_random_config();
+
+        // The key ID follows the prefix whichever builder comes first
+        let msg = b"Hello world!";
+        let mut en = EncryptCursor::new(*new.key())
+            .with_key_id(2)
+            .with_prefix(b"v1");
+        let mut buf = [0; 1024];
+        let (_, n) = en.encrypt(msg, &mut buf).unwrap();
+        assert_eq!(&buf[..3], b"v1\x02");
+
+        let mut de = DecryptCursor::new(*old.key())
+            .with_keys([(1, *old.key()), (2, *new.key())])
+            .with_prefix_len(2);
+        let i = de.decrypt(&mut buf[..n]).unwrap().unwrap();
+        assert_eq!(de.prefix(), b"v1\x02");
+        assert_eq!(&buf[i..n], msg);
+    }
+
+    #[test]
+    fn test_key_version() {
+        let old = create_random_config();
+        let new = create_random_config();
+        let aad = b"header";
+
+        let msg = b"Hello world!";
+        let mut en = EncryptCursor::new(*new.key())
+            .with_key_id(2)
+            .with_key_version(7)
+            .with_aad(aad);
+        let mut buf = [0; 1024];
+        let (_, n) = en.encrypt(msg, &mut buf).unwrap();
+        let tag = en.tag().unwrap();
+        assert_eq!(&buf[..2], &[2, 7]);
+
+        let decrypt = |buf: &mut [u8]| {
+            let mut de = DecryptCursor::new(*old.key())
+                .with_keys([(1, *old.key()), (2, *new.key())])
+                .with_key_version()
+                .with_aad(aad);
+            assert_eq!(de.decrypt(&mut buf[..1]), Ok(None));
+            assert_eq!(de.key_version(), None);
+            let i = de.decrypt(&mut buf[1..]).unwrap().unwrap();
+            (de.key_version(), de.tag().unwrap(), buf[1 + i..].to_vec())
+        };
+        let (version, de_tag, plaintext) = decrypt(&mut buf[..n].to_vec());
+        assert_eq!(version, Some(7));
+        assert_eq!(de_tag, tag);
+        assert_eq!(plaintext, msg);
+
+        // A forged version fails the tag
+        let mut forged = buf[..n].to_vec();
+        forged[1] = 8;
+        let (version, de_tag, _) = decrypt(&mut forged);
+        assert_eq!(version, Some(8));
+        assert_ne!(de_tag, tag);
+
+        // The version is authenticated whichever builder comes first
+        let mut en = EncryptCursor::new(*new.key())
+            .with_aad(aad)
+            .with_key_version(7)
+            .with_key_id(2);
+        let (_, n) = en.encrypt(msg, &mut buf).unwrap();
+        let (version, de_tag, plaintext) = decrypt(&mut buf[..n].to_vec());
+        assert_eq!(version, Some(7));
+        assert_eq!(de_tag, en.tag().unwrap());
+        assert_eq!(plaintext, msg);
+
+        // But not at all without AAD
+        let mut en = EncryptCursor::new(*new.key()).with_key_version(7);
+        assert_eq!(
+            en.encrypt(msg, &mut buf),
+            Err(CursorError::KeyVersionWithoutAad)
+        );
+        let mut de = DecryptCursor::new(*new.key()).with_key_version();
+        assert_eq!(de.decrypt(&mut [7]), Err(CursorError::KeyVersionWithoutAad));
+    }
+
+    #[test]
+    fn test_tagged_decrypt() {
+        let config = create_random_config();
+        let key = *config.key();
+        let aad = b"header";
+
+        let msg = b"Hello world!";
+        let mut en = EncryptCursor::new_x(key).with_aad(aad);
+        let mut buf = [0; 1024];
+        let (_, n) = en.encrypt(msg, &mut buf).unwrap();
+        buf[n..n + BLOCK_BYTES].copy_from_slice(&en.tag().unwrap());
+        let wire = &mut buf[..n + BLOCK_BYTES];
+
+        let mut de = TaggedDecryptCursor::new_x(key, msg.len()).with_aad(aad);
+        for b in wire.chunks(5) {
+            assert_eq!(de.verify(), Ok(None));
+            assert_eq!(de.consume(b), Ok(b.len()));
         }
+        assert_eq!(de.consume(b"trailing"), Ok(0));
+        assert_eq!(de.verify(), Ok(Some(&msg[..])));
+
+        wire[X_NONCE_BYTES] ^= 1;
+        let mut de = TaggedDecryptCursor::new_x(key, msg.len()).with_aad(aad);
+        assert_eq!(de.consume(wire), Ok(wire.len()));
+        assert!(de.is_complete());
+        assert_eq!(de.verify(), Err(TagMismatch));
     }
 }
@@ -0,0 +1,71 @@
+use crate::{NONCE_BYTES, X_NONCE_BYTES};
+
+/// A nonce of ChaCha20 or XChaCha20 told apart by its length
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NonceBuf {
+    Nonce([u8; NONCE_BYTES]),
+    XNonce([u8; X_NONCE_BYTES]),
+}
+impl NonceBuf {
+    #[cfg(feature = "std")]
+    pub fn random() -> Self {
+        Self::Nonce(rand::random())
+    }
+    #[cfg(feature = "std")]
+    pub fn random_x() -> Self {
+        Self::XNonce(rand::random())
+    }
+
+    /// `nonce`: [`NONCE_BYTES`] for ChaCha20 or [`X_NONCE_BYTES`] for XChaCha20
+    pub fn from_slice(nonce: &[u8]) -> Result<Self, NonceLengthError> {
+        match nonce.len() {
+            NONCE_BYTES => Ok(Self::Nonce(nonce.try_into().unwrap())),
+            X_NONCE_BYTES => Ok(Self::XNonce(nonce.try_into().unwrap())),
+            len => Err(NonceLengthError(len)),
+        }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        match self {
+            NonceBuf::Nonce(nonce) => nonce,
+            NonceBuf::XNonce(nonce) => nonce,
+        }
+    }
+}
+impl TryFrom<&[u8]> for NonceBuf {
+    type Error = NonceLengthError;
+
+    fn try_from(nonce: &[u8]) -> Result<Self, Self::Error> {
+        Self::from_slice(nonce)
+    }
+}
+impl From<[u8; NONCE_BYTES]> for NonceBuf {
+    fn from(nonce: [u8; NONCE_BYTES]) -> Self {
+        Self::Nonce(nonce)
+    }
+}
+impl From<[u8; X_NONCE_BYTES]> for NonceBuf {
+    fn from(nonce: [u8; X_NONCE_BYTES]) -> Self {
+        Self::XNonce(nonce)
+    }
+}
+impl AsRef<[u8]> for NonceBuf {
+    fn as_ref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+/// The length of a nonce that fits neither ChaCha20 nor XChaCha20
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NonceLengthError(pub usize);
+impl core::fmt::Display for NonceLengthError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "nonce must be {NONCE_BYTES} or {X_NONCE_BYTES} bytes, not {}",
+            self.0
+        )
+    }
+}
+#[cfg(feature = "std")]
+impl std::error::Error for NonceLengthError {}
@@ -1,34 +1,57 @@
-use std::io::{self};
+#[cfg(feature = "std")]
+use std::io;
 
+#[cfg(feature = "std")]
 use tokio::io::{AsyncWrite, AsyncWriteExt};
 
-use crate::{cipher::StreamCipher, KEY_BYTES, NONCE_BYTES, X_NONCE_BYTES};
+use crate::{cipher::StreamCipher, key::SecretKey, KEY_BYTES, NONCE_BYTES, X_NONCE_BYTES};
 
-use super::{user_data::UserDataCursor, NonceCursor};
+use super::{user_data::UserDataCursor, NonceBuf, NonceCursor};
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NonceReadCursor {
-    key: [u8; KEY_BYTES],
+    #[cfg_attr(feature = "serde", serde(with = "crate::key::expose"))]
+    key: SecretKey,
     nonce: NonceCursor,
 }
 impl NonceReadCursor {
+    #[cfg(feature = "std")]
     pub fn new(key: [u8; KEY_BYTES]) -> Self {
-        let nonce: [u8; NONCE_BYTES] = rand::random();
-        let nonce = io::Cursor::new(nonce);
+        Self::from_nonce(key, rand::random())
+    }
+    #[cfg(feature = "std")]
+    pub fn new_x(key: [u8; KEY_BYTES]) -> Self {
+        Self::from_x_nonce(key, rand::random())
+    }
+    /// `nonce`: Must be unique per `key`
+    pub fn from_nonce(key: [u8; KEY_BYTES], nonce: [u8; NONCE_BYTES]) -> Self {
         Self {
-            key,
-            nonce: NonceCursor::Nonce(nonce),
+            key: key.into(),
+            nonce: NonceCursor::Nonce(nonce, 0),
         }
     }
-    pub fn new_x(key: [u8; KEY_BYTES]) -> Self {
-        let nonce: [u8; X_NONCE_BYTES] = rand::random();
-        let nonce = io::Cursor::new(nonce);
+    /// `nonce`: Must be unique per `key`
+    pub fn from_x_nonce(key: [u8; KEY_BYTES], nonce: [u8; X_NONCE_BYTES]) -> Self {
+        Self {
+            key: key.into(),
+            nonce: NonceCursor::XNonce(nonce, 0),
+        }
+    }
+
+    /// `nonce`: Must be unique per `key`
+    pub fn from_nonce_buf(key: [u8; KEY_BYTES], nonce: NonceBuf) -> Self {
         Self {
-            key,
-            nonce: NonceCursor::XNonce(nonce),
+            key: key.into(),
+            nonce: nonce.into(),
         }
     }
 
+    /// The whole nonce including the part already consumed
+    pub fn nonce(&self) -> &[u8] {
+        self.nonce.nonce()
+    }
+
     pub fn remaining_nonce(&self) -> &[u8] {
         self.nonce.remaining()
     }
@@ -39,28 +62,31 @@ impl NonceReadCursor {
             return ReadCursorState::Nonce(self);
         }
 
-        let cipher = match self.nonce {
-            NonceCursor::Nonce(cursor) => StreamCipher::new(self.key, cursor.into_inner()),
-            NonceCursor::XNonce(cursor) => StreamCipher::new_x(self.key, cursor.into_inner()),
-        };
-        let cursor = UserDataCursor::new(cipher);
+        let cursor = UserDataCursor::new(self.nonce.stream_cipher(*self.key.expose_secret()));
         ReadCursorState::UserData(cursor)
     }
 
+    #[cfg(feature = "std")]
     pub async fn encode_nonce_to<W: AsyncWrite + Unpin>(
         self,
         w: &mut W,
     ) -> io::Result<UserDataCursor> {
         AsyncWriteExt::write_all(w, self.remaining_nonce()).await?;
-        let cipher = match self.nonce {
-            NonceCursor::Nonce(cursor) => StreamCipher::new(self.key, cursor.into_inner()),
-            NonceCursor::XNonce(cursor) => StreamCipher::new_x(self.key, cursor.into_inner()),
-        };
-        Ok(UserDataCursor::new(cipher))
+        Ok(UserDataCursor::new(
+            self.nonce.stream_cipher(*self.key.expose_secret()),
+        ))
+    }
+
+    pub(crate) fn stream_cipher(&self) -> StreamCipher {
+        self.nonce.stream_cipher(*self.key.expose_secret())
+    }
+
+    pub(crate) fn set_key(&mut self, key: [u8; KEY_BYTES]) {
+        self.key = key.into();
     }
 
     pub fn key(&self) -> &[u8; KEY_BYTES] {
-        &self.key
+        self.key.expose_secret()
     }
 
     pub fn chacha20_nonce(&self) -> [u8; NONCE_BYTES] {
@@ -69,6 +95,7 @@ impl NonceReadCursor {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ReadCursorState {
     Nonce(NonceReadCursor),
     UserData(UserDataCursor),
@@ -1,67 +1,88 @@
-use std::io::{self, Read};
+#[cfg(feature = "std")]
+use std::io;
 
+#[cfg(feature = "std")]
 use tokio::io::{AsyncRead, AsyncReadExt};
 
-use crate::{cipher::StreamCipher, KEY_BYTES, NONCE_BYTES, X_NONCE_BYTES};
+use crate::{key::SecretKey, KEY_BYTES, NONCE_BYTES, X_NONCE_BYTES};
 
 use super::{user_data::UserDataCursor, NonceCursor};
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NonceWriteCursor {
-    key: [u8; KEY_BYTES],
+    #[cfg_attr(feature = "serde", serde(with = "crate::key::expose"))]
+    key: SecretKey,
     nonce: NonceCursor,
 }
 impl NonceWriteCursor {
     pub fn new(key: [u8; KEY_BYTES]) -> Self {
-        let nonce = io::Cursor::new([0; NONCE_BYTES]);
         Self {
-            key,
-            nonce: NonceCursor::Nonce(nonce),
+            key: key.into(),
+            nonce: NonceCursor::Nonce([0; NONCE_BYTES], 0),
         }
     }
     pub fn new_x(key: [u8; KEY_BYTES]) -> Self {
-        let nonce = io::Cursor::new([0; X_NONCE_BYTES]);
         Self {
-            key,
-            nonce: NonceCursor::XNonce(nonce),
+            key: key.into(),
+            nonce: NonceCursor::XNonce([0; X_NONCE_BYTES], 0),
         }
     }
 
+    pub(crate) fn set_key(&mut self, key: [u8; KEY_BYTES]) {
+        self.key = key.into();
+    }
+
+    pub fn key(&self) -> &[u8; KEY_BYTES] {
+        self.key.expose_secret()
+    }
+
     pub fn remaining_nonce_size(&self) -> usize {
         self.nonce.remaining().len()
     }
 
-    pub fn collect_nonce_from(mut self, r: &mut io::Cursor<&[u8]>) -> WriteCursorState {
-        let n = Read::read(r, self.nonce.remaining_mut()).unwrap();
+    /// Whether part of the nonce has been collected
+    pub fn nonce_started(&self) -> bool {
+        self.remaining_nonce_size() != self.nonce.nonce().len()
+    }
+
+    /// Return the next state and the amount of bytes consumed from `buf`
+    pub fn collect_nonce(mut self, buf: &[u8]) -> (WriteCursorState, usize) {
+        let remaining = self.nonce.remaining_mut();
+        let n = remaining.len().min(buf.len());
+        remaining[..n].copy_from_slice(&buf[..n]);
         self.nonce.consume(n);
 
         if !self.nonce.complete() {
-            return WriteCursorState::Nonce(self);
+            return (WriteCursorState::Nonce(self), n);
         }
 
-        let cipher = match self.nonce {
-            NonceCursor::Nonce(cursor) => StreamCipher::new(self.key, cursor.into_inner()),
-            NonceCursor::XNonce(cursor) => StreamCipher::new_x(self.key, cursor.into_inner()),
-        };
-        let cursor = UserDataCursor::new(cipher);
-        WriteCursorState::UserData(cursor)
+        let cursor = UserDataCursor::new(self.nonce.stream_cipher(*self.key.expose_secret()));
+        (WriteCursorState::UserData(cursor), n)
+    }
+
+    #[cfg(feature = "std")]
+    pub fn collect_nonce_from(self, r: &mut io::Cursor<&[u8]>) -> WriteCursorState {
+        let pos = r.position() as usize;
+        let (state, n) = self.collect_nonce(&r.get_ref()[pos..]);
+        r.set_position((pos + n) as u64);
+        state
     }
 
+    #[cfg(feature = "std")]
     pub async fn decode_nonce_from<R: AsyncRead + Unpin>(
         mut self,
         r: &mut R,
     ) -> io::Result<UserDataCursor> {
         AsyncReadExt::read_exact(r, self.nonce.remaining_mut()).await?;
-        let cipher = match self.nonce {
-            NonceCursor::Nonce(cursor) => StreamCipher::new(self.key, cursor.into_inner()),
-            NonceCursor::XNonce(cursor) => StreamCipher::new_x(self.key, cursor.into_inner()),
-        };
-        let cursor = UserDataCursor::new(cipher);
-        Ok(cursor)
+        Ok(UserDataCursor::new(
+            self.nonce.stream_cipher(*self.key.expose_secret()),
+        ))
     }
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum WriteCursorState {
     Nonce(NonceWriteCursor),
     UserData(UserDataCursor),
@@ -0,0 +1,213 @@
+use alloc::vec::Vec;
+
+use crate::{
+    mac::{constant_time_eq, Poly1305Hasher, BLOCK_BYTES},
+    KEY_BYTES, NONCE_BYTES, X_NONCE_BYTES,
+};
+
+use super::{CursorError, DecryptCursor, EncryptCursor};
+
+/// IO-free engine of both directions of an encrypted stream
+///
+/// - [`Self::write_app`]: plaintext from the application
+/// - [`Self::write_wire`]: ciphertext for the transport
+/// - [`Self::read_wire`]: ciphertext from the transport
+/// - [`Self::read_app`]: plaintext for the application
+///
+/// The local nonce is queued for [`Self::write_wire`] from the start.
+/// A running Poly1305 tag over the ciphertext of each direction is kept for protocols to exchange.
+pub struct Session {
+    tx: EncryptCursor,
+    tx_hasher: Poly1305Hasher,
+    /// Ciphertext not yet taken by [`Self::write_wire`]
+    tx_buf: Vec<u8>,
+    tx_pos: usize,
+    rx: DecryptCursor,
+    rx_hasher: Option<Poly1305Hasher>,
+    /// Plaintext not yet taken by [`Self::read_app`]
+    rx_buf: Vec<u8>,
+    rx_pos: usize,
+}
+impl Session {
+    /// Both directions use 12-byte nonces
+    #[cfg(feature = "std")]
+    pub fn new(key: [u8; KEY_BYTES]) -> Self {
+        Self::from_cursors(EncryptCursor::new(key), DecryptCursor::new(key))
+    }
+    /// Both directions use 24-byte nonces
+    #[cfg(feature = "std")]
+    pub fn new_x(key: [u8; KEY_BYTES]) -> Self {
+        Self::from_cursors(EncryptCursor::new_x(key), DecryptCursor::new_x(key))
+    }
+    /// `nonce`: Must be unique per `key`
+    pub fn from_nonce(key: [u8; KEY_BYTES], nonce: [u8; NONCE_BYTES]) -> Self {
+        Self::from_cursors(
+            EncryptCursor::from_nonce(key, nonce),
+            DecryptCursor::new(key),
+        )
+    }
+    /// `nonce`: Must be unique per `key`
+    pub fn from_x_nonce(key: [u8; KEY_BYTES], nonce: [u8; X_NONCE_BYTES]) -> Self {
+        Self::from_cursors(
+            EncryptCursor::from_x_nonce(key, nonce),
+            DecryptCursor::new_x(key),
+        )
+    }
+    fn from_cursors(mut tx: EncryptCursor, rx: DecryptCursor) -> Self {
+        // Queue the nonce so the one-time key comes from the keystream it starts
+        let mut tx_buf = alloc::vec![0; X_NONCE_BYTES];
+        let (_, n) = tx.encrypt(&[], &mut tx_buf).unwrap();
+        tx_buf.truncate(n);
+        Self {
+            tx_hasher: Poly1305Hasher::new(tx.poly1305_key().unwrap()),
+            tx,
+            tx_buf,
+            tx_pos: 0,
+            rx,
+            rx_hasher: None,
+            rx_buf: Vec::new(),
+            rx_pos: 0,
+        }
+    }
+
+    /// Encrypt all of `buf` for [`Self::write_wire`]
+    pub fn write_app(&mut self, buf: &[u8]) -> Result<(), CursorError> {
+        let start = self.tx_buf.len();
+        self.tx_buf.resize(start + buf.len(), 0);
+        let res = self.tx.encrypt(buf, &mut self.tx_buf[start..]);
+        if res.is_err() {
+            self.tx_buf.truncate(start);
+        }
+        res?;
+        self.tx_hasher.update(&self.tx_buf[start..]);
+        Ok(())
+    }
+
+    /// Move pending ciphertext to `buf`
+    ///
+    /// Return the amount of bytes written to `buf`.
+    pub fn write_wire(&mut self, buf: &mut [u8]) -> usize {
+        let pending = &self.tx_buf[self.tx_pos..];
+        let n = pending.len().min(buf.len());
+        buf[..n].copy_from_slice(&pending[..n]);
+        self.tx_pos += n;
+        if self.tx_pos == self.tx_buf.len() {
+            self.tx_buf.clear();
+            self.tx_pos = 0;
+        }
+        n
+    }
+
+    /// Whether [`Self::write_wire`] has ciphertext to hand out
+    pub fn wants_write(&self) -> bool {
+        self.tx_pos < self.tx_buf.len()
+    }
+
+    /// Decrypt all of `buf` for [`Self::read_app`]
+    pub fn read_wire(&mut self, buf: &[u8]) -> Result<(), CursorError> {
+        let start = self.rx_buf.len();
+        self.rx_buf.extend_from_slice(buf);
+        let pos = match self.rx.decrypt(&mut self.rx_buf[start..]) {
+            Ok(Some(pos)) => pos,
+            res => {
+                // Only nonce so far
+                self.rx_buf.truncate(start);
+                return res.map(|_| ());
+            }
+        };
+        if self.rx_hasher.is_none() {
+            self.rx_hasher = Some(Poly1305Hasher::new(self.rx.poly1305_key()?));
+        }
+        self.rx_hasher.as_mut().unwrap().update(&buf[pos..]);
+        self.rx_buf.drain(start..start + pos);
+        Ok(())
+    }
+
+    /// Move pending plaintext to `buf`
+    ///
+    /// Return the amount of bytes written to `buf`.
+    pub fn read_app(&mut self, buf: &mut [u8]) -> usize {
+        let pending = &self.rx_buf[self.rx_pos..];
+        let n = pending.len().min(buf.len());
+        buf[..n].copy_from_slice(&pending[..n]);
+        self.rx_pos += n;
+        if self.rx_pos == self.rx_buf.len() {
+            self.rx_buf.clear();
+            self.rx_pos = 0;
+        }
+        n
+    }
+
+    /// Whether [`Self::read_app`] has plaintext to hand out
+    pub fn wants_read(&self) -> bool {
+        self.rx_pos < self.rx_buf.len()
+    }
+
+    /// Tag over the ciphertext passed to [`Self::write_app`] so far
+    pub fn tx_tag(&self) -> [u8; BLOCK_BYTES] {
+        self.tx_hasher.current_tag()
+    }
+
+    /// Tag over the ciphertext passed to [`Self::read_wire`] so far
+    ///
+    /// `None` until the whole nonce of the peer has arrived.
+    pub fn rx_tag(&self) -> Option<[u8; BLOCK_BYTES]> {
+        match &self.rx_hasher {
+            Some(hasher) => Some(hasher.current_tag()),
+            None => self
+                .rx
+                .poly1305_key()
+                .ok()
+                .map(|key| Poly1305Hasher::new(key).current_tag()),
+        }
+    }
+
+    /// Compare `tag` from the peer with [`Self::rx_tag`] in constant time
+    pub fn verify_rx_tag(&self, tag: &[u8; BLOCK_BYTES]) -> bool {
+        match self.rx_tag() {
+            Some(expected) => constant_time_eq(&expected, tag),
+            None => false,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use crate::config::tests::create_random_config;
+
+    use super::*;
+
+    fn pump(from: &mut Session, to: &mut Session) {
+        let mut wire = [0; 7];
+        while from.wants_write() {
+            let n = from.write_wire(&mut wire);
+            to.read_wire(&wire[..n]).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_session() {
+        let config = create_random_config();
+        let mut a = Session::new_x(*config.key());
+        let mut b = Session::new_x(*config.key());
+        assert!(b.rx_tag().is_none());
+
+        let mut buf = [0; 64];
+        for msg in [&b"Hello"[..], b"", b"Cryptographic Forum Research Group"] {
+            a.write_app(msg).unwrap();
+            pump(&mut a, &mut b);
+            let mut n = 0;
+            while b.wants_read() {
+                n += b.read_app(&mut buf[n..]);
+            }
+            assert_eq!(&buf[..n], msg);
+            assert!(b.verify_rx_tag(&a.tx_tag()));
+
+            b.write_app(&buf[..n]).unwrap();
+            pump(&mut b, &mut a);
+            let n = a.read_app(&mut buf);
+            assert_eq!(&buf[..n], msg);
+            assert_eq!(a.rx_tag(), Some(b.tx_tag()));
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use alloc::vec::Vec;
+
+use arrayvec::ArrayVec;
+
+use crate::{
+    mac::{verify_tag, TagMismatch, BLOCK_BYTES},
+    KEY_BYTES, X_NONCE_BYTES,
+};
+
+use super::{CursorError, DecryptCursor};
+
+/// [`DecryptCursor`] for a message of known length followed by its tag
+///
+/// The wire format is the nonce, `msg_len` bytes of ciphertext and the AEAD_CHACHA20_POLY1305 tag from [`super::EncryptCursor::tag`].
+/// No user data is handed out before the tag is verified.
+pub struct TaggedDecryptCursor {
+    de: DecryptCursor,
+    msg_len: usize,
+    /// Withheld user data
+    buf: Vec<u8>,
+    tag: ArrayVec<u8, BLOCK_BYTES>,
+}
+impl TaggedDecryptCursor {
+    pub fn new(key: [u8; KEY_BYTES], msg_len: usize) -> Self {
+        Self::from_cursor(DecryptCursor::new(key), msg_len)
+    }
+    pub fn new_x(key: [u8; KEY_BYTES], msg_len: usize) -> Self {
+        Self::from_cursor(DecryptCursor::new_x(key), msg_len)
+    }
+    fn from_cursor(de: DecryptCursor, msg_len: usize) -> Self {
+        Self {
+            de: de.with_aad(&[]),
+            msg_len,
+            buf: Vec::with_capacity(msg_len),
+            tag: ArrayVec::new(),
+        }
+    }
+
+    pub fn with_aad(mut self, aad: &[u8]) -> Self {
+        self.de = self.de.with_aad(aad);
+        self
+    }
+
+    /// Return the amount of bytes consumed from `buf`
+    ///
+    /// Nothing past the tag is consumed.
+    pub fn consume(&mut self, mut buf: &[u8]) -> Result<usize, CursorError> {
+        let mut consumed = 0;
+
+        // Nonce
+        let n = self.de.remaining_nonce_size()?.min(buf.len());
+        if n != 0 {
+            let mut nonce: ArrayVec<u8, X_NONCE_BYTES> = buf[..n].try_into().unwrap();
+            assert!(self.de.decrypt(&mut nonce)?.is_none());
+            buf = &buf[n..];
+            consumed += n;
+        }
+        if self.de.remaining_nonce_size()? != 0 {
+            return Ok(consumed);
+        }
+
+        // Ciphertext
+        let n = (self.msg_len - self.buf.len()).min(buf.len());
+        let start = self.buf.len();
+        self.buf.extend_from_slice(&buf[..n]);
+        let res = self.de.decrypt(&mut self.buf[start..]);
+        if res.is_err() {
+            self.buf.truncate(start);
+        }
+        assert_eq!(res?, Some(0));
+        buf = &buf[n..];
+        consumed += n;
+
+        // Tag
+        let n = self.tag.remaining_capacity().min(buf.len());
+        self.tag.try_extend_from_slice(&buf[..n]).unwrap();
+        consumed += n;
+
+        Ok(consumed)
+    }
+
+    /// Whether the whole nonce, ciphertext and tag have arrived
+    pub fn is_complete(&self) -> bool {
+        self.buf.len() == self.msg_len && self.tag.is_full()
+    }
+
+    /// Return the user data once the message is complete and its tag matches
+    ///
+    /// `Ok(None)` while the message is incomplete.
+    pub fn verify(&self) -> Result<Option<&[u8]>, TagMismatch> {
+        if !self.is_complete() {
+            return Ok(None);
+        }
+        let expected = self.de.tag().unwrap();
+        let tag = self.tag.as_slice().try_into().unwrap();
+        verify_tag(&expected, tag)?;
+        Ok(Some(&self.buf))
+    }
+}
@@ -1,6 +1,7 @@
-use crate::cipher::StreamCipher;
+use crate::{cipher::StreamCipher, KEY_BYTES};
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct UserDataCursor {
     cipher: StreamCipher,
 }
@@ -13,6 +14,41 @@ impl UserDataCursor {
         self.cipher.encrypt(buf);
     }
 
+    #[cfg(feature = "parallel")]
+    pub fn set_parallelism(&mut self, par: crate::cipher::ParallelismConfig) {
+        self.cipher.set_parallelism(par);
+    }
+
+    /// Move the keystream to the plaintext offset `pos`
+    pub fn seek(&mut self, pos: u64) {
+        self.cipher.seek(pos);
+    }
+
+    /// Discard the next `n` keystream bytes
+    pub fn skip(&mut self, n: u64) {
+        self.cipher.skip(n);
+    }
+
+    /// Switch to `key` keeping the nonce and the keystream position
+    pub fn rekey(&mut self, key: [u8; KEY_BYTES]) {
+        self.cipher.rekey(key);
+    }
+
+    /// Offset of the next keystream byte from the start of the stream
+    pub fn position(&self) -> u64 {
+        self.cipher.position()
+    }
+
+    /// Keystream bytes left before the block counter wraps
+    pub fn remaining(&self) -> u64 {
+        self.cipher.remaining()
+    }
+
+    /// Counter of the next keystream block
+    pub fn block_counter(&self) -> u32 {
+        self.cipher.block().counter()
+    }
+
     pub fn cipher(&self) -> &StreamCipher {
         &self.cipher
     }
@@ -0,0 +1,188 @@
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use crate::KEY_BYTES;
+
+use super::{
+    open_packet, ratchet_key, seal_packet, split_packet, DatagramOpener, DatagramSealer,
+    EpochState, OpenPacketError, PacketHeader,
+};
+
+/// Batches with fewer payload bytes than this are not worth the rayon overhead
+#[cfg(feature = "parallel")]
+const PAR_BATCH_BYTES_THRESHOLD: usize = 64 * 1024;
+
+impl DatagramSealer {
+    /// Seal the payloads with consecutive counters
+    pub fn seal_batch(&mut self, aad: &[u8], payloads: &[&[u8]]) -> Vec<Vec<u8>> {
+        let first = self.next_counter;
+        self.next_counter = first
+            .checked_add(payloads.len() as u64)
+            .expect("packet counter exhausted");
+        let (key, iv, epoch) = (*self.key.expose_secret(), self.iv, self.epoch);
+        let seal = |(i, payload): (usize, &&[u8])| {
+            let header = PacketHeader {
+                epoch,
+                counter: first + i as u64,
+            };
+            seal_packet(key, iv, header, aad, payload)
+        };
+        #[cfg(feature = "parallel")]
+        if par(payloads) {
+            return payloads.par_iter().enumerate().map(seal).collect();
+        }
+        payloads.iter().enumerate().map(seal).collect()
+    }
+}
+
+impl DatagramOpener {
+    /// Open the packets in order as if calling `open` on each of them
+    pub fn open_batch(
+        &mut self,
+        aad: &[u8],
+        packets: &[&[u8]],
+    ) -> Vec<Result<(PacketHeader, Vec<u8>), OpenPacketError>> {
+        // Authenticate all packets against the epoch keys known before the batch
+        let next = self
+            .current
+            .epoch
+            .checked_add(1)
+            .map(|epoch| (epoch, ratchet_key(*self.current.key.expose_secret())));
+        let keys: Vec<(u32, [u8; KEY_BYTES])> = [
+            Some((self.current.epoch, *self.current.key.expose_secret())),
+            self.previous
+                .as_ref()
+                .map(|s| (s.epoch, *s.key.expose_secret())),
+            next,
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        let iv = self.iv;
+        let open = |packet: &&[u8]| {
+            let (header, _, _) = split_packet(packet)?;
+            let Some((_, key)) = keys.iter().find(|(epoch, _)| *epoch == header.epoch) else {
+                return Err(OpenPacketError::UnknownEpoch(header.epoch));
+            };
+            open_packet(*key, iv, aad, packet)
+        };
+        #[cfg(feature = "parallel")]
+        let opened: Vec<_> = match par(packets) {
+            true => packets.par_iter().map(open).collect(),
+            false => packets.iter().map(open).collect(),
+        };
+        #[cfg(not(feature = "parallel"))]
+        let opened: Vec<_> = packets.iter().map(open).collect();
+
+        // Apply the replay windows and epoch transitions sequentially
+        opened
+            .into_iter()
+            .zip(packets)
+            .map(|(res, packet)| match res {
+                // An earlier packet of the batch may have ratcheted to an epoch that reaches this one
+                Err(OpenPacketError::UnknownEpoch(_)) => self.open(aad, packet),
+                res => {
+                    let (header, payload) = res?;
+                    self.commit(header)?;
+                    Ok((header, payload))
+                }
+            })
+            .collect()
+    }
+
+    fn commit(&mut self, header: PacketHeader) -> Result<(), OpenPacketError> {
+        if Some(header.epoch) == self.current.epoch.checked_add(1) {
+            let next =
+                EpochState::new(header.epoch, ratchet_key(*self.current.key.expose_secret()));
+            self.previous = Some(std::mem::replace(&mut self.current, next));
+        }
+        let state = if header.epoch == self.current.epoch {
+            &mut self.current
+        } else if Some(header.epoch) == self.previous.as_ref().map(|s| s.epoch) {
+            self.previous.as_mut().unwrap()
+        } else {
+            return Err(OpenPacketError::UnknownEpoch(header.epoch));
+        };
+        if !state.window.check(header.counter) {
+            return Err(OpenPacketError::Replayed(header.counter));
+        }
+        state.window.update(header.counter);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "parallel")]
+fn par(bufs: &[&[u8]]) -> bool {
+    PAR_BATCH_BYTES_THRESHOLD < bufs.iter().map(|b| b.len()).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::tests::create_random_config;
+
+    use super::*;
+
+    #[test]
+    fn test_batch() {
+        let config = create_random_config();
+        let iv = rand::random();
+        let mut sealer = DatagramSealer::new(*config.key(), iv);
+        let mut opener = DatagramOpener::new(*config.key(), iv);
+
+        for size in [16, 1024] {
+            let payloads: Vec<Vec<u8>> = (0..128).map(|i| vec![i as u8; size]).collect();
+            let payloads: Vec<&[u8]> = payloads.iter().map(|p| p.as_slice()).collect();
+            let mut packets = sealer.seal_batch(&[], &payloads);
+            sealer.rekey();
+            packets.extend(sealer.seal_batch(&[], &payloads[..1]));
+            packets.push(packets[0].clone());
+
+            let packets: Vec<&[u8]> = packets.iter().map(|p| p.as_slice()).collect();
+            let opened = opener.open_batch(&[], &packets);
+            assert_eq!(opened.len(), payloads.len() + 2);
+            for (res, payload) in opened.iter().zip(&payloads) {
+                assert_eq!(&res.as_ref().unwrap().1, payload);
+            }
+            let (header, _) = opened[payloads.len()].as_ref().unwrap();
+            assert_eq!(header.epoch, sealer.epoch());
+            assert!(matches!(
+                opened.last().unwrap(),
+                Err(OpenPacketError::Replayed(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_batch_epochs() {
+        let config = create_random_config();
+        let iv = rand::random();
+        let mut sealer = DatagramSealer::new(*config.key(), iv);
+        let mut opener = DatagramOpener::new(*config.key(), iv);
+        let mut one_by_one = DatagramOpener::new(*config.key(), iv);
+
+        // Epoch 2 is only reachable once the packet of epoch 1 has been opened
+        let mut packets = vec![];
+        for _ in 0..3 {
+            sealer.rekey();
+            packets.extend(sealer.seal_batch(&[], &[b"Hello"]));
+        }
+        packets.swap(1, 2);
+        let packets: Vec<&[u8]> = packets.iter().map(|p| p.as_slice()).collect();
+
+        let opened = opener.open_batch(&[], &packets);
+        for (res, packet) in opened.iter().zip(&packets) {
+            let expected = one_by_one.open(&[], packet);
+            match (res, expected) {
+                (Ok(res), Ok(expected)) => assert_eq!(res, &expected),
+                (Err(e), Err(expected)) => assert_eq!(e.to_string(), expected.to_string()),
+                (res, expected) => panic!("{res:?} != {expected:?}"),
+            }
+        }
+        let epochs: Vec<_> = opened
+            .iter()
+            .map(|res| res.as_ref().ok().map(|(h, _)| h.epoch))
+            .collect();
+        assert_eq!(epochs, [Some(1), None, Some(2)]);
+        assert_eq!(opener.epoch(), 2);
+    }
+}
@@ -0,0 +1,113 @@
+use std::io;
+
+use bytes::{Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::stream::Error;
+
+use super::{DatagramOpener, DatagramSealer, OpenPacketError};
+
+/// Seal outgoing datagrams and open incoming ones of a single session
+///
+/// Meant for `tokio_util::udp::UdpFramed` where each call to `decode` sees exactly one datagram.
+#[derive(Debug, Clone)]
+pub struct DatagramCodec {
+    sealer: DatagramSealer,
+    opener: DatagramOpener,
+}
+impl DatagramCodec {
+    pub fn new(sealer: DatagramSealer, opener: DatagramOpener) -> Self {
+        Self { sealer, opener }
+    }
+
+    pub fn sealer(&self) -> &DatagramSealer {
+        &self.sealer
+    }
+    pub fn sealer_mut(&mut self) -> &mut DatagramSealer {
+        &mut self.sealer
+    }
+    pub fn opener(&self) -> &DatagramOpener {
+        &self.opener
+    }
+}
+impl Encoder<Bytes> for DatagramCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let packet = self.sealer.seal(&[], &item);
+        dst.extend_from_slice(&packet);
+        Ok(())
+    }
+}
+impl Decoder for DatagramCodec {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.is_empty() {
+            return Ok(None);
+        }
+        let packet = src.split();
+        let (_, payload) = self.opener.open(&[], &packet).map_err(|e| match e {
+            OpenPacketError::TagMismatch => Error::TagMismatch.into(),
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        })?;
+        Ok(Some(BytesMut::from(&payload[..])))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{SinkExt, StreamExt};
+    use tokio::net::UdpSocket;
+    use tokio_util::udp::UdpFramed;
+
+    use crate::config::tests::create_random_config;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_udp_framed() {
+        let config = create_random_config();
+        let iv = rand::random();
+        let codec = || {
+            DatagramCodec::new(
+                DatagramSealer::new(*config.key(), iv),
+                DatagramOpener::new(*config.key(), iv),
+            )
+        };
+
+        let a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let b_addr = b.local_addr().unwrap();
+        let mut a = UdpFramed::new(a, codec());
+        let mut b = UdpFramed::new(b, codec());
+
+        let data = Bytes::from_static(b"Hello, world!");
+        for _ in 0..16 {
+            a.send((data.clone(), b_addr)).await.unwrap();
+            let (payload, _) = b.next().await.unwrap().unwrap();
+            assert_eq!(payload, data);
+        }
+    }
+
+    #[test]
+    fn test_tag_mismatch() {
+        let config = create_random_config();
+        let iv = rand::random();
+        let mut codec = DatagramCodec::new(
+            DatagramSealer::new(*config.key(), iv),
+            DatagramOpener::new(*config.key(), iv),
+        );
+
+        let mut packet = BytesMut::new();
+        codec
+            .encode(Bytes::from_static(b"Hello, world!"), &mut packet)
+            .unwrap();
+        let last = packet.len() - 1;
+        packet[last] ^= 1;
+        let e = codec.decode(&mut packet).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(Error::from_io(&e), Some(&Error::TagMismatch));
+    }
+}
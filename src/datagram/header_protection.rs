@@ -0,0 +1,35 @@
+use crate::{cipher::ChaCha20, KEY_BYTES, NONCE_BYTES};
+
+pub const SAMPLE_BYTES: usize = 16;
+pub const MASK_BYTES: usize = 5;
+
+/// ChaCha20-based header protection mask from RFC 9001 section 5.4.4
+///
+/// - `key`: A header protection key independent of the packet protection key
+/// - `sample`: Ciphertext sampled from the protected packet
+pub fn header_protection(key: [u8; KEY_BYTES], sample: &[u8; SAMPLE_BYTES]) -> [u8; MASK_BYTES] {
+    let counter = u32::from_le_bytes(sample[..size_of::<u32>()].try_into().unwrap());
+    let nonce: [u8; NONCE_BYTES] = sample[size_of::<u32>()..].try_into().unwrap();
+    let block = ChaCha20::new(key, nonce, counter).next_nth_block(0);
+    block.byte_vec()[..MASK_BYTES].try_into().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_protection() {
+        let key = [
+            0x25, 0xa2, 0x82, 0xb9, 0xe8, 0x2f, 0x06, 0xf2, 0x1f, 0x48, 0x89, 0x17, 0xa4, 0xfc,
+            0x8f, 0x1b, 0x73, 0x57, 0x36, 0x85, 0x60, 0x85, 0x97, 0xd0, 0xef, 0xcb, 0x07, 0x6b,
+            0x0a, 0xb7, 0xa7, 0xa4,
+        ];
+        let sample = [
+            0x5e, 0x5c, 0xd5, 0x5c, 0x41, 0xf6, 0x90, 0x80, 0x57, 0x5d, 0x79, 0x99, 0xc2, 0x5a,
+            0x5b, 0xfb,
+        ];
+        let mask = header_protection(key, &sample);
+        assert_eq!(mask, [0xae, 0xfe, 0xfe, 0x7d, 0x03]);
+    }
+}
@@ -0,0 +1,435 @@
+mod batch;
+mod codec;
+pub use codec::DatagramCodec;
+mod header_protection;
+pub use header_protection::{header_protection, MASK_BYTES, SAMPLE_BYTES};
+mod replay;
+pub use replay::ReplayWindow;
+
+use thiserror::Error;
+
+use crate::{
+    cipher::StreamCipher,
+    key::SecretKey,
+    mac::{poly1305_key_gen, verify_tag, AeadHasher, TagMismatch},
+    KEY_BYTES, NONCE_BYTES,
+};
+
+pub const EPOCH_BYTES: usize = size_of::<u32>();
+pub const COUNTER_BYTES: usize = size_of::<u64>();
+pub const HEADER_BYTES: usize = EPOCH_BYTES + COUNTER_BYTES;
+pub use crate::TAG_BYTES;
+pub const OVERHEAD_BYTES: usize = HEADER_BYTES + TAG_BYTES;
+
+const RATCHET_CONTEXT: &str = "tokio_chacha20 datagram epoch ratchet";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PacketHeader {
+    pub epoch: u32,
+    pub counter: u64,
+}
+impl PacketHeader {
+    pub fn to_bytes(&self) -> [u8; HEADER_BYTES] {
+        let mut buf = [0; HEADER_BYTES];
+        buf[..EPOCH_BYTES].copy_from_slice(&self.epoch.to_le_bytes());
+        buf[EPOCH_BYTES..].copy_from_slice(&self.counter.to_le_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8; HEADER_BYTES]) -> Self {
+        let epoch = u32::from_le_bytes(buf[..EPOCH_BYTES].try_into().unwrap());
+        let counter = u64::from_le_bytes(buf[EPOCH_BYTES..].try_into().unwrap());
+        Self { epoch, counter }
+    }
+}
+
+/// Return a packet of `epoch || counter || ciphertext || tag`
+///
+/// - `key`: The key of `header.epoch`
+/// - `header.counter`: Must not be reused under the same `key` and `iv`
+pub fn seal_packet(
+    key: [u8; KEY_BYTES],
+    iv: [u8; NONCE_BYTES],
+    header: PacketHeader,
+    aad: &[u8],
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(OVERHEAD_BYTES + payload.len());
+    packet.extend(header.to_bytes());
+    packet.extend(payload);
+    let nonce = nonce(iv, header.counter);
+    let tag = seal(key, nonce, aad, &mut packet[HEADER_BYTES..]);
+    packet.extend(tag);
+    packet
+}
+
+/// Return the header and the payload of a packet from `seal_packet`
+///
+/// `key`: The key of the epoch in the header
+pub fn open_packet(
+    key: [u8; KEY_BYTES],
+    iv: [u8; NONCE_BYTES],
+    aad: &[u8],
+    packet: &[u8],
+) -> Result<(PacketHeader, Vec<u8>), OpenPacketError> {
+    let (header, ciphertext, tag) = split_packet(packet)?;
+    let mut payload = ciphertext.to_vec();
+    open(key, nonce(iv, header.counter), aad, &mut payload, tag)?;
+    Ok((header, payload))
+}
+#[derive(Debug, Error)]
+pub enum OpenPacketError {
+    #[error("packet too short: {0} bytes")]
+    TooShort(usize),
+    #[error("tag mismatch")]
+    TagMismatch,
+    #[error("replayed or too old packet: counter = {0}")]
+    Replayed(u64),
+    #[error("unknown epoch: {0}")]
+    UnknownEpoch(u32),
+}
+impl From<TagMismatch> for OpenPacketError {
+    fn from(_: TagMismatch) -> Self {
+        Self::TagMismatch
+    }
+}
+
+pub fn split_packet(
+    packet: &[u8],
+) -> Result<(PacketHeader, &[u8], &[u8; TAG_BYTES]), OpenPacketError> {
+    if packet.len() < OVERHEAD_BYTES {
+        return Err(OpenPacketError::TooShort(packet.len()));
+    }
+    let (header, rest) = packet.split_at(HEADER_BYTES);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_BYTES);
+    let header = PacketHeader::from_bytes(header.try_into().unwrap());
+    Ok((header, ciphertext, tag.try_into().unwrap()))
+}
+
+/// Derive the key of the next epoch
+///
+/// The old key cannot be recovered from the new one.
+pub fn ratchet_key(key: [u8; KEY_BYTES]) -> [u8; KEY_BYTES] {
+    blake3::derive_key(RATCHET_CONTEXT, &key)
+}
+
+/// Seal packets with an incrementing counter so that nonces never repeat within a session
+#[derive(Debug, Clone)]
+pub struct DatagramSealer {
+    key: SecretKey,
+    iv: [u8; NONCE_BYTES],
+    epoch: u32,
+    next_counter: u64,
+}
+impl DatagramSealer {
+    /// `iv`: A per-session value shared with the opener
+    pub fn new(key: [u8; KEY_BYTES], iv: [u8; NONCE_BYTES]) -> Self {
+        Self {
+            key: key.into(),
+            iv,
+            epoch: 0,
+            next_counter: 0,
+        }
+    }
+
+    pub fn seal(&mut self, aad: &[u8], payload: &[u8]) -> Vec<u8> {
+        let counter = self.next_counter;
+        self.next_counter = counter.checked_add(1).expect("packet counter exhausted");
+        let header = PacketHeader {
+            epoch: self.epoch,
+            counter,
+        };
+        seal_packet(*self.key.expose_secret(), self.iv, header, aad, payload)
+    }
+
+    /// Move on to the next epoch with a ratcheted key
+    pub fn rekey(&mut self) {
+        self.key = ratchet_key(*self.key.expose_secret()).into();
+        self.epoch = self.epoch.checked_add(1).expect("epoch exhausted");
+        self.next_counter = 0;
+    }
+
+    pub fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    pub fn next_counter(&self) -> u64 {
+        self.next_counter
+    }
+}
+
+/// Open packets and reject the duplicated or replayed ones
+///
+/// Packets from the previous epoch are still accepted so that the in-flight ones survive a rekey.
+#[derive(Debug, Clone)]
+pub struct DatagramOpener {
+    iv: [u8; NONCE_BYTES],
+    current: EpochState,
+    previous: Option<EpochState>,
+}
+impl DatagramOpener {
+    pub fn new(key: [u8; KEY_BYTES], iv: [u8; NONCE_BYTES]) -> Self {
+        Self {
+            iv,
+            current: EpochState::new(0, key),
+            previous: None,
+        }
+    }
+
+    pub fn open(
+        &mut self,
+        aad: &[u8],
+        packet: &[u8],
+    ) -> Result<(PacketHeader, Vec<u8>), OpenPacketError> {
+        let (header, _, _) = split_packet(packet)?;
+
+        if Some(header.epoch) == self.current.epoch.checked_add(1) {
+            let mut next =
+                EpochState::new(header.epoch, ratchet_key(*self.current.key.expose_secret()));
+            let (header, payload) = next.open(self.iv, aad, packet)?;
+            // The peer has moved on to the next epoch
+            self.previous = Some(std::mem::replace(&mut self.current, next));
+            return Ok((header, payload));
+        }
+
+        let state = if header.epoch == self.current.epoch {
+            &mut self.current
+        } else if Some(header.epoch) == self.previous.as_ref().map(|s| s.epoch) {
+            self.previous.as_mut().unwrap()
+        } else {
+            return Err(OpenPacketError::UnknownEpoch(header.epoch));
+        };
+        state.open(self.iv, aad, packet)
+    }
+
+    pub fn epoch(&self) -> u32 {
+        self.current.epoch
+    }
+}
+
+#[derive(Debug, Clone)]
+struct EpochState {
+    epoch: u32,
+    key: SecretKey,
+    window: ReplayWindow,
+}
+impl EpochState {
+    pub fn new(epoch: u32, key: [u8; KEY_BYTES]) -> Self {
+        Self {
+            epoch,
+            key: key.into(),
+            window: ReplayWindow::new(),
+        }
+    }
+
+    pub fn open(
+        &mut self,
+        iv: [u8; NONCE_BYTES],
+        aad: &[u8],
+        packet: &[u8],
+    ) -> Result<(PacketHeader, Vec<u8>), OpenPacketError> {
+        let (header, _, _) = split_packet(packet)?;
+        if !self.window.check(header.counter) {
+            return Err(OpenPacketError::Replayed(header.counter));
+        }
+        let (header, payload) = open_packet(*self.key.expose_secret(), iv, aad, packet)?;
+        // Only authenticated packets are allowed to move the window
+        self.window.update(header.counter);
+        Ok((header, payload))
+    }
+}
+
+/// Return `iv XOR (0x00000000 || counter)`
+pub(crate) fn nonce(iv: [u8; NONCE_BYTES], counter: u64) -> [u8; NONCE_BYTES] {
+    let mut nonce = iv;
+    nonce[NONCE_BYTES - COUNTER_BYTES..]
+        .iter_mut()
+        .zip(counter.to_le_bytes())
+        .for_each(|(n, c)| *n ^= c);
+    nonce
+}
+
+/// AEAD_CHACHA20_POLY1305 from RFC 8439
+pub(crate) fn seal(
+    key: [u8; KEY_BYTES],
+    nonce: [u8; NONCE_BYTES],
+    aad: &[u8],
+    buf: &mut [u8],
+) -> [u8; TAG_BYTES] {
+    #[cfg(feature = "nonce-tracking")]
+    crate::nonce_tracker::record(&key, &nonce);
+    StreamCipher::new(key, nonce).encrypt(buf);
+    tag(key, nonce, aad, buf)
+}
+
+pub(crate) fn open(
+    key: [u8; KEY_BYTES],
+    nonce: [u8; NONCE_BYTES],
+    aad: &[u8],
+    buf: &mut [u8],
+    expected_tag: &[u8; TAG_BYTES],
+) -> Result<(), OpenPacketError> {
+    verify_tag(&tag(key, nonce, aad, buf), expected_tag)?;
+    StreamCipher::new(key, nonce).encrypt(buf);
+    Ok(())
+}
+
+fn tag(
+    key: [u8; KEY_BYTES],
+    nonce: [u8; NONCE_BYTES],
+    aad: &[u8],
+    ciphertext: &[u8],
+) -> [u8; TAG_BYTES] {
+    let mut hasher = AeadHasher::new(poly1305_key_gen(key, nonce), aad);
+    hasher.update(ciphertext);
+    hasher.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::tests::create_random_config;
+
+    use super::*;
+
+    #[test]
+    fn test_aead() {
+        let key = [
+            0x80, 0x81, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8a, 0x8b, 0x8c, 0x8d,
+            0x8e, 0x8f, 0x90, 0x91, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0x9b,
+            0x9c, 0x9d, 0x9e, 0x9f,
+        ];
+        let nonce = [
+            0x07, 0x00, 0x00, 0x00, 0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47,
+        ];
+        let aad = [
+            0x50, 0x51, 0x52, 0x53, 0xc0, 0xc1, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7,
+        ];
+        let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.";
+        let mut buf = *plaintext;
+        let tag = seal(key, nonce, &aad, &mut buf);
+        assert_eq!(
+            &buf[..16],
+            &[
+                0xd3, 0x1a, 0x8d, 0x34, 0x64, 0x8e, 0x60, 0xdb, 0x7b, 0x86, 0xaf, 0xbc, 0x53, 0xef,
+                0x7e, 0xc2,
+            ]
+        );
+        assert_eq!(
+            tag,
+            [
+                0x1a, 0xe1, 0x0b, 0x59, 0x4f, 0x09, 0xe2, 0x6a, 0x7e, 0x90, 0x2e, 0xcb, 0xd0, 0x60,
+                0x06, 0x91,
+            ]
+        );
+        open(key, nonce, &aad, &mut buf, &tag).unwrap();
+        assert_eq!(buf, *plaintext);
+    }
+
+    #[test]
+    fn test_packet() {
+        let config = create_random_config();
+        let iv = rand::random();
+        let msg = b"Hello world!";
+        let aad = b"header";
+        let header = PacketHeader {
+            epoch: 3,
+            counter: 42,
+        };
+
+        let packet = seal_packet(*config.key(), iv, header, aad, msg);
+        assert_eq!(packet.len(), OVERHEAD_BYTES + msg.len());
+        let (h, payload) = open_packet(*config.key(), iv, aad, &packet).unwrap();
+        assert_eq!(h, header);
+        assert_eq!(payload, msg);
+
+        assert!(open_packet(*config.key(), iv, b"", &packet).is_err());
+        assert!(open_packet(*config.key(), rand::random(), aad, &packet).is_err());
+        let mut tampered = packet.clone();
+        tampered[HEADER_BYTES] ^= 1;
+        assert!(open_packet(*config.key(), iv, aad, &tampered).is_err());
+        assert!(open_packet(*config.key(), iv, aad, &packet[..OVERHEAD_BYTES - 1]).is_err());
+
+        let header = PacketHeader {
+            epoch: 3,
+            counter: 43,
+        };
+        let packet = seal_packet(*config.key(), iv, header, &[], &[]);
+        let (_, payload) = open_packet(*config.key(), iv, &[], &packet).unwrap();
+        assert!(payload.is_empty());
+    }
+
+    #[test]
+    fn test_sealer_opener() {
+        let config = create_random_config();
+        let iv = rand::random();
+        let mut sealer = DatagramSealer::new(*config.key(), iv);
+        let mut opener = DatagramOpener::new(*config.key(), iv);
+
+        let msg = b"Hello world!";
+        let a = sealer.seal(&[], msg);
+        let b = sealer.seal(&[], msg);
+        assert_ne!(a[HEADER_BYTES..], b[HEADER_BYTES..]);
+        assert_eq!(opener.open(&[], &a).unwrap().0.counter, 0);
+        let (header, payload) = opener.open(&[], &b).unwrap();
+        assert_eq!(header.counter, 1);
+        assert_eq!(payload, msg);
+        assert_eq!(sealer.next_counter(), 2);
+
+        assert!(matches!(
+            opener.open(&[], &a),
+            Err(OpenPacketError::Replayed(0))
+        ));
+        let mut forged = sealer.seal(&[], msg);
+        let last = forged.len() - 1;
+        forged[last] ^= 1;
+        assert!(matches!(
+            opener.open(&[], &forged),
+            Err(OpenPacketError::TagMismatch)
+        ));
+        forged[last] ^= 1;
+        assert!(opener.open(&[], &forged).is_ok());
+    }
+
+    #[test]
+    fn test_rekey() {
+        let config = create_random_config();
+        let iv = rand::random();
+        let mut sealer = DatagramSealer::new(*config.key(), iv);
+        let mut opener = DatagramOpener::new(*config.key(), iv);
+
+        let msg = b"Hello world!";
+        let in_flight = sealer.seal(&[], msg);
+        sealer.rekey();
+        assert_eq!(sealer.epoch(), 1);
+        let a = sealer.seal(&[], msg);
+        assert_ne!(a[HEADER_BYTES..], in_flight[HEADER_BYTES..]);
+
+        let (header, _) = opener.open(&[], &a).unwrap();
+        assert_eq!(
+            header,
+            PacketHeader {
+                epoch: 1,
+                counter: 0
+            }
+        );
+        assert_eq!(opener.epoch(), 1);
+        let (header, payload) = opener.open(&[], &in_flight).unwrap();
+        assert_eq!(
+            header,
+            PacketHeader {
+                epoch: 0,
+                counter: 0
+            }
+        );
+        assert_eq!(payload, msg);
+        assert!(opener.open(&[], &in_flight).is_err());
+
+        sealer.rekey();
+        sealer.rekey();
+        let b = sealer.seal(&[], msg);
+        assert!(matches!(
+            opener.open(&[], &b),
+            Err(OpenPacketError::UnknownEpoch(3))
+        ));
+    }
+}
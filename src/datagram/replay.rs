@@ -0,0 +1,99 @@
+const WORDS: usize = 32;
+const WORD_BITS: u64 = u64::BITS as u64;
+/// One word is reserved so that advancing the window never clears a word still in use
+const WINDOW_SIZE: u64 = (WORDS as u64 - 1) * WORD_BITS;
+
+/// Anti-replay window over packet counters from RFC 6479
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayWindow {
+    bitmap: [u64; WORDS],
+    highest: Option<u64>,
+}
+impl ReplayWindow {
+    pub fn new() -> Self {
+        Self {
+            bitmap: [0; WORDS],
+            highest: None,
+        }
+    }
+
+    /// Return `false` if `counter` has been seen or is too old to tell
+    pub fn check(&self, counter: u64) -> bool {
+        let Some(highest) = self.highest else {
+            return true;
+        };
+        if highest < counter {
+            return true;
+        }
+        if WINDOW_SIZE <= highest - counter {
+            return false;
+        }
+        let (word, bit) = position(counter);
+        self.bitmap[word] & bit == 0
+    }
+
+    /// Mark `counter` as seen
+    ///
+    /// Should only be called after the packet has been authenticated
+    pub fn update(&mut self, counter: u64) {
+        match self.highest {
+            Some(highest) if counter <= highest => (),
+            _ => {
+                if let Some(highest) = self.highest {
+                    // Clear the words the window is sliding over
+                    let diff = (counter / WORD_BITS - highest / WORD_BITS).min(WORDS as u64);
+                    for i in 1..=diff {
+                        let word = ((highest / WORD_BITS + i) % WORDS as u64) as usize;
+                        self.bitmap[word] = 0;
+                    }
+                }
+                self.highest = Some(counter);
+            }
+        }
+        let (word, bit) = position(counter);
+        self.bitmap[word] |= bit;
+    }
+
+    pub fn highest(&self) -> Option<u64> {
+        self.highest
+    }
+}
+impl Default for ReplayWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn position(counter: u64) -> (usize, u64) {
+    let word = ((counter / WORD_BITS) % WORDS as u64) as usize;
+    let bit = 1 << (counter % WORD_BITS);
+    (word, bit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_window() {
+        let mut w = ReplayWindow::new();
+        for counter in [0, 1, 3, 2, 100, 64, 99] {
+            assert!(w.check(counter));
+            w.update(counter);
+            assert!(!w.check(counter));
+        }
+        assert!(w.check(4));
+        assert_eq!(w.highest(), Some(100));
+
+        w.update(WINDOW_SIZE + 100);
+        assert!(!w.check(100));
+        assert!(w.check(101));
+        assert!(!w.check(0));
+
+        w.update(u64::MAX - 1);
+        assert!(w.check(u64::MAX));
+        assert!(!w.check(u64::MAX - 1));
+        assert!(w.check(u64::MAX - WINDOW_SIZE + 1));
+        assert!(!w.check(u64::MAX - WINDOW_SIZE - 1));
+    }
+}
@@ -0,0 +1,152 @@
+use core::{fmt, net::SocketAddr};
+
+use alloc::sync::Arc;
+
+use crate::{mac::constant_time_eq, KEY_BYTES};
+
+/// Names one of several keys on the wire so that the reader can pick it
+pub type KeyId = u8;
+
+/// Tells apart messages under old and new keys on the wire, authenticated by the tag
+pub type KeyVersion = u8;
+
+/// Resolves the key of each incoming connection, e.g. from a database or a cache of tenants
+pub trait KeyProvider: Send + Sync {
+    /// `key_id`: The key ID sent by the peer if the acceptor reads one
+    ///
+    /// `None` turns the connection away.
+    fn key_for(&self, peer: SocketAddr, key_id: Option<KeyId>) -> Option<Arc<SecretKey>>;
+}
+impl<F> KeyProvider for F
+where
+    F: Fn(SocketAddr, Option<KeyId>) -> Option<Arc<SecretKey>> + Send + Sync,
+{
+    fn key_for(&self, peer: SocketAddr, key_id: Option<KeyId>) -> Option<Arc<SecretKey>> {
+        self(peer, key_id)
+    }
+}
+
+/// Overwrite `buf` with zeros in a way that is not optimized out
+pub(crate) fn zeroize(buf: &mut [u8]) {
+    for b in buf.iter_mut() {
+        // SAFETY: `b` is a valid and aligned `u8`
+        unsafe { core::ptr::write_volatile(b, 0) };
+    }
+    core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+}
+
+/// A key that stays out of logs and is zeroed on drop
+///
+/// `Debug` and `Display` are redacted and there is no `Serialize`.
+/// Fields that have to persist the raw bytes opt in with `#[serde(with = "crate::key::expose")]`.
+#[derive(Clone)]
+pub struct SecretKey([u8; KEY_BYTES]);
+impl SecretKey {
+    pub fn new(key: [u8; KEY_BYTES]) -> Self {
+        Self(key)
+    }
+
+    /// The raw key bytes
+    pub fn expose_secret(&self) -> &[u8; KEY_BYTES] {
+        &self.0
+    }
+}
+impl From<[u8; KEY_BYTES]> for SecretKey {
+    fn from(key: [u8; KEY_BYTES]) -> Self {
+        Self::new(key)
+    }
+}
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        zeroize(&mut self.0);
+    }
+}
+impl PartialEq for SecretKey {
+    fn eq(&self, other: &Self) -> bool {
+        constant_time_eq(&self.0, &other.0)
+    }
+}
+impl Eq for SecretKey {}
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretKey(<redacted>)")
+    }
+}
+impl fmt::Display for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+/// (De)serialize the raw bytes of a [`SecretKey`] on purpose
+#[cfg(any(feature = "std", feature = "serde"))]
+pub mod expose {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::SecretKey;
+    use crate::KEY_BYTES;
+
+    pub fn serialize<S: Serializer>(key: &SecretKey, serializer: S) -> Result<S::Ok, S::Error> {
+        key.expose_secret().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SecretKey, D::Error> {
+        <[u8; KEY_BYTES]>::deserialize(deserializer).map(SecretKey::new)
+    }
+
+    /// The same for a key shared behind an `Arc`
+    pub mod shared {
+        use alloc::sync::Arc;
+
+        use serde::{Deserializer, Serializer};
+
+        use super::SecretKey;
+
+        pub fn serialize<S: Serializer>(
+            key: &Arc<SecretKey>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            super::serialize(key, serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Arc<SecretKey>, D::Error> {
+            super::deserialize(deserializer).map(Arc::new)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redacted() {
+        let key = SecretKey::new([0xab; KEY_BYTES]);
+        assert_eq!(format!("{key:?}"), "SecretKey(<redacted>)");
+        assert_eq!(format!("{key}"), "<redacted>");
+        assert_eq!(key, key.clone());
+        assert_ne!(key, SecretKey::new([0; KEY_BYTES]));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_cursor_debug() {
+        use crate::cursor::{DecryptCursor, EncryptCursor};
+
+        // Neither the key bytes (171) nor the key words (2880154539) may show up
+        let key = [0xab; KEY_BYTES];
+        let mut en = EncryptCursor::new(key);
+        let mut buf = [0; 64];
+        let (_, n) = en.encrypt(b"hello", &mut buf).unwrap();
+        let mut de = DecryptCursor::new(key);
+        de.decrypt(&mut buf[..n]).unwrap();
+
+        let debug = format!("{de:?}");
+        assert!(debug.contains("UserData"), "{debug}");
+        // The random nonce may contain a lone 171
+        assert!(!debug.contains("171, 171, 171"), "{debug}");
+        assert!(!debug.contains("2880154539"), "{debug}");
+    }
+}
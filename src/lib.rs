@@ -1,12 +1,131 @@
-#![feature(test)]
-extern crate test;
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+extern crate alloc;
 
 pub mod cipher;
+#[cfg(feature = "std")]
+pub mod codec;
+#[cfg(feature = "std")]
 pub mod config;
 pub mod cursor;
+#[cfg(feature = "std")]
+pub mod datagram;
+pub mod key;
 pub mod mac;
+#[cfg(feature = "nonce-tracking")]
+pub mod nonce_tracker;
+#[cfg(feature = "std")]
+pub mod owned;
+#[cfg(feature = "std")]
 pub mod stream;
+#[cfg(feature = "std")]
+pub mod sync;
 
 pub const NONCE_BYTES: usize = 12;
 pub const X_NONCE_BYTES: usize = 24;
 pub const KEY_BYTES: usize = 32;
+/// Poly1305 tag of the AEAD formats
+pub const TAG_BYTES: usize = mac::BLOCK_BYTES;
+pub use cipher::BLOCK_SIZE;
+
+/// Wire size of `plaintext_len` bytes of user data without a plaintext header
+///
+/// - `nonce_bytes`: [`NONCE_BYTES`] or [`X_NONCE_BYTES`] if the nonce goes in front, `0` otherwise
+/// - `with_tag`: Whether a tag of [`TAG_BYTES`] follows the ciphertext
+pub const fn ciphertext_len(plaintext_len: usize, nonce_bytes: usize, with_tag: bool) -> usize {
+    ciphertext_len_with_header(plaintext_len, 0, nonce_bytes, with_tag)
+}
+
+/// User data in `ciphertext_len` wire bytes; the inverse of [`ciphertext_len`]
+///
+/// `None` if `ciphertext_len` cannot even hold the nonce and the tag.
+pub const fn plaintext_len(
+    ciphertext_len: usize,
+    nonce_bytes: usize,
+    with_tag: bool,
+) -> Option<usize> {
+    plaintext_len_with_header(ciphertext_len, 0, nonce_bytes, with_tag)
+}
+
+/// [`ciphertext_len`] with a plaintext header in front of the nonce
+///
+/// `header_bytes`: The prefix plus a byte each for the key ID and the key version if sent,
+/// i.e. [`EncryptCursor::header_len`](cursor::EncryptCursor::header_len)
+pub const fn ciphertext_len_with_header(
+    plaintext_len: usize,
+    header_bytes: usize,
+    nonce_bytes: usize,
+    with_tag: bool,
+) -> usize {
+    header_bytes + nonce_bytes + plaintext_len + tag_len(with_tag)
+}
+
+/// User data in `ciphertext_len` wire bytes; the inverse of [`ciphertext_len_with_header`]
+///
+/// `None` if `ciphertext_len` cannot even hold the header, the nonce, and the tag.
+pub const fn plaintext_len_with_header(
+    ciphertext_len: usize,
+    header_bytes: usize,
+    nonce_bytes: usize,
+    with_tag: bool,
+) -> Option<usize> {
+    ciphertext_len.checked_sub(header_bytes + nonce_bytes + tag_len(with_tag))
+}
+
+const fn tag_len(with_tag: bool) -> usize {
+    match with_tag {
+        true => TAG_BYTES,
+        false => 0,
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use crate::{config::tests::create_random_config, cursor::EncryptCursor};
+
+    use super::*;
+
+    #[test]
+    fn test_lengths() {
+        let config = create_random_config();
+        let msg = b"Hello world!";
+        let mut en = EncryptCursor::new_x(*config.key()).with_aad(b"");
+        let mut buf = [0; 1024];
+        let (_, n) = en.encrypt(msg, &mut buf).unwrap();
+        let wire = n + en.tag().unwrap().len();
+
+        assert_eq!(ciphertext_len(msg.len(), X_NONCE_BYTES, true), wire);
+        assert_eq!(plaintext_len(wire, X_NONCE_BYTES, true), Some(msg.len()));
+        assert_eq!(plaintext_len(n, X_NONCE_BYTES, false), Some(msg.len()));
+        assert_eq!(plaintext_len(TAG_BYTES, X_NONCE_BYTES, true), None);
+    }
+
+    #[test]
+    fn test_lengths_with_header() {
+        let config = create_random_config();
+        let msg = b"Hello world!";
+        let mut en = EncryptCursor::new(*config.key())
+            .with_prefix(b"v1")
+            .with_key_id(1)
+            .with_key_version(2)
+            .with_aad(b"");
+        let mut buf = [0; 1024];
+        let (_, n) = en.encrypt(msg, &mut buf).unwrap();
+        let wire = n + en.tag().unwrap().len();
+        let header = en.header_len();
+        assert_eq!(header, 4);
+
+        assert_eq!(
+            ciphertext_len_with_header(msg.len(), header, NONCE_BYTES, true),
+            wire
+        );
+        assert_eq!(
+            plaintext_len_with_header(wire, header, NONCE_BYTES, true),
+            Some(msg.len())
+        );
+        assert_eq!(
+            plaintext_len_with_header(NONCE_BYTES + TAG_BYTES, header, NONCE_BYTES, true),
+            None
+        );
+    }
+}
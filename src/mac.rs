@@ -1,5 +1,4 @@
 use arrayvec::ArrayVec;
-use num_bigint::BigUint;
 
 use crate::{cipher::ChaCha20, KEY_BYTES, NONCE_BYTES};
 
@@ -25,36 +24,339 @@ fn s(key: &[u8; KEY_BYTES]) -> [u8; BLOCK_BYTES] {
 
 /// `key`: Should be a one-time key generated from `poly1305_key_gen`
 pub fn poly1305_mac(key: [u8; KEY_BYTES], msg: &[u8]) -> [u8; BLOCK_BYTES] {
-    let mut r: [u8; BLOCK_BYTES] = r(&key);
-    let s: [u8; BLOCK_BYTES] = s(&key);
-    clamp_r(&mut r);
-    let mut cum = BigUint::new(vec![0]);
-
-    let r = BigUint::from_bytes_le(&r);
-    let s = BigUint::from_bytes_le(&s);
-    let p = BigUint::new(vec![2]).pow(130) - BigUint::new(vec![5]);
-
-    const BLOCK_BYTES_PLUS_1: usize = BLOCK_BYTES + 1;
-    msg.chunks(BLOCK_BYTES).for_each(|c| {
-        let mut n: ArrayVec<u8, BLOCK_BYTES_PLUS_1> = c.try_into().unwrap();
-        n.push(0x1);
-        let n = BigUint::from_bytes_le(&n);
-        cum += n;
-        cum = (&r * &cum) % &p;
-    });
-    cum += &s;
-
-    let mut cum = cum.to_bytes_le();
-    cum.truncate(16);
-    let n = 16 - cum.len();
-    cum.extend(std::iter::repeat(0).take(n));
-    cum.try_into().unwrap()
+    let mut hasher = Poly1305Hasher::new(key);
+    hasher.update(msg);
+    hasher.finalize()
 }
 
+/// Poly1305 over a message arriving in pieces
+///
+/// The accumulator is kept in 26-bit limbs so every block takes the same time and no allocation.
+///
+/// Not `PartialEq` since comparing the key-derived limbs would leak them through timing; compare tags by [`verify_tag`] instead.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Poly1305Hasher {
+    /// `r`, `r^2`, `r^3`, and `r^4`
+    r_pow: [[u32; 5]; BATCH_BLOCKS],
+    s: [u32; 4],
+    h: [u32; 5],
+    /// Bytes short of a full block in `partial[..partial_len]`
+    partial: [u8; BLOCK_BYTES],
+    partial_len: usize,
+}
+impl Poly1305Hasher {
+    /// `key`: Should be a one-time key generated from `poly1305_key_gen`
+    pub fn new(key: [u8; KEY_BYTES]) -> Self {
+        let mut r: [u8; BLOCK_BYTES] = r(&key);
+        let s: [u8; BLOCK_BYTES] = s(&key);
+        clamp_r(&mut r);
+        let r = limbs(&r);
+        let mut r_pow = [r; BATCH_BLOCKS];
+        for i in 1..BATCH_BLOCKS {
+            r_pow[i] = carry(mul(r_pow[i - 1], &r));
+        }
+        Self {
+            r_pow,
+            s: core::array::from_fn(|i| le_u32(&s, i * 4)),
+            h: [0; 5],
+            partial: [0; BLOCK_BYTES],
+            partial_len: 0,
+        }
+    }
+
+    pub fn update(&mut self, mut msg: &[u8]) {
+        if self.partial_len != 0 {
+            let n = (BLOCK_BYTES - self.partial_len).min(msg.len());
+            self.partial[self.partial_len..][..n].copy_from_slice(&msg[..n]);
+            self.partial_len += n;
+            msg = &msg[n..];
+            if self.partial_len != BLOCK_BYTES {
+                return;
+            }
+            self.partial_len = 0;
+            self.h = self.absorb(self.h, &self.partial, HIBIT);
+        }
+        let mut batches = msg.chunks_exact(BATCH_BLOCKS * BLOCK_BYTES);
+        for c in &mut batches {
+            self.h = self.absorb_4(self.h, c.try_into().unwrap());
+        }
+        let mut chunks = batches.remainder().chunks_exact(BLOCK_BYTES);
+        for c in &mut chunks {
+            self.h = self.absorb(self.h, c.try_into().unwrap(), HIBIT);
+        }
+        let rest = chunks.remainder();
+        self.partial[..rest.len()].copy_from_slice(rest);
+        self.partial_len = rest.len();
+    }
+
+    /// Tag of the message so far; more can still be fed with [`Self::update`]
+    pub fn current_tag(&self) -> [u8; BLOCK_BYTES] {
+        let h = match self.partial_len {
+            0 => self.h,
+            len => {
+                // The padding takes the place of the high bit
+                let mut block = [0; BLOCK_BYTES];
+                block[..len].copy_from_slice(&self.partial[..len]);
+                block[len] = 1;
+                self.absorb(self.h, &block, 0)
+            }
+        };
+        tag(h, &self.s)
+    }
+
+    pub fn finalize(self) -> [u8; BLOCK_BYTES] {
+        self.current_tag()
+    }
+
+    /// `(h + block) * r mod 2^130 - 5`
+    fn absorb(&self, h: [u32; 5], block: &[u8; BLOCK_BYTES], hibit: u32) -> [u32; 5] {
+        carry(mul(add(h, block, hibit), &self.r_pow[0]))
+    }
+
+    /// Four blocks in one step of Horner's method over `r^4`
+    ///
+    /// `(h + m0) * r^4 + m1 * r^3 + m2 * r^2 + m3 * r mod 2^130 - 5`
+    fn absorb_4(&self, h: [u32; 5], blocks: &[u8; BATCH_BLOCKS * BLOCK_BYTES]) -> [u32; 5] {
+        let mut d = [0; 5];
+        for (i, block) in blocks.chunks_exact(BLOCK_BYTES).enumerate() {
+            let m = match i {
+                0 => add(h, block.try_into().unwrap(), HIBIT),
+                _ => add([0; 5], block.try_into().unwrap(), HIBIT),
+            };
+            let p = mul(m, &self.r_pow[BATCH_BLOCKS - 1 - i]);
+            d.iter_mut().zip(p).for_each(|(d, p)| *d += p);
+        }
+        carry(d)
+    }
+}
+impl core::fmt::Debug for Poly1305Hasher {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Poly1305Hasher")
+            .field("partial_len", &self.partial_len)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Feeds everything written to [`Poly1305Hasher::update`]
+#[cfg(feature = "std")]
+impl std::io::Write for Poly1305Hasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+/// Feeds everything written to [`Poly1305Hasher::update`], e.g. from `tokio::io::copy`
+#[cfg(feature = "std")]
+impl tokio::io::AsyncWrite for Poly1305Hasher {
+    fn poll_write(
+        self: core::pin::Pin<&mut Self>,
+        _cx: &mut core::task::Context<'_>,
+        buf: &[u8],
+    ) -> core::task::Poll<std::io::Result<usize>> {
+        self.get_mut().update(buf);
+        Ok(buf.len()).into()
+    }
+
+    fn poll_flush(
+        self: core::pin::Pin<&mut Self>,
+        _cx: &mut core::task::Context<'_>,
+    ) -> core::task::Poll<std::io::Result<()>> {
+        Ok(()).into()
+    }
+
+    fn poll_shutdown(
+        self: core::pin::Pin<&mut Self>,
+        _cx: &mut core::task::Context<'_>,
+    ) -> core::task::Poll<std::io::Result<()>> {
+        Ok(()).into()
+    }
+}
+
+/// Blocks absorbed at a time by [`Poly1305Hasher::absorb_4`]
+const BATCH_BLOCKS: usize = 4;
+
+/// `h + block` without carrying
+fn add(h: [u32; 5], block: &[u8; BLOCK_BYTES], hibit: u32) -> [u32; 5] {
+    let mut m = limbs(block);
+    m[4] |= hibit;
+    core::array::from_fn(|i| h[i] + m[i])
+}
+
+/// `h * r` folded at 2^130 into `5` without carrying
+fn mul(h: [u32; 5], r: &[u32; 5]) -> [u64; 5] {
+    let [h0, h1, h2, h3, h4] = h.map(u64::from);
+    let [r0, r1, r2, r3, r4] = r.map(u64::from);
+    let [s1, s2, s3, s4] = [r1 * 5, r2 * 5, r3 * 5, r4 * 5];
+    [
+        h0 * r0 + h1 * s4 + h2 * s3 + h3 * s2 + h4 * s1,
+        h0 * r1 + h1 * r0 + h2 * s4 + h3 * s3 + h4 * s2,
+        h0 * r2 + h1 * r1 + h2 * r0 + h3 * s4 + h4 * s3,
+        h0 * r3 + h1 * r2 + h2 * r1 + h3 * r0 + h4 * s4,
+        h0 * r4 + h1 * r3 + h2 * r2 + h3 * r1 + h4 * r0,
+    ]
+}
+
+/// Partial carry back into 26-bit limbs
+fn carry(d: [u64; 5]) -> [u32; 5] {
+    let mut h = [0; 5];
+    let mut c = 0;
+    for (h, d) in h.iter_mut().zip(d) {
+        let d = d + c;
+        c = d >> 26;
+        *h = d as u32 & LIMB_MASK;
+    }
+    // The carry out of a batch of products can exceed 32 bits
+    let h0 = u64::from(h[0]) + c * 5;
+    h[0] = h0 as u32 & LIMB_MASK;
+    h[1] += (h0 >> 26) as u32;
+    h
+}
+
+const LIMB_MASK: u32 = (1 << 26) - 1;
+/// The bit appended to every full block
+const HIBIT: u32 = 1 << 24;
+
+fn le_u32(bytes: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap())
+}
+
+/// Split 128 bits into 26-bit limbs
+fn limbs(bytes: &[u8; BLOCK_BYTES]) -> [u32; 5] {
+    [
+        le_u32(bytes, 0) & LIMB_MASK,
+        (le_u32(bytes, 3) >> 2) & LIMB_MASK,
+        (le_u32(bytes, 6) >> 4) & LIMB_MASK,
+        (le_u32(bytes, 9) >> 6) & LIMB_MASK,
+        le_u32(bytes, 12) >> 8,
+    ]
+}
+
+/// Tag of AEAD_CHACHA20_POLY1305 from RFC 8439 over ciphertext arriving in pieces
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AeadHasher {
+    hasher: Poly1305Hasher,
+    aad_len: u64,
+    ciphertext_len: u64,
+}
+impl AeadHasher {
+    /// `key`: Should be a one-time key generated from `poly1305_key_gen`
+    pub fn new(key: [u8; KEY_BYTES], aad: &[u8]) -> Self {
+        let mut hasher = Poly1305Hasher::new(key);
+        hasher.update(aad);
+        hasher.update(&[0; BLOCK_BYTES][..pad(aad.len() as u64)]);
+        Self {
+            hasher,
+            aad_len: aad.len() as u64,
+            ciphertext_len: 0,
+        }
+    }
+
+    pub fn update(&mut self, ciphertext: &[u8]) {
+        self.hasher.update(ciphertext);
+        self.ciphertext_len += ciphertext.len() as u64;
+    }
+
+    /// Tag of the ciphertext so far; more can still be fed with [`Self::update`]
+    pub fn current_tag(&self) -> [u8; BLOCK_BYTES] {
+        let mut hasher = self.hasher.clone();
+        hasher.update(&[0; BLOCK_BYTES][..pad(self.ciphertext_len)]);
+        hasher.update(&self.aad_len.to_le_bytes());
+        hasher.update(&self.ciphertext_len.to_le_bytes());
+        hasher.finalize()
+    }
+
+    pub fn finalize(self) -> [u8; BLOCK_BYTES] {
+        self.current_tag()
+    }
+}
+
+fn pad(len: u64) -> usize {
+    (BLOCK_BYTES - (len % BLOCK_BYTES as u64) as usize) % BLOCK_BYTES
+}
+
+/// `(h mod 2^130 - 5) + s mod 2^128`
+fn tag(mut h: [u32; 5], s: &[u32; 4]) -> [u8; BLOCK_BYTES] {
+    // Full carry
+    let mut c = 0;
+    for h in &mut h[1..] {
+        *h += c;
+        c = *h >> 26;
+        *h &= LIMB_MASK;
+    }
+    h[0] += c * 5;
+    h[1] += h[0] >> 26;
+    h[0] &= LIMB_MASK;
+
+    // `g = h - p` selected without branching if `h >= p`
+    let mut g = [0; 5];
+    let mut c = 5;
+    for (g, h) in g[..4].iter_mut().zip(&h) {
+        *g = h + c;
+        c = *g >> 26;
+        *g &= LIMB_MASK;
+    }
+    g[4] = (h[4] + c).wrapping_sub(1 << 26);
+    let select_g = (g[4] >> 31).wrapping_sub(1);
+    for (h, g) in h.iter_mut().zip(&g) {
+        *h = (*h & !select_g) | (g & select_g);
+    }
+
+    // Pack into 32-bit words and add `s`
+    let words = [
+        h[0] | (h[1] << 26),
+        (h[1] >> 6) | (h[2] << 20),
+        (h[2] >> 12) | (h[3] << 14),
+        (h[3] >> 18) | (h[4] << 8),
+    ];
+    let mut out = [0; BLOCK_BYTES];
+    let mut carry = 0;
+    for ((out, word), s) in out.chunks_exact_mut(4).zip(words).zip(s) {
+        let f = word as u64 + *s as u64 + carry;
+        out.copy_from_slice(&(f as u32).to_le_bytes());
+        carry = f >> 32;
+    }
+    out
+}
+
+/// Compare two tags or keys in constant time
+pub fn constant_time_eq<const N: usize>(a: &[u8; N], b: &[u8; N]) -> bool {
+    // Hiding every step keeps the optimizer from leaving the loop at the first difference
+    let diff = a
+        .iter()
+        .zip(b)
+        .fold(0, |acc, (a, b)| core::hint::black_box(acc | (a ^ b)));
+    diff == 0
+}
+
+/// Check a received tag against the expected one in constant time
+pub fn verify_tag<const N: usize>(expected: &[u8; N], tag: &[u8; N]) -> Result<(), TagMismatch> {
+    match constant_time_eq(expected, tag) {
+        true => Ok(()),
+        false => Err(TagMismatch),
+    }
+}
+
+/// The data was forged, corrupted or sealed under another key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TagMismatch;
+impl core::fmt::Display for TagMismatch {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "authentication tag mismatch")
+    }
+}
+#[cfg(feature = "std")]
+impl std::error::Error for TagMismatch {}
+
 /// Generate a one-time key for `poly1305_mac`
 pub fn poly1305_key_gen_8_byte_nonce(key: [u8; KEY_BYTES], nonce: [u8; 8]) -> [u8; KEY_BYTES] {
     let mut nonce: ArrayVec<u8, 12> = nonce.as_slice().try_into().unwrap();
-    nonce.extend(std::iter::repeat(0).take(12 - 8));
+    nonce.extend(core::iter::repeat_n(0, 12 - 8));
     poly1305_key_gen(key, nonce.as_slice().try_into().unwrap())
 }
 
@@ -70,6 +372,17 @@ pub fn poly1305_key_gen(key: [u8; KEY_BYTES], nonce: [u8; NONCE_BYTES]) -> [u8;
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_hasher_debug() {
+        // Neither the key bytes (171) nor the `s` limbs (2880154539) may show up
+        let mut hasher = AeadHasher::new([0xab; KEY_BYTES], b"aad");
+        hasher.update(b"hello");
+        let debug = format!("{hasher:?}");
+        assert!(debug.contains("Poly1305Hasher"), "{debug}");
+        assert!(!debug.contains("171"), "{debug}");
+        assert!(!debug.contains("2880154539"), "{debug}");
+    }
+
     #[test]
     fn test_mac() {
         let key = [
@@ -113,6 +426,123 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_mac_edge_cases() {
+        // Test vectors from RFC 8439 Appendix A.3 exercising the final reduction
+        fn key(r: u8, s: u8) -> [u8; KEY_BYTES] {
+            let mut key = [0; KEY_BYTES];
+            key[0] = r;
+            key[BLOCK_BYTES..].fill(s);
+            key
+        }
+        fn block(first: u8, rest: u8) -> [u8; BLOCK_BYTES] {
+            let mut block = [rest; BLOCK_BYTES];
+            block[0] = first;
+            block
+        }
+
+        let mut tag = [0; BLOCK_BYTES];
+        tag[0] = 3;
+        assert_eq!(poly1305_mac(key(2, 0), &[0xff; BLOCK_BYTES]), tag);
+        assert_eq!(poly1305_mac(key(2, 0xff), &block(2, 0)), tag);
+
+        let msg = [block(0xff, 0xff), block(0xf0, 0xff), block(0x11, 0)].concat();
+        tag[0] = 5;
+        assert_eq!(poly1305_mac(key(1, 0), &msg), tag);
+
+        let msg = [block(0xff, 0xff), block(0xfb, 0xfe), block(1, 1)].concat();
+        assert_eq!(poly1305_mac(key(1, 0), &msg), [0; BLOCK_BYTES]);
+
+        assert_eq!(
+            poly1305_mac(key(2, 0), &block(0xfd, 0xff)),
+            block(0xfa, 0xff)
+        );
+    }
+
+    #[test]
+    fn test_hasher() {
+        let key = [7; KEY_BYTES];
+        let msg: alloc::vec::Vec<u8> = (0..100).collect();
+
+        let mut hasher = Poly1305Hasher::new(key);
+        for (i, c) in msg.chunks(7).enumerate() {
+            hasher.update(c);
+            let fed = (i * 7 + c.len()).min(msg.len());
+            assert_eq!(hasher.current_tag(), poly1305_mac(key, &msg[..fed]));
+        }
+        assert_eq!(hasher.finalize(), poly1305_mac(key, &msg));
+    }
+
+    #[cfg(feature = "std")]
+    #[tokio::test]
+    async fn test_hasher_writer() {
+        let key = [7; KEY_BYTES];
+        let msg: alloc::vec::Vec<u8> = (0..100).collect();
+
+        let mut hasher = Poly1305Hasher::new(key);
+        std::io::copy(&mut &msg[..50], &mut hasher).unwrap();
+        tokio::io::copy(&mut &msg[50..], &mut hasher).await.unwrap();
+        assert_eq!(hasher.finalize(), poly1305_mac(key, &msg));
+    }
+
+    #[test]
+    fn test_batched() {
+        let counting: alloc::vec::Vec<u8> = (0..=u8::MAX).cycle().take(1000).collect();
+        for (key, msg) in [
+            ([7; KEY_BYTES], &counting[..]),
+            ([0xff; KEY_BYTES], &[0xff; 1000]),
+        ] {
+            // Feed one block at a time to stay off the batched path
+            let mut hasher = Poly1305Hasher::new(key);
+            for c in msg.chunks(BLOCK_BYTES) {
+                hasher.update(c);
+            }
+            assert_eq!(hasher.finalize(), poly1305_mac(key, msg));
+        }
+    }
+
+    #[test]
+    fn test_batched_vectors() {
+        // Test vectors #3 and #4 from RFC 8439 Appendix A.3, long enough for the batched path
+        let ietf = b"Any submission to the IETF intended by the Contributor for publication as all or part of an IETF Internet-Draft or RFC and any statement made within the context of an IETF activity is considered an \"IETF Contribution\". Such statements include oral statements in IETF sessions, as well as written and electronic communications made at any time or place, which are addressed to";
+        let mut ietf_key = [0; KEY_BYTES];
+        ietf_key[..BLOCK_BYTES].copy_from_slice(&[
+            0x36, 0xe5, 0xf6, 0xb5, 0xc5, 0xe0, 0x60, 0x70, 0xf0, 0xef, 0xca, 0x96, 0x22, 0x7a,
+            0x86, 0x3e,
+        ]);
+        let ietf_tag = [
+            0xf3, 0x47, 0x7e, 0x7c, 0xd9, 0x54, 0x17, 0xaf, 0x89, 0xa6, 0xb8, 0x79, 0x4c, 0x31,
+            0x0c, 0xf0,
+        ];
+        let jabberwocky = b"'Twas brillig, and the slithy toves\nDid gyre and gimble in the wabe:\nAll mimsy were the borogoves,\nAnd the mome raths outgrabe.";
+        let jabberwocky_key = [
+            0x1c, 0x92, 0x40, 0xa5, 0xeb, 0x55, 0xd3, 0x8a, 0xf3, 0x33, 0x88, 0x86, 0x04, 0xf6,
+            0xb5, 0xf0, 0x47, 0x39, 0x17, 0xc1, 0x40, 0x2b, 0x80, 0x09, 0x9d, 0xca, 0x5c, 0xbc,
+            0x20, 0x70, 0x75, 0xc0,
+        ];
+        let jabberwocky_tag = [
+            0x45, 0x41, 0x66, 0x9a, 0x7e, 0xaa, 0xee, 0x61, 0xe7, 0x08, 0xdc, 0x7c, 0xbc, 0xc5,
+            0xeb, 0x62,
+        ];
+        assert_eq!(ietf.len(), 375);
+        assert_eq!(jabberwocky.len(), 127);
+
+        for (key, msg, tag) in [
+            (ietf_key, &ietf[..], ietf_tag),
+            (jabberwocky_key, &jabberwocky[..], jabberwocky_tag),
+        ] {
+            assert_eq!(poly1305_mac(key, msg), tag);
+            // Ragged pieces that start batches off block boundaries
+            for size in [1, 5, 17, 63, 65, 100] {
+                let mut hasher = Poly1305Hasher::new(key);
+                for c in msg.chunks(size) {
+                    hasher.update(c);
+                }
+                assert_eq!(hasher.finalize(), tag, "{size}");
+            }
+        }
+    }
+
     #[test]
     fn test_key_gen() {
         let key = [
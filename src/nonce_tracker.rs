@@ -0,0 +1,50 @@
+//! Opt-in guard against encrypting twice under the same key and nonce
+//!
+//! Every nonce an encryptor starts from is recorded for the life of the process, so memory grows with each stream and datagram.
+//! Meant for debugging and tests rather than production.
+
+use std::{
+    collections::HashSet,
+    sync::{Mutex, OnceLock},
+};
+
+use crate::KEY_BYTES;
+
+/// Fingerprints of the `(key, nonce)` pairs used so far
+fn seen() -> &'static Mutex<HashSet<[u8; 32]>> {
+    static SEEN: OnceLock<Mutex<HashSet<[u8; 32]>>> = OnceLock::new();
+    SEEN.get_or_init(Default::default)
+}
+
+/// Record that `nonce` starts a keystream of `key`
+///
+/// Panics if the pair has been recorded before.
+pub fn record(key: &[u8; KEY_BYTES], nonce: &[u8]) {
+    // Keep neither the key nor the pair in the clear
+    let fingerprint = *blake3::keyed_hash(key, nonce).as_bytes();
+    let fresh = seen()
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(fingerprint);
+    assert!(fresh, "nonce reused under the same key");
+}
+
+/// Forget every recorded pair
+pub fn clear() {
+    seen().lock().unwrap_or_else(|e| e.into_inner()).clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cursor::EncryptCursor;
+
+    #[test]
+    #[should_panic(expected = "nonce reused")]
+    fn test_reuse() {
+        let key = rand::random();
+        let nonce = rand::random();
+        let _ = EncryptCursor::from_nonce(key, nonce);
+        let _ = EncryptCursor::from_nonce(rand::random(), nonce);
+        let _ = EncryptCursor::from_nonce(key, nonce);
+    }
+}
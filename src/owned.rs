@@ -0,0 +1,263 @@
+//! Completion-based IO where buffers are owned by the runtime during IO, e.g. `tokio-uring` and `monoio`
+
+use std::{future::Future, io};
+
+use crate::{
+    cursor::{CursorError, NonceReadCursor, NonceWriteCursor, ReadCursorState, WriteCursorState},
+    stream::Error,
+    KEY_BYTES,
+};
+
+pub trait OwnedRead {
+    /// Read into `buf[..buf.len()]` and return the amount of bytes read along with the buffer
+    fn read_owned(&mut self, buf: Vec<u8>) -> impl Future<Output = (io::Result<usize>, Vec<u8>)>;
+}
+
+pub trait OwnedWrite {
+    /// Write from `buf` and return the amount of bytes written along with the buffer
+    fn write_owned(&mut self, buf: Vec<u8>) -> impl Future<Output = (io::Result<usize>, Vec<u8>)>;
+}
+
+#[derive(Debug)]
+pub struct OwnedReadHalf<R> {
+    cursor: Option<WriteCursorState>,
+    r: R,
+}
+impl<R> OwnedReadHalf<R> {
+    pub fn new(key: [u8; KEY_BYTES], r: R) -> Self {
+        let cursor = Some(WriteCursorState::Nonce(NonceWriteCursor::new(key)));
+        Self { cursor, r }
+    }
+    pub fn new_x(key: [u8; KEY_BYTES], r: R) -> Self {
+        let cursor = Some(WriteCursorState::Nonce(NonceWriteCursor::new_x(key)));
+        Self { cursor, r }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.r
+    }
+}
+impl<R: OwnedRead> OwnedReadHalf<R> {
+    /// Fill `buf[..buf.len()]` with plaintext
+    ///
+    /// Return the amount of bytes of plaintext along with the buffer; `0` means EOF.
+    pub async fn read_owned(&mut self, buf: Vec<u8>) -> (io::Result<usize>, Vec<u8>) {
+        match self.read_nonce().await {
+            Ok(true) => (),
+            Ok(false) => return (Ok(0), buf),
+            Err(e) => return (Err(e), buf),
+        }
+        let (res, mut buf) = self.r.read_owned(buf).await;
+        let n = match res {
+            Ok(n) => n,
+            Err(e) => return (Err(e), buf),
+        };
+        match &mut self.cursor {
+            Some(WriteCursorState::UserData(c)) => {
+                c.xor(&mut buf[..n]);
+                (Ok(n), buf)
+            }
+            _ => (Err(Error::Cursor(CursorError::WrongState).into()), buf),
+        }
+    }
+
+    /// Return `false` if `r` hits EOF before the nonce starts
+    ///
+    /// [`Error::NonceTruncated`] if `r` hits EOF after part of the nonce.
+    ///
+    /// The cursor stays in `self` across reads so that a cancelled read loses no state.
+    async fn read_nonce(&mut self) -> io::Result<bool> {
+        loop {
+            let (size, started) = match &self.cursor {
+                Some(WriteCursorState::Nonce(c)) => (c.remaining_nonce_size(), c.nonce_started()),
+                Some(WriteCursorState::UserData(_)) => return Ok(true),
+                None => return Err(Error::Cursor(CursorError::WrongState).into()),
+            };
+            let (res, nonce) = self.r.read_owned(vec![0; size]).await;
+            let n = res?;
+            if n == 0 {
+                return match started {
+                    true => Err(Error::NonceTruncated.into()),
+                    false => Ok(false),
+                };
+            }
+            let Some(WriteCursorState::Nonce(c)) = self.cursor.take() else {
+                unreachable!();
+            };
+            let (c, _) = c.collect_nonce(&nonce[..n]);
+            self.cursor = Some(c);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct OwnedWriteHalf<W> {
+    cursor: Option<ReadCursorState>,
+    w: W,
+}
+impl<W> OwnedWriteHalf<W> {
+    pub fn new(key: [u8; KEY_BYTES], w: W) -> Self {
+        Self::from_cursor(NonceReadCursor::new(key), w)
+    }
+    pub fn new_x(key: [u8; KEY_BYTES], w: W) -> Self {
+        Self::from_cursor(NonceReadCursor::new_x(key), w)
+    }
+    fn from_cursor(cursor: NonceReadCursor, w: W) -> Self {
+        #[cfg(feature = "nonce-tracking")]
+        crate::nonce_tracker::record(cursor.key(), cursor.nonce());
+        let cursor = Some(ReadCursorState::Nonce(cursor));
+        Self { cursor, w }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+}
+impl<W: OwnedWrite> OwnedWriteHalf<W> {
+    /// Encrypt `buf` in place and write all of it
+    ///
+    /// The returned buffer holds the ciphertext.
+    pub async fn write_owned(&mut self, buf: Vec<u8>) -> (io::Result<usize>, Vec<u8>) {
+        if let Err(e) = self.write_nonce().await {
+            return (Err(e), buf);
+        }
+        let mut buf = buf;
+        match &mut self.cursor {
+            Some(ReadCursorState::UserData(c)) => c.xor(&mut buf),
+            _ => return (Err(Error::Cursor(CursorError::WrongState).into()), buf),
+        }
+
+        // The keystream has been consumed so the whole ciphertext must go out
+        let len = buf.len();
+        let (res, buf) = write_all(&mut self.w, buf).await;
+        (res.map(|()| len), buf)
+    }
+
+    /// The cursor stays in `self` across writes so that a cancelled write loses no state
+    async fn write_nonce(&mut self) -> io::Result<()> {
+        loop {
+            let nonce = match &self.cursor {
+                Some(ReadCursorState::Nonce(c)) => c.remaining_nonce().to_vec(),
+                Some(ReadCursorState::UserData(_)) => return Ok(()),
+                None => return Err(Error::Cursor(CursorError::WrongState).into()),
+            };
+            let (res, _) = self.w.write_owned(nonce).await;
+            let n = res?;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            let Some(ReadCursorState::Nonce(c)) = self.cursor.take() else {
+                unreachable!();
+            };
+            self.cursor = Some(c.consume_nonce(n));
+        }
+    }
+}
+
+async fn write_all<W: OwnedWrite>(w: &mut W, buf: Vec<u8>) -> (io::Result<()>, Vec<u8>) {
+    let mut written = 0;
+    let mut buf = buf;
+    while written < buf.len() {
+        let rest = buf.split_off(written);
+        let (res, rest) = w.write_owned(rest).await;
+        buf.extend(rest);
+        match res {
+            Ok(0) => return (Err(io::ErrorKind::WriteZero.into()), buf),
+            Ok(n) => written += n,
+            Err(e) => return (Err(e), buf),
+        }
+    }
+    (Ok(()), buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+    use crate::config::tests::create_random_config;
+
+    use super::*;
+
+    struct Owned<T>(T);
+    impl<T: AsyncRead + Unpin> OwnedRead for Owned<T> {
+        async fn read_owned(&mut self, mut buf: Vec<u8>) -> (io::Result<usize>, Vec<u8>) {
+            let res = self.0.read(&mut buf).await;
+            (res, buf)
+        }
+    }
+    impl<T: AsyncWrite + Unpin> OwnedWrite for Owned<T> {
+        async fn write_owned(&mut self, buf: Vec<u8>) -> (io::Result<usize>, Vec<u8>) {
+            // Write at most 5 bytes at a time to exercise partial writes
+            let n = buf.len().min(5);
+            let res = self.0.write(&buf[..n]).await;
+            (res, buf)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_owned() {
+        let config = create_random_config();
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = OwnedWriteHalf::new_x(*config.key(), Owned(client));
+        let mut server = OwnedReadHalf::new_x(*config.key(), Owned(server));
+
+        let data = b"Hello, world!";
+        let mut buf = vec![0; 1024];
+        for _ in 0..64 {
+            let (res, _) = client.write_owned(data.to_vec()).await;
+            assert_eq!(res.unwrap(), data.len());
+
+            let mut read = 0;
+            while read < data.len() {
+                let (res, b) = server.read_owned(buf).await;
+                let n = res.unwrap();
+                assert_eq!(&b[..n], &data[read..read + n]);
+                read += n;
+                buf = b;
+            }
+        }
+
+        drop(client);
+        let (res, _) = server.read_owned(buf).await;
+        assert_eq!(res.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_read() {
+        let config = create_random_config();
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = OwnedWriteHalf::new(*config.key(), Owned(client));
+        let mut server = OwnedReadHalf::new(*config.key(), Owned(server));
+
+        // Drop a read that is waiting for the nonce
+        let read = server.read_owned(vec![0; 16]);
+        let res = tokio::time::timeout(std::time::Duration::from_millis(10), read).await;
+        assert!(res.is_err());
+
+        let data = b"Hello, world!";
+        let (res, _) = client.write_owned(data.to_vec()).await;
+        assert_eq!(res.unwrap(), data.len());
+        let mut plaintext = vec![];
+        while plaintext.len() < data.len() {
+            let (res, buf) = server.read_owned(vec![0; 16]).await;
+            plaintext.extend_from_slice(&buf[..res.unwrap()]);
+        }
+        assert_eq!(plaintext, data);
+    }
+
+    #[tokio::test]
+    async fn test_nonce_truncated() {
+        let config = create_random_config();
+
+        let mut r = OwnedReadHalf::new(*config.key(), Owned(&[][..]));
+        let (res, _) = r.read_owned(vec![0; 16]).await;
+        assert_eq!(res.unwrap(), 0);
+
+        let mut r = OwnedReadHalf::new(*config.key(), Owned(&[0; 5][..]));
+        let (res, _) = r.read_owned(vec![0; 16]).await;
+        let err = res.unwrap_err();
+        assert_eq!(Error::from_io(&err), Some(&Error::NonceTruncated));
+    }
+}
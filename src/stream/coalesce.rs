@@ -0,0 +1,163 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use pin_project::pin_project;
+use tokio::{io::AsyncWrite, time::Instant};
+
+/// Gather small writes into one write to `W`
+///
+/// Wrap the writer of plaintext, e.g. `Coalesced::new(WriteHalf::new(key, w), max_bytes, max_delay)`,
+/// so that chatty protocols pay for one encryption and one write of the transport per batch.
+/// The batch goes out once it holds `max_bytes` or on the first write after `max_delay`;
+/// nothing goes out while no write comes in, so flush as with any buffered writer.
+#[pin_project]
+#[derive(Debug)]
+pub struct Coalesced<W> {
+    #[pin]
+    inner: W,
+    buf: Vec<u8>,
+    /// Part of `buf` accepted by `inner`
+    pos: usize,
+    max_bytes: usize,
+    max_delay: Duration,
+    /// When the oldest byte of `buf` is due
+    deadline: Option<Instant>,
+}
+impl<W> Coalesced<W> {
+    pub fn new(inner: W, max_bytes: usize, max_delay: Duration) -> Self {
+        assert!(max_bytes > 0);
+        Self {
+            inner,
+            buf: Vec::with_capacity(max_bytes),
+            pos: 0,
+            max_bytes,
+            max_delay,
+            deadline: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+    /// The batch not written yet is dropped; flush first
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+impl<W: AsyncWrite> Coalesced<W> {
+    /// Pass the whole batch to `inner`
+    fn poll_emit(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+        while *this.pos < this.buf.len() {
+            let n = ready!(this.inner.as_mut().poll_write(cx, &this.buf[*this.pos..]))?;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into()).into();
+            }
+            *this.pos += n;
+        }
+        this.buf.clear();
+        *this.pos = 0;
+        *this.deadline = None;
+        Ok(()).into()
+    }
+
+    fn is_due(&self) -> bool {
+        self.buf.len() >= self.max_bytes
+            || self
+                .deadline
+                .is_some_and(|deadline| deadline <= Instant::now())
+    }
+}
+impl<W: AsyncWrite> AsyncWrite for Coalesced<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if !self.buf.is_empty() && (self.is_due() || self.max_bytes < self.buf.len() + buf.len()) {
+            ready!(self.as_mut().poll_emit(cx))?;
+        }
+        // Nothing to gather a large write with
+        if self.max_bytes <= buf.len() {
+            return self.project().inner.poll_write(cx, buf);
+        }
+
+        let this = self.as_mut().project();
+        if this.buf.is_empty() {
+            *this.deadline = Some(Instant::now() + *this.max_delay);
+        }
+        this.buf.extend_from_slice(buf);
+        if self.is_due() {
+            // Errors surface on the next call
+            let _ = self.poll_emit(cx);
+        }
+        Ok(buf.len()).into()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_emit(cx))?;
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_emit(cx))?;
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{
+        config::tests::create_random_config,
+        stream::{ReadHalf, WriteHalf},
+    };
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_coalesce() {
+        let mut w = Coalesced::new(vec![], 16, Duration::from_millis(10));
+        for _ in 0..5 {
+            w.write_all(b"abc").await.unwrap();
+        }
+        assert!(w.get_ref().is_empty());
+        // Would overflow the batch
+        w.write_all(b"abc").await.unwrap();
+        assert_eq!(w.get_ref().len(), 15);
+
+        tokio::time::advance(Duration::from_millis(10)).await;
+        w.write_all(b"abc").await.unwrap();
+        assert_eq!(w.get_ref().len(), 18);
+        w.write_all(&[0; 16]).await.unwrap();
+        assert_eq!(w.get_ref().len(), 37);
+        w.flush().await.unwrap();
+        assert_eq!(w.get_ref().len(), 37);
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_encrypted() {
+        let config = create_random_config();
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = Coalesced::new(
+            WriteHalf::new(*config.key(), client),
+            64,
+            Duration::from_millis(1),
+        );
+        let mut server = ReadHalf::new(*config.key(), server);
+
+        for _ in 0..8 {
+            client.write_all(b"Hello, world!").await.unwrap();
+        }
+        client.flush().await.unwrap();
+        let mut buf = [0; 8 * 13];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, &b"Hello, world!".repeat(8)[..]);
+    }
+}
@@ -0,0 +1,465 @@
+use std::{
+    fmt, io,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, RwLock},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    config::{derive_subkey, Config, KeySource, Role},
+    key::{KeyId, KeyProvider, SecretKey},
+    mac::constant_time_eq,
+    KEY_BYTES,
+};
+
+use super::{ChaCha20Stream, Error};
+
+/// Client side of the nonce exchange
+#[derive(Debug, Clone)]
+pub struct ChaCha20Connector {
+    config: Config,
+    handshake: Handshake,
+    send_key_id: bool,
+}
+impl ChaCha20Connector {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            handshake: Handshake::default(),
+            send_key_id: false,
+        }
+    }
+
+    /// Connect with the key `id` from `source`, sending its ID for [`ChaCha20Acceptor::with_source`]
+    pub async fn from_source(source: &dyn KeySource, id: KeyId) -> io::Result<Self> {
+        let key = source.fetch(id).await?;
+        let config = Config::from_raw_key(*key.expose_secret()).with_key_id(id);
+        Ok(Self::new(config).send_key_id(true))
+    }
+
+    /// Use 24-byte XChaCha20 nonces instead of 12-byte ChaCha20 nonces
+    pub fn x_nonce(mut self, x: bool) -> Self {
+        self.handshake.x = x;
+        self
+    }
+
+    /// Use a separate key per direction; see [`ChaCha20Stream::with_role`]
+    ///
+    /// The acceptor has to agree.
+    pub fn directional(mut self, directional: bool) -> Self {
+        self.handshake.directional = directional;
+        self
+    }
+
+    /// Encrypt under subkeys so that the key only ever derives them; see [`ChaCha20Stream::with_subkey`]
+    ///
+    /// The acceptor has to agree.
+    pub fn subkey(mut self, subkey: bool) -> Self {
+        self.handshake.subkey = subkey;
+        self
+    }
+
+    /// Prove the key to the acceptor and check its proof after the nonce exchange
+    ///
+    /// A peer on another key fails the connection with [`Error::KeyMismatch`].
+    /// The acceptor has to agree.
+    pub fn confirm_key(mut self, confirm: bool) -> Self {
+        self.handshake.confirm_key = confirm;
+        self
+    }
+
+    /// Send the key ID of the config ahead of the nonce for [`ChaCha20Acceptor::read_key_id`]
+    pub fn send_key_id(mut self, send: bool) -> Self {
+        self.send_key_id = send;
+        self
+    }
+
+    /// Send the local nonce and wait for the nonce of the acceptor
+    pub async fn connect<S>(&self, mut stream: S) -> io::Result<ChaCha20Stream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        if self.send_key_id {
+            stream.write_all(&[self.config.key_id()]).await?;
+        }
+        self.handshake
+            .run(self.config.key(), Role::Client, stream)
+            .await
+    }
+}
+
+/// Server side of the nonce exchange
+///
+/// Clones share the keys; see [`Self::reload`].
+#[derive(Clone)]
+pub struct ChaCha20Acceptor {
+    keys: Arc<RwLock<Keys>>,
+    handshake: Handshake,
+    read_key_id: bool,
+}
+impl fmt::Debug for ChaCha20Acceptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChaCha20Acceptor")
+            .field("handshake", &self.handshake)
+            .field("read_key_id", &self.read_key_id)
+            .finish_non_exhaustive()
+    }
+}
+/// Where the acceptor looks up keys
+#[derive(Clone)]
+enum Keys {
+    Provider(Arc<dyn KeyProvider>),
+    Source(Arc<dyn KeySource>),
+}
+impl ChaCha20Acceptor {
+    pub fn new(config: Config) -> Self {
+        Self::with_provider(Arc::new(config))
+    }
+    /// Look up the key of each connection in `keys`
+    pub fn with_provider(keys: Arc<dyn KeyProvider>) -> Self {
+        Self::with_keys(Keys::Provider(keys))
+    }
+    /// Fetch the key named by each connection from `source`
+    ///
+    /// Implies [`Self::read_key_id`].
+    pub fn with_source(source: Arc<dyn KeySource>) -> Self {
+        Self::with_keys(Keys::Source(source)).read_key_id(true)
+    }
+    fn with_keys(keys: Keys) -> Self {
+        Self {
+            keys: Arc::new(RwLock::new(keys)),
+            handshake: Handshake::default(),
+            read_key_id: false,
+        }
+    }
+
+    /// Swap in `keys` for the connections accepted from now on by this acceptor and its clones
+    ///
+    /// Connections already accepted keep their keys, so rotated keys roll out without rebuilding listeners.
+    pub fn reload(&self, keys: Arc<dyn KeyProvider>) {
+        *self.keys.write().unwrap() = Keys::Provider(keys);
+    }
+    /// [`Self::reload`] with a [`KeySource`]
+    pub fn reload_source(&self, source: Arc<dyn KeySource>) {
+        *self.keys.write().unwrap() = Keys::Source(source);
+    }
+
+    /// Use 24-byte XChaCha20 nonces instead of 12-byte ChaCha20 nonces
+    pub fn x_nonce(mut self, x: bool) -> Self {
+        self.handshake.x = x;
+        self
+    }
+
+    /// Use a separate key per direction; see [`ChaCha20Stream::with_role`]
+    ///
+    /// The connector has to agree.
+    pub fn directional(mut self, directional: bool) -> Self {
+        self.handshake.directional = directional;
+        self
+    }
+
+    /// See [`ChaCha20Connector::subkey`]
+    pub fn subkey(mut self, subkey: bool) -> Self {
+        self.handshake.subkey = subkey;
+        self
+    }
+
+    /// See [`ChaCha20Connector::confirm_key`]
+    pub fn confirm_key(mut self, confirm: bool) -> Self {
+        self.handshake.confirm_key = confirm;
+        self
+    }
+
+    /// Read the key ID sent by [`ChaCha20Connector::send_key_id`] and pass it to the provider
+    pub fn read_key_id(mut self, read: bool) -> Self {
+        self.read_key_id = read;
+        self
+    }
+
+    /// Send the local nonce and wait for the nonce of the connector
+    ///
+    /// The provider sees the unspecified address `0.0.0.0:0` as the peer; see [`Self::accept_from`].
+    pub async fn accept<S>(&self, stream: S) -> io::Result<ChaCha20Stream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let peer = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+        self.accept_from(peer, stream).await
+    }
+    /// [`Self::accept`] with the key the provider resolves for `peer`
+    pub async fn accept_from<S>(
+        &self,
+        peer: SocketAddr,
+        mut stream: S,
+    ) -> io::Result<ChaCha20Stream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let key_id = match self.read_key_id {
+            true => Some(stream.read_u8().await?),
+            false => None,
+        };
+        let unknown = || io::Error::from(Error::UnknownKey(key_id));
+        let keys = self.keys.read().unwrap().clone();
+        let key: Arc<SecretKey> = match keys {
+            Keys::Provider(keys) => keys.key_for(peer, key_id).ok_or_else(unknown)?,
+            Keys::Source(source) => {
+                let Some(id) = key_id else {
+                    return Err(Error::KeyIdRequired.into());
+                };
+                match source.fetch(id).await {
+                    Ok(key) => Arc::new(key),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(unknown()),
+                    Err(e) => return Err(e),
+                }
+            }
+        };
+        self.handshake
+            .run(key.expose_secret(), Role::Server, stream)
+            .await
+    }
+}
+
+const KEY_CONFIRMATION_INFO: &[u8] = b"key confirmation";
+
+/// Options agreed on by both ends
+#[derive(Debug, Clone, Copy, Default)]
+struct Handshake {
+    x: bool,
+    directional: bool,
+    subkey: bool,
+    confirm_key: bool,
+}
+impl Handshake {
+    async fn run<S>(
+        self,
+        key: &[u8; KEY_BYTES],
+        role: Role,
+        stream: S,
+    ) -> io::Result<ChaCha20Stream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let key = *key;
+        let mut stream = match (self.x, self.directional) {
+            (true, true) => ChaCha20Stream::with_role_x(key, role, stream),
+            (false, true) => ChaCha20Stream::with_role(key, role, stream),
+            (true, false) => ChaCha20Stream::new_x(key, stream),
+            (false, false) => ChaCha20Stream::new(key, stream),
+        };
+        if self.subkey {
+            stream = stream.with_subkey();
+        }
+        stream.handshake().await?;
+        if self.confirm_key {
+            confirm_key(&key, role, &mut stream).await?;
+        }
+        Ok(stream)
+    }
+}
+
+/// Exchange MACs over the role of the sender and both nonces
+async fn confirm_key<S>(
+    key: &[u8; KEY_BYTES],
+    role: Role,
+    stream: &mut ChaCha20Stream<S>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mac_key = derive_subkey(key, &[], KEY_CONFIRMATION_INFO);
+    let tx_nonce = stream.tx_nonce().to_vec();
+    let rx_nonce = stream.rx_nonce().expect("nonces exchanged").to_vec();
+    let mac = |sender: Role, sender_nonce: &[u8], receiver_nonce: &[u8]| {
+        let mut hasher = blake3::Hasher::new_keyed(&mac_key);
+        hasher.update(&[sender as u8]);
+        hasher.update(sender_nonce);
+        hasher.update(receiver_nonce);
+        *hasher.finalize().as_bytes()
+    };
+    let peer = match role {
+        Role::Client => Role::Server,
+        Role::Server => Role::Client,
+    };
+
+    stream.write_all(&mac(role, &tx_nonce, &rx_nonce)).await?;
+    stream.flush().await?;
+    let mut peer_mac = [0; blake3::OUT_LEN];
+    stream.read_exact(&mut peer_mac).await?;
+    match constant_time_eq(&peer_mac, &mac(peer, &rx_nonce, &tx_nonce)) {
+        true => Ok(()),
+        false => Err(Error::KeyMismatch.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use crate::config::tests::create_random_config;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_connect_accept() {
+        let config = create_random_config();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = ChaCha20Acceptor::new(config.clone())
+            .x_nonce(true)
+            .directional(true)
+            .subkey(true)
+            .confirm_key(true);
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(stream).await.unwrap();
+            let mut buf = [0; 13];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+            stream.flush().await.unwrap();
+        });
+
+        let connector = ChaCha20Connector::new(config)
+            .x_nonce(true)
+            .directional(true)
+            .subkey(true)
+            .confirm_key(true);
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut stream = connector.connect(stream).await.unwrap();
+        stream.write_all(b"Hello, world!").await.unwrap();
+        stream.flush().await.unwrap();
+        let mut buf = [0; 13];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"Hello, world!");
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_key_provider() {
+        let tenant = create_random_config().with_key_id(7);
+        let keys = {
+            let key = Arc::new(SecretKey::new(*tenant.key()));
+            move |_peer: SocketAddr, key_id: Option<u8>| (key_id == Some(7)).then(|| key.clone())
+        };
+        let acceptor = ChaCha20Acceptor::with_provider(Arc::new(keys)).read_key_id(true);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept_from(peer, stream).await.unwrap();
+            let mut buf = [0; 13];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+            stream.flush().await.unwrap();
+
+            let (stream, peer) = listener.accept().await.unwrap();
+            let err = acceptor.accept_from(peer, stream).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        });
+
+        let connector = ChaCha20Connector::new(tenant).send_key_id(true);
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut stream = connector.connect(stream).await.unwrap();
+        stream.write_all(b"Hello, world!").await.unwrap();
+        stream.flush().await.unwrap();
+        let mut buf = [0; 13];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"Hello, world!");
+
+        // An unknown tenant is turned away
+        let stranger =
+            ChaCha20Connector::new(create_random_config().with_key_id(8)).send_key_id(true);
+        let stream = TcpStream::connect(addr).await.unwrap();
+        assert!(stranger.connect(stream).await.is_err());
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let old = create_random_config();
+        let new = create_random_config();
+        let acceptor = ChaCha20Acceptor::new(old.clone()).confirm_key(true);
+        let connect = |config: &Config| {
+            let connector = ChaCha20Connector::new(config.clone()).confirm_key(true);
+            let acceptor = acceptor.clone();
+            async move {
+                let (client, server) = tokio::io::duplex(1024);
+                let (client, server) =
+                    tokio::join!(connector.connect(client), acceptor.accept(server));
+                client.and(server).is_ok()
+            }
+        };
+
+        assert!(connect(&old).await);
+        assert!(!connect(&new).await);
+        // Clones share the keys
+        acceptor.clone().reload(Arc::new(new.clone()));
+        assert!(connect(&new).await);
+        assert!(!connect(&old).await);
+    }
+
+    #[tokio::test]
+    async fn test_key_source() {
+        use crate::config::{FetchKey, KeySource};
+
+        struct Kms(Config);
+        impl KeySource for Kms {
+            fn fetch(&self, id: KeyId) -> FetchKey<'_> {
+                let key = self.0.key_by_id(id).map(|key| SecretKey::new(*key));
+                Box::pin(async move { key.ok_or_else(|| io::ErrorKind::NotFound.into()) })
+            }
+        }
+        let kms = Kms(create_random_config().with_key_id(1));
+        let acceptor =
+            ChaCha20Acceptor::with_source(Arc::new(Kms(kms.0.clone()))).confirm_key(true);
+
+        let connector = ChaCha20Connector::from_source(&kms, 1)
+            .await
+            .unwrap()
+            .confirm_key(true);
+        let (client, server) = tokio::io::duplex(1024);
+        let (client, server) = tokio::join!(connector.connect(client), acceptor.accept(server));
+        client.unwrap();
+        server.unwrap();
+
+        assert!(ChaCha20Connector::from_source(&kms, 2).await.is_err());
+        let stranger =
+            ChaCha20Connector::new(create_random_config().with_key_id(2)).send_key_id(true);
+        let (client, server) = tokio::io::duplex(1024);
+        let (_, server) = tokio::join!(stranger.connect(client), acceptor.accept(server));
+        let err = server.unwrap_err();
+        assert_eq!(Error::from_io(&err), Some(&Error::UnknownKey(Some(2))));
+    }
+
+    #[tokio::test]
+    async fn test_key_mismatch() {
+        let (client, server) = tokio::io::duplex(1024);
+        let connector = ChaCha20Connector::new(create_random_config()).confirm_key(true);
+        let acceptor = ChaCha20Acceptor::new(create_random_config()).confirm_key(true);
+        let (client, server) = tokio::join!(connector.connect(client), acceptor.accept(server));
+        for err in [client.unwrap_err(), server.unwrap_err()] {
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+            assert_eq!(Error::from_io(&err), Some(&Error::KeyMismatch));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_accept_eof() {
+        let config = create_random_config();
+
+        let (client, server) = tokio::io::duplex(1024);
+        drop(client);
+        let acceptor = ChaCha20Acceptor::new(config);
+        let err = acceptor.accept(server).await.unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            io::ErrorKind::UnexpectedEof | io::ErrorKind::BrokenPipe
+        ));
+    }
+}
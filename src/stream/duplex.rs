@@ -0,0 +1,235 @@
+use std::{
+    io::{self, IoSlice},
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use bytes::BufMut;
+use pin_project::pin_project;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::{
+    config::{directional_keys, Role},
+    KEY_BYTES,
+};
+
+use super::{Error, ReadState, WriteState};
+
+/// Encrypted stream owning a duplex transport like `TcpStream`
+///
+/// Each direction has its own nonce and keystream.
+/// The local nonce is sent before the first data written and the peer's nonce is collected on the first read;
+/// call [`Self::handshake`] to exchange them upfront.
+#[pin_project]
+#[derive(Debug)]
+pub struct ChaCha20Stream<S> {
+    rx: ReadState,
+    tx: WriteState,
+    #[pin]
+    s: S,
+}
+impl<S> ChaCha20Stream<S> {
+    pub fn new(key: [u8; KEY_BYTES], s: S) -> Self {
+        Self::from_states(ReadState::new(key), WriteState::new(key), s)
+    }
+    pub fn new_x(key: [u8; KEY_BYTES], s: S) -> Self {
+        Self::from_states(ReadState::new_x(key), WriteState::new_x(key), s)
+    }
+    /// Each direction under its own key derived from `key` by [`directional_keys`]
+    pub fn with_role(key: [u8; KEY_BYTES], role: Role, s: S) -> Self {
+        let (rx, tx) = directional_keys(&key, role);
+        Self::from_states(ReadState::new(rx), WriteState::new(tx), s)
+    }
+    pub fn with_role_x(key: [u8; KEY_BYTES], role: Role, s: S) -> Self {
+        let (rx, tx) = directional_keys(&key, role);
+        Self::from_states(ReadState::new_x(rx), WriteState::new_x(tx), s)
+    }
+    pub fn from_states(rx: ReadState, tx: WriteState, s: S) -> Self {
+        Self { rx, tx, s }
+    }
+
+    /// Encrypt each direction under a subkey of its key and nonce; see [`WriteState::with_subkey`]
+    ///
+    /// Must be called before any IO. The peer has to call it as well.
+    pub fn with_subkey(self) -> Self {
+        Self {
+            rx: self.rx.with_subkey(),
+            tx: self.tx.with_subkey(),
+            s: self.s,
+        }
+    }
+
+    /// The nonce of the peer once it has been read
+    pub fn rx_nonce(&self) -> Option<&[u8]> {
+        self.rx.nonce()
+    }
+    /// The local nonce
+    pub fn tx_nonce(&self) -> &[u8] {
+        self.tx.nonce()
+    }
+
+    /// Decryption progress of the incoming direction; see [`ReadState::fork`]
+    pub fn rx_state(&self) -> &ReadState {
+        &self.rx
+    }
+    /// Encryption progress of the outgoing direction; see [`WriteState::fork`]
+    pub fn tx_state(&self) -> &WriteState {
+        &self.tx
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.s
+    }
+    /// Ciphertext not yet accepted by the transport is dropped; flush first
+    pub fn into_inner(self) -> S {
+        self.s
+    }
+}
+impl<S: AsyncRead> ChaCha20Stream<S> {
+    /// Decrypt into the spare capacity of `buf`, e.g. a `BytesMut`, without zeroing it first
+    ///
+    /// Return the amount of bytes appended to `buf`; `0` means EOF.
+    pub fn poll_read_buf(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut impl BufMut,
+    ) -> Poll<io::Result<usize>> {
+        tokio_util::io::poll_read_buf(self, cx, buf)
+    }
+}
+impl<S> ChaCha20Stream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Send the local nonce before reading the peer's so both sides can call this at once
+    pub async fn handshake(&mut self) -> io::Result<()> {
+        self.flush().await?;
+        let complete = std::future::poll_fn(|cx| {
+            let this = Pin::new(&mut *self).project();
+            let mut s = this.s;
+            this.rx.poll_nonce(|b| {
+                let mut b = ReadBuf::new(b);
+                ready!(s.as_mut().poll_read(cx, &mut b))?;
+                Ok(b.filled().len()).into()
+            })
+        })
+        .await?;
+        match complete {
+            true => Ok(()),
+            false => Err(Error::NonceTruncated.into()),
+        }
+    }
+}
+impl<S: AsyncRead> AsyncRead for ChaCha20Stream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let mut s = this.s;
+        this.rx.poll_into(buf, |b| s.as_mut().poll_read(cx, b))
+    }
+}
+impl<S: AsyncWrite> AsyncWrite for ChaCha20Stream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_write_vectored(cx, &[IoSlice::new(buf)])
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let mut s = this.s;
+        this.tx
+            .poll_vectored(bufs, |b| s.as_mut().poll_write(cx, b))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let mut s = this.s;
+        ready!(this.tx.poll_drain(|b| s.as_mut().poll_write(cx, b)))?;
+        s.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let mut s = this.s;
+        ready!(this.tx.poll_shutdown(|b| s.as_mut().poll_write(cx, b)))?;
+        s.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use crate::config::tests::create_random_config;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_duplex() {
+        let config = create_random_config();
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = ChaCha20Stream::new(*config.key(), client);
+        let mut server = ChaCha20Stream::new(*config.key(), server);
+        assert!(client.rx_nonce().is_none());
+
+        let data = b"Hello, world!";
+        let mut buf = [0u8; 13];
+        for _ in 0..64 {
+            client.write_all(data).await.unwrap();
+            client.flush().await.unwrap();
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, data);
+
+            server.write_all(&buf).await.unwrap();
+            server.flush().await.unwrap();
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, data);
+        }
+        assert_eq!(client.rx_nonce(), Some(server.tx_nonce()));
+        assert_eq!(server.rx_nonce(), Some(client.tx_nonce()));
+    }
+
+    #[tokio::test]
+    async fn test_role() {
+        let config = create_random_config();
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = ChaCha20Stream::with_role(*config.key(), Role::Client, client);
+        let mut server = ChaCha20Stream::with_role(*config.key(), Role::Server, server);
+
+        let data = b"Hello, world!";
+        let mut buf = [0u8; 13];
+        client.write_all(data).await.unwrap();
+        client.flush().await.unwrap();
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, data);
+        server.write_all(&buf).await.unwrap();
+        server.flush().await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, data);
+
+        // Both ends on the same role cannot understand each other
+        let (a, b) = tokio::io::duplex(1024);
+        let mut a = ChaCha20Stream::with_role(*config.key(), Role::Client, a);
+        let mut b = ChaCha20Stream::with_role(*config.key(), Role::Client, b);
+        a.write_all(data).await.unwrap();
+        a.flush().await.unwrap();
+        b.read_exact(&mut buf).await.unwrap();
+        assert_ne!(&buf, data);
+    }
+}
@@ -0,0 +1,136 @@
+use std::io;
+
+use thiserror::Error;
+
+use crate::{cursor::CursorError, key::KeyId, mac::TagMismatch};
+
+/// Failures of the stream layer
+///
+/// Surfaces as the inner error of an [`io::Error`] of the matching [`io::ErrorKind`]; get it back by [`Error::from_io`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum Error {
+    /// The stream ended before the whole nonce arrived
+    #[error("stream ended within the nonce")]
+    NonceTruncated,
+    /// The stream ended within the length or the payload of a frame
+    #[error("stream ended within a frame payload")]
+    PayloadTruncated,
+    /// The stream ended within the tag of a frame
+    #[error("stream ended within a frame tag")]
+    TagTruncated,
+    /// The operation needs the whole nonce
+    #[error("nonce has not been read")]
+    NonceNotRead,
+    /// The data was forged, corrupted or sealed under another key
+    #[error("authentication tag mismatch")]
+    TagMismatch,
+    /// A frame to send exceeds the maximum frame size
+    #[error("frame too large: {0} bytes")]
+    FrameTooLarge(usize),
+    /// A received frame announces more than the maximum frame size
+    #[error("received frame too large: {0} bytes")]
+    ReceivedFrameTooLarge(usize),
+    /// The operation needed the keystream up to the position `.0`, past the last keystream byte of the nonce
+    #[error("keystream exhausted: position {0} is past the end")]
+    KeystreamExhausted(u64),
+    /// The seek position lies outside the plaintext
+    #[error("seek position out of range")]
+    SeekOutOfRange,
+    /// Decryption on another thread has not come back yet
+    #[error("decryption is still in progress")]
+    Busy,
+    /// The handshake found the peer on another key
+    #[error("handshake failed: the peer has a different key")]
+    KeyMismatch,
+    /// The handshake found no key for the peer, or none for the key ID it sent
+    #[error("handshake failed: no key for the peer{}", .0.map(|id| format!(" with key ID {id}")).unwrap_or_default())]
+    UnknownKey(Option<KeyId>),
+    /// The handshake needs a key ID the acceptor was not set up to read
+    #[error("handshake failed: key sources need `read_key_id`")]
+    KeyIdRequired,
+    /// The writer has been shut down
+    #[error("write after shutdown")]
+    Closed,
+    /// The encryption worker thread is gone
+    #[error("encryption worker stopped")]
+    WorkerStopped,
+    /// Neither side made progress within the idle timeout
+    #[error("no progress within the idle timeout")]
+    Timeout,
+    /// No message has been started to read or verify
+    #[error("no message started")]
+    NoMessage,
+    /// The current message has to be verified before the next one starts
+    #[error("current message not verified")]
+    MessageUnverified,
+    /// A cursor refused the operation
+    #[error("{0}")]
+    Cursor(CursorError),
+}
+impl Error {
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Error::NonceTruncated | Error::PayloadTruncated | Error::TagTruncated => {
+                io::ErrorKind::UnexpectedEof
+            }
+            Error::NonceNotRead
+            | Error::FrameTooLarge(_)
+            | Error::KeystreamExhausted(_)
+            | Error::SeekOutOfRange
+            | Error::KeyIdRequired
+            | Error::NoMessage
+            | Error::MessageUnverified => io::ErrorKind::InvalidInput,
+            Error::TagMismatch | Error::ReceivedFrameTooLarge(_) => io::ErrorKind::InvalidData,
+            Error::KeyMismatch | Error::UnknownKey(_) => io::ErrorKind::PermissionDenied,
+            Error::Closed => io::ErrorKind::BrokenPipe,
+            Error::Busy | Error::WorkerStopped => io::ErrorKind::Other,
+            Error::Timeout => io::ErrorKind::TimedOut,
+            Error::Cursor(e) => match e {
+                CursorError::WrongState => io::ErrorKind::Other,
+                CursorError::NonceIncomplete | CursorError::KeyVersionWithoutAad => {
+                    io::ErrorKind::InvalidInput
+                }
+                CursorError::UnknownKeyId(_) => io::ErrorKind::PermissionDenied,
+            },
+        }
+    }
+
+    /// The stream error carried by `e` if any
+    pub fn from_io(e: &io::Error) -> Option<&Error> {
+        e.get_ref()?.downcast_ref()
+    }
+}
+impl From<TagMismatch> for Error {
+    fn from(_: TagMismatch) -> Self {
+        Error::TagMismatch
+    }
+}
+impl From<CursorError> for Error {
+    fn from(e: CursorError) -> Self {
+        Error::Cursor(e)
+    }
+}
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        io::Error::new(e.kind(), e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_io_error() {
+        let e = io::Error::from(Error::UnknownKey(Some(3)));
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(Error::from_io(&e), Some(&Error::UnknownKey(Some(3))));
+        assert_eq!(
+            e.to_string(),
+            "handshake failed: no key for the peer with key ID 3"
+        );
+
+        let e = io::Error::from(io::ErrorKind::UnexpectedEof);
+        assert_eq!(Error::from_io(&e), None);
+    }
+}
@@ -0,0 +1,123 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use super::{ReadHalf, WholeStream, WriteHalf};
+
+impl<R: futures_io::AsyncRead> futures_io::AsyncRead for ReadHalf<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.as_mut().poll_offloaded(cx))?;
+        let n = self.as_mut().read_buffered(buf);
+        if n != 0 {
+            return Ok(n).into();
+        }
+        let (state, mut r) = self.parts_mut();
+        state.poll(buf, |b| r.as_mut().poll_read(cx, b))
+    }
+}
+
+impl<W: futures_io::AsyncWrite> futures_io::AsyncWrite for WriteHalf<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_write_vectored(cx, &[io::IoSlice::new(buf)])
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.as_mut().poll_offloaded(cx))?;
+        let (state, mut w) = self.parts_mut();
+        state.poll_vectored(bufs, |b| w.as_mut().poll_write(cx, b))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_offloaded(cx))?;
+        let (state, mut w) = self.parts_mut();
+        ready!(state.poll_drain(|b| w.as_mut().poll_write(cx, b)))?;
+        w.poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_offloaded(cx))?;
+        let (state, mut w) = self.parts_mut();
+        ready!(state.poll_shutdown(|b| w.as_mut().poll_write(cx, b)))?;
+        w.poll_close(cx)
+    }
+}
+
+impl<R: futures_io::AsyncRead, W> futures_io::AsyncRead for WholeStream<R, W> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.read_half_mut().poll_read(cx, buf)
+    }
+}
+
+impl<R, W: futures_io::AsyncWrite> futures_io::AsyncWrite for WholeStream<R, W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.write_half_mut().poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        self.write_half_mut().poll_write_vectored(cx, bufs)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.write_half_mut().poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.write_half_mut().poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::config::tests::create_random_config;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_futures_io() {
+        let config = create_random_config();
+
+        let (client, server) = tokio::io::duplex(1024);
+        let client = tokio_util::compat::TokioAsyncWriteCompatExt::compat_write(client);
+        let server = tokio_util::compat::TokioAsyncReadCompatExt::compat(server);
+        let mut client = WriteHalf::new(*config.key(), client);
+        let mut server = ReadHalf::new(*config.key(), server);
+
+        let data = b"Hello, world!";
+        let mut buf = [0u8; 1024];
+
+        for _ in 0..1024 {
+            client.write_all(data).await.unwrap();
+            client.flush().await.unwrap();
+            server.read_exact(&mut buf[..data.len()]).await.unwrap();
+            assert_eq!(&buf[..data.len()], data);
+        }
+    }
+}
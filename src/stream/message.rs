@@ -0,0 +1,157 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use bytes::{Bytes, BytesMut};
+use futures_core::Stream;
+use futures_sink::Sink;
+use pin_project::pin_project;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Decoder, FramedRead, FramedWrite, LengthDelimitedCodec};
+
+use super::{Error, ReadHalf, WriteHalf};
+
+/// Encrypt length-delimited messages into a [`WriteHalf`]
+#[pin_project]
+#[derive(Debug)]
+pub struct MessageSink<W> {
+    #[pin]
+    w: FramedWrite<WriteHalf<W>, LengthDelimitedCodec>,
+}
+impl<W: AsyncWrite> MessageSink<W> {
+    pub fn new(w: WriteHalf<W>) -> Self {
+        let w = FramedWrite::new(w, LengthDelimitedCodec::new());
+        Self { w }
+    }
+}
+impl<W> MessageSink<W> {
+    pub fn into_inner(self) -> WriteHalf<W> {
+        self.w.into_inner()
+    }
+}
+impl<W: AsyncWrite> Sink<Bytes> for MessageSink<W> {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<Bytes>::poll_ready(self.project().w, cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Bytes) -> Result<(), Self::Error> {
+        self.project().w.start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<Bytes>::poll_flush(self.project().w, cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<Bytes>::poll_close(self.project().w, cx)
+    }
+}
+
+/// Decrypt length-delimited messages from a [`ReadHalf`]
+#[pin_project]
+#[derive(Debug)]
+pub struct MessageStream<R> {
+    #[pin]
+    r: FramedRead<ReadHalf<R>, MessageCodec>,
+}
+impl<R: AsyncRead> MessageStream<R> {
+    pub fn new(r: ReadHalf<R>) -> Self {
+        let codec = MessageCodec {
+            codec: LengthDelimitedCodec::new(),
+            in_frame: false,
+        };
+        let r = FramedRead::new(r, codec);
+        Self { r }
+    }
+}
+impl<R> MessageStream<R> {
+    pub fn into_inner(self) -> ReadHalf<R> {
+        self.r.into_inner()
+    }
+}
+impl<R: AsyncRead> Stream for MessageStream<R> {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let msg = ready!(self.project().r.poll_next(cx));
+        Poll::Ready(msg.map(|res| res.map(|msg| msg.freeze())))
+    }
+}
+
+/// [`LengthDelimitedCodec`] telling a truncated message apart from a clean EOF
+#[derive(Debug)]
+struct MessageCodec {
+    codec: LengthDelimitedCodec,
+    /// Part of a message has been taken from the buffer
+    in_frame: bool,
+}
+impl Decoder for MessageCodec {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let len = src.len();
+        let frame = self.codec.decode(src)?;
+        // The inner codec takes the length off the buffer before the payload arrives
+        self.in_frame = match frame {
+            Some(_) => false,
+            None => self.in_frame || len != 0,
+        };
+        Ok(frame)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(src)? {
+            Some(frame) => Ok(Some(frame)),
+            None if self.in_frame => Err(Error::PayloadTruncated.into()),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::AsyncWriteExt;
+
+    use crate::config::tests::create_random_config;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_messages() {
+        let config = create_random_config();
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut sink = MessageSink::new(WriteHalf::new(*config.key(), client));
+        let mut stream = MessageStream::new(ReadHalf::new(*config.key(), server));
+
+        for i in 0..64 {
+            let msg = Bytes::from(vec![i as u8; i * 7]);
+            sink.send(msg.clone()).await.unwrap();
+            assert_eq!(stream.next().await.unwrap().unwrap(), msg);
+        }
+        sink.close().await.unwrap();
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_truncated() {
+        let config = create_random_config();
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut w = WriteHalf::new(*config.key(), client);
+        let mut stream = MessageStream::new(ReadHalf::new(*config.key(), server));
+
+        // A length of 16 followed by only 3 bytes of payload
+        w.write_all(&[0, 0, 0, 16, 1, 2, 3]).await.unwrap();
+        w.shutdown().await.unwrap();
+        let e = stream.next().await.unwrap().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(Error::from_io(&e), Some(&Error::PayloadTruncated));
+    }
+}
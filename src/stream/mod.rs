@@ -1,13 +1,48 @@
+mod coalesce;
+pub use coalesce::Coalesced;
+mod connector;
+pub use connector::{ChaCha20Acceptor, ChaCha20Connector};
+mod duplex;
+pub use duplex::ChaCha20Stream;
+mod error;
+pub use error::Error;
+#[cfg(feature = "futures-io")]
+mod futures_io;
+mod message;
+pub use message::{MessageSink, MessageStream};
+mod offload;
+mod pool;
+pub use pool::BufferPool;
+mod rate_limit;
+pub use rate_limit::{RateLimit, RateLimited};
 mod read;
-pub use read::ReadHalf;
+pub use read::{ReadHalf, ReadState};
+mod relay;
+pub use relay::{relay, subkey_relay};
+mod tagged;
+pub use tagged::{NonceCiphertextTagWriter, TagReader};
+mod tap;
+pub use tap::{Direction, Tap};
+mod timeout;
+pub use timeout::{TimeoutReader, TimeoutWriter};
 mod whole;
-pub use whole::WholeStream;
+pub use whole::{pair, subkey_pair, WholeStream};
+mod worker;
+pub use worker::WorkerMessageSink;
 mod write;
-pub use write::WriteHalf;
+pub use write::{WriteHalf, WriteState};
 
 #[cfg(test)]
 mod tests {
-    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use std::{
+        io,
+        marker::PhantomPinned,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use pin_project::pin_project;
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
 
     use crate::config::tests::create_random_config;
 
@@ -26,6 +61,7 @@ mod tests {
 
         for _ in 0..1024 {
             client.write_all(data).await.unwrap();
+            client.flush().await.unwrap();
             server.read_exact(&mut buf[..data.len()]).await.unwrap();
             assert_eq!(&buf[..data.len()], data);
         }
@@ -46,8 +82,120 @@ mod tests {
 
         for _ in 0..1024 {
             client.write_all(data).await.unwrap();
+            client.flush().await.unwrap();
             server.read_exact(&mut buf[..data.len()]).await.unwrap();
             assert_eq!(&buf[..data.len()], data);
         }
     }
+
+    #[tokio::test]
+    async fn test_whole_from_stream() {
+        let config = create_random_config();
+        let key = *config.key();
+
+        let (client, server) = tokio::io::duplex(1024);
+        let server = tokio::spawn(async move {
+            let mut server = WholeStream::from_stream(key, server).await.unwrap();
+            let mut buf = [0u8; 13];
+            server.read_exact(&mut buf).await.unwrap();
+            server.write_all(&buf).await.unwrap();
+            server.flush().await.unwrap();
+        });
+        let mut client = WholeStream::from_stream(key, client).await.unwrap();
+
+        let data = b"Hello, world!";
+        client.write_all(data).await.unwrap();
+        client.flush().await.unwrap();
+        let mut buf = [0u8; 13];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, data);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_whole_into_split() {
+        let config = create_random_config();
+
+        let (client, server) = tokio::io::duplex(1024);
+        let (r, w) = tokio::io::split(client);
+        let client = WholeStream::from_key_halves(*config.key(), r, w);
+        let (r, w) = tokio::io::split(server);
+        let mut server = WholeStream::from_key_halves(*config.key(), r, w);
+
+        let data = b"Hello, world!";
+        let (mut r, mut w) = client.into_split();
+        let echo = tokio::spawn(async move {
+            let mut buf = [0u8; 13];
+            r.read_exact(&mut buf).await.unwrap();
+            buf
+        });
+        w.write_all(data).await.unwrap();
+        w.flush().await.unwrap();
+
+        let (sr, sw) = server.split();
+        let mut buf = [0u8; 13];
+        sr.read_exact(&mut buf).await.unwrap();
+        sw.write_all(&buf).await.unwrap();
+        sw.flush().await.unwrap();
+        assert_eq!(&echo.await.unwrap(), data);
+    }
+
+    #[pin_project]
+    struct NotUnpin {
+        #[pin]
+        inner: DuplexStream,
+        _pinned: PhantomPinned,
+    }
+    impl AsyncRead for NotUnpin {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            self.project().inner.poll_read(cx, buf)
+        }
+    }
+    impl AsyncWrite for NotUnpin {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.project().inner.poll_write(cx, buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.project().inner.poll_flush(cx)
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.project().inner.poll_shutdown(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_not_unpin() {
+        let config = create_random_config();
+
+        let (client, server) = tokio::io::duplex(1024);
+        let client = NotUnpin {
+            inner: client,
+            _pinned: PhantomPinned,
+        };
+        let server = NotUnpin {
+            inner: server,
+            _pinned: PhantomPinned,
+        };
+        let client = WriteHalf::new(*config.key(), client);
+        let server = ReadHalf::new(*config.key(), server);
+        tokio::pin!(client);
+        tokio::pin!(server);
+
+        let data = b"Hello, world!";
+        let mut buf = [0u8; 13];
+        client.write_all(data).await.unwrap();
+        client.flush().await.unwrap();
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, data);
+    }
 }
@@ -0,0 +1,41 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use tokio::task::JoinHandle;
+
+use crate::cursor::UserDataCursor;
+
+/// The keystream and the XORed buffer
+pub(crate) type Output = (UserDataCursor, Vec<u8>);
+
+/// Keystream application running on the blocking thread pool of tokio
+pub(crate) type Job = JoinHandle<Output>;
+
+/// XOR `buf` with the keystream of `c` without stalling the reactor thread
+pub(crate) fn spawn(mut c: UserDataCursor, mut buf: Vec<u8>) -> Job {
+    tokio::task::spawn_blocking(move || {
+        c.xor(&mut buf);
+        (c, buf)
+    })
+}
+
+/// Wait for the job in `job` if any and take its output
+pub(crate) fn poll_job(
+    job: &mut Option<Job>,
+    cx: &mut Context<'_>,
+) -> Poll<io::Result<Option<Output>>> {
+    let Some(handle) = job else {
+        return Ok(None).into();
+    };
+    let res = ready!(Pin::new(handle).poll(cx));
+    *job = None;
+    match res {
+        Ok(output) => Ok(Some(output)).into(),
+        Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+        Err(e) => Err(io::Error::other(e)).into(),
+    }
+}
@@ -0,0 +1,80 @@
+use std::sync::{Arc, Mutex};
+
+/// Buffers shared by the streams of many connections
+///
+/// Streams check a buffer out only while holding data and return it once drained,
+/// so idle connections do not keep a staging buffer each.
+#[derive(Debug, Clone)]
+pub struct BufferPool {
+    bufs: Arc<Mutex<Vec<Vec<u8>>>>,
+    max_bufs: usize,
+}
+impl BufferPool {
+    /// `max_bufs`: the most idle buffers kept for reuse
+    pub fn new(max_bufs: usize) -> Self {
+        Self {
+            bufs: Arc::new(Mutex::new(vec![])),
+            max_bufs,
+        }
+    }
+
+    /// Check out an empty buffer
+    pub fn get(&self) -> Vec<u8> {
+        self.bufs.lock().unwrap().pop().unwrap_or_default()
+    }
+
+    /// Return a buffer for reuse
+    pub fn put(&self, mut buf: Vec<u8>) {
+        if buf.capacity() == 0 {
+            return;
+        }
+        buf.clear();
+        let mut bufs = self.bufs.lock().unwrap();
+        if bufs.len() < self.max_bufs {
+            bufs.push(buf);
+        }
+    }
+
+    /// Amount of idle buffers
+    pub fn idle(&self) -> usize {
+        self.bufs.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+    use crate::{
+        config::tests::create_random_config,
+        stream::{ReadHalf, WriteHalf},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_pool() {
+        let config = create_random_config();
+        let pool = BufferPool::new(1);
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = WriteHalf::new(*config.key(), client).with_buffer_pool(pool.clone());
+        let mut server = ReadHalf::new(*config.key(), server).with_buffer_pool(pool.clone());
+
+        client.write_all(b"Hello\n").await.unwrap();
+        client.flush().await.unwrap();
+        assert_eq!(pool.idle(), 1);
+
+        let mut line = String::new();
+        server.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "Hello\n");
+        assert_eq!(pool.idle(), 1);
+
+        client.write_all(b"world").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut rest = vec![];
+        server.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"world");
+        assert_eq!(pool.idle(), 1);
+    }
+}
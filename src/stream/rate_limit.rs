@@ -0,0 +1,188 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use pin_project::pin_project;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Instant, Sleep},
+};
+
+/// Token bucket parameters of one direction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub bytes_per_sec: u64,
+    /// The most bytes passed through at once after being idle
+    pub burst_bytes: u64,
+}
+
+/// Cap the bandwidth of each direction of `T`
+///
+/// Wrap the inner stream of the halves, e.g. `WriteHalf::new(key, RateLimited::new(w, limit))`, to limit the ciphertext.
+#[pin_project]
+#[derive(Debug)]
+pub struct RateLimited<T> {
+    #[pin]
+    inner: T,
+    read: Bucket,
+    write: Bucket,
+}
+impl<T> RateLimited<T> {
+    pub fn new(inner: T, limit: RateLimit) -> Self {
+        Self::with_limits(inner, limit, limit)
+    }
+    pub fn with_limits(inner: T, read: RateLimit, write: RateLimit) -> Self {
+        Self {
+            inner,
+            read: Bucket::new(read),
+            write: Bucket::new(write),
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+impl<T: AsyncRead> AsyncRead for RateLimited<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let allowed = ready!(this.read.poll_acquire(cx, buf.remaining()));
+        let mut b = ReadBuf::new(buf.initialize_unfilled_to(allowed));
+        ready!(this.inner.poll_read(cx, &mut b))?;
+        let n = b.filled().len();
+        this.read.consume(n);
+        buf.advance(n);
+        Ok(()).into()
+    }
+}
+impl<T: AsyncWrite> AsyncWrite for RateLimited<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let allowed = ready!(this.write.poll_acquire(cx, buf.len()));
+        let n = ready!(this.inner.poll_write(cx, &buf[..allowed]))?;
+        this.write.consume(n);
+        Ok(n).into()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    limit: RateLimit,
+    tokens: u64,
+    refilled_at: Instant,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+impl Bucket {
+    fn new(limit: RateLimit) -> Self {
+        assert!(limit.bytes_per_sec > 0);
+        assert!(limit.burst_bytes > 0);
+        Self {
+            limit,
+            tokens: limit.burst_bytes,
+            refilled_at: Instant::now(),
+            sleep: None,
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at);
+        let new = elapsed.as_nanos() * self.limit.bytes_per_sec as u128 / 1_000_000_000;
+        if new == 0 {
+            return;
+        }
+        self.tokens = (self.tokens as u128 + new).min(self.limit.burst_bytes as u128) as u64;
+        self.refilled_at = match self.tokens == self.limit.burst_bytes {
+            true => now,
+            // Keep the fraction of a token earned
+            false => self.refilled_at + nanos_for(new, self.limit.bytes_per_sec),
+        };
+    }
+
+    /// Wait until some of the `wanted` bytes can pass and return that amount
+    fn poll_acquire(&mut self, cx: &mut Context<'_>, wanted: usize) -> Poll<usize> {
+        if wanted == 0 {
+            return 0.into();
+        }
+        loop {
+            if let Some(sleep) = &mut self.sleep {
+                ready!(sleep.as_mut().poll(cx));
+                self.sleep = None;
+            }
+            self.refill();
+            if self.tokens > 0 {
+                return ((wanted as u64).min(self.tokens) as usize).into();
+            }
+
+            // Sleep until enough tokens for the whole request or a full burst
+            let missing = (wanted as u64).min(self.limit.burst_bytes);
+            let deadline = self.refilled_at + nanos_for(missing as u128, self.limit.bytes_per_sec);
+            self.sleep = Some(Box::pin(tokio::time::sleep_until(deadline)));
+        }
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.tokens = self.tokens.saturating_sub(n as u64);
+    }
+}
+
+fn nanos_for(bytes: u128, bytes_per_sec: u64) -> Duration {
+    let nanos = (bytes * 1_000_000_000).div_ceil(bytes_per_sec as u128);
+    Duration::from_nanos(nanos.try_into().unwrap_or(u64::MAX))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{config::tests::create_random_config, stream::WriteHalf};
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limit() {
+        let config = create_random_config();
+        let limit = RateLimit {
+            bytes_per_sec: 1000,
+            burst_bytes: 500,
+        };
+
+        let (client, mut server) = tokio::io::duplex(64 * 1024);
+        let client = RateLimited::new(client, limit);
+        let mut client = WriteHalf::new(*config.key(), client);
+
+        let start = Instant::now();
+        client.write_all(&[0; 2488]).await.unwrap();
+        client.flush().await.unwrap();
+        let elapsed = start.elapsed();
+
+        // The burst plus 2000 bytes at 1000 bytes per second
+        assert!(Duration::from_secs(2) <= elapsed);
+        assert!(elapsed < Duration::from_millis(2100));
+        let mut buf = vec![0; 12 + 2488];
+        server.read_exact(&mut buf).await.unwrap();
+    }
+}
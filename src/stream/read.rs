@@ -1,76 +1,827 @@
-use std::{io, pin::Pin, task::ready};
+use std::{
+    io::{self, SeekFrom},
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
 
 use arrayvec::ArrayVec;
-use tokio::io::{AsyncRead, ReadBuf};
+use bytes::BufMut;
+use pin_project::pin_project;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncSeek, ReadBuf};
 
+#[cfg(feature = "parallel")]
+use crate::cipher::ParallelismConfig;
 use crate::{
-    cursor::{NonceWriteCursor, WriteCursorState},
-    KEY_BYTES,
+    cipher::MAX_KEYSTREAM_BYTES,
+    config::{derive_subkey, STREAM_SUBKEY_INFO},
+    cursor::{NonceWriteCursor, UserDataCursor, WriteCursorState},
+    KEY_BYTES, NONCE_BYTES, X_NONCE_BYTES,
 };
 
-#[derive(Debug)]
-pub struct ReadHalf<R> {
+use super::{offload, BufferPool, Error};
+
+/// IO-agnostic state machine of a decrypting reader
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ReadState {
     cursor: Option<WriteCursorState>,
-    r: R,
+    nonce_bytes: usize,
+    /// The nonce collected so far
+    nonce: ArrayVec<u8, X_NONCE_BYTES>,
+    /// Switch to the subkey of the key and the nonce once the nonce is in
+    #[cfg_attr(feature = "serde", serde(default))]
+    subkey: bool,
+    #[cfg(feature = "parallel")]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    par: Option<ParallelismConfig>,
 }
-impl<R> ReadHalf<R> {
-    pub fn new(key: [u8; KEY_BYTES], r: R) -> Self {
+impl ReadState {
+    pub fn new(key: [u8; KEY_BYTES]) -> Self {
         let cursor = NonceWriteCursor::new(key);
         let cursor = Some(WriteCursorState::Nonce(cursor));
-        Self { cursor, r }
+        let nonce_bytes = NONCE_BYTES;
+        Self {
+            cursor,
+            nonce_bytes,
+            nonce: ArrayVec::new(),
+            subkey: false,
+            #[cfg(feature = "parallel")]
+            par: None,
+        }
     }
-    pub fn new_x(key: [u8; KEY_BYTES], r: R) -> Self {
+    pub fn new_x(key: [u8; KEY_BYTES]) -> Self {
         let cursor = NonceWriteCursor::new_x(key);
         let cursor = Some(WriteCursorState::Nonce(cursor));
-        Self { cursor, r }
+        let nonce_bytes = X_NONCE_BYTES;
+        Self {
+            cursor,
+            nonce_bytes,
+            nonce: ArrayVec::new(),
+            subkey: false,
+            #[cfg(feature = "parallel")]
+            par: None,
+        }
     }
-}
-impl<R: AsyncRead + Unpin> AsyncRead for ReadHalf<R> {
-    fn poll_read(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-        buf: &mut tokio::io::ReadBuf<'_>,
-    ) -> std::task::Poll<std::io::Result<()>> {
+
+    /// Decrypt with the subkey of [`WriteState::with_subkey`](super::WriteState::with_subkey)
+    ///
+    /// Must be called before the nonce is collected.
+    pub fn with_subkey(mut self) -> Self {
+        self.subkey = true;
+        self
+    }
+
+    /// Tune how large reads are decrypted in parallel
+    #[cfg(feature = "parallel")]
+    pub fn with_parallelism(mut self, par: ParallelismConfig) -> Self {
+        self.par = Some(par);
+        self.apply_parallelism();
+        self
+    }
+    fn apply_parallelism(&mut self) {
+        #[cfg(feature = "parallel")]
+        if let (Some(WriteCursorState::UserData(c)), Some(par)) = (&mut self.cursor, &self.par) {
+            c.set_parallelism(par.clone());
+        }
+    }
+
+    /// Decrypt the ciphertext following `c` on its own
+    pub(crate) fn from_keystream(c: UserDataCursor, nonce: &[u8]) -> Self {
+        Self {
+            cursor: Some(WriteCursorState::UserData(c)),
+            nonce_bytes: nonce.len(),
+            nonce: nonce.try_into().unwrap(),
+            subkey: false,
+            #[cfg(feature = "parallel")]
+            par: None,
+        }
+    }
+
+    /// An independent copy decrypting the same ciphertext from the same position on
+    ///
+    /// Lets tools like recorders mirror the decryption of a session without taking over its stream.
+    /// `None` while the keystream is out with an offloaded job.
+    pub fn fork(&self) -> Option<Self> {
+        self.cursor.as_ref()?;
+        Some(self.clone())
+    }
+
+    /// Size of the nonce prefixing the ciphertext
+    pub fn nonce_bytes(&self) -> usize {
+        self.nonce_bytes
+    }
+
+    /// Whether the whole nonce has been collected
+    pub fn nonce_complete(&self) -> bool {
+        matches!(self.cursor, Some(WriteCursorState::UserData(_)))
+    }
+
+    /// The nonce prefixing the ciphertext once it has been collected
+    pub fn nonce(&self) -> Option<&[u8]> {
+        match self.nonce_complete() {
+            true => Some(&self.nonce),
+            false => None,
+        }
+    }
+
+    /// Move the keystream to the plaintext offset `pos`
+    ///
+    /// The whole nonce must have been collected.
+    pub fn seek(&mut self, pos: u64) -> io::Result<()> {
+        let Some(WriteCursorState::UserData(c)) = &mut self.cursor else {
+            return Err(Error::NonceNotRead.into());
+        };
+        if MAX_KEYSTREAM_BYTES < pos {
+            return Err(Error::KeystreamExhausted(pos).into());
+        }
+        c.seek(pos);
+        Ok(())
+    }
+
+    /// Keystream bytes left before the block counter wraps once the whole nonce is collected
+    pub fn remaining_keystream(&self) -> Option<u64> {
+        match &self.cursor {
+            Some(WriteCursorState::UserData(c)) => Some(c.remaining()),
+            _ => None,
+        }
+    }
+
+    /// Part of `len` bytes to read that the keystream covers
+    ///
+    /// One byte past the end of the keystream is still read to spot data that cannot be decrypted.
+    pub(crate) fn read_limit(&self, len: usize) -> usize {
+        match self.remaining_keystream() {
+            Some(remaining) => len.min(usize::try_from(remaining).unwrap_or(usize::MAX).max(1)),
+            None => len,
+        }
+    }
+
+    /// Fail if `n` freshly read bytes run past the end of the keystream
+    pub(crate) fn check_read(&self, n: usize) -> io::Result<()> {
+        match self.remaining_keystream() {
+            Some(remaining) if remaining < n as u64 => {
+                let end = MAX_KEYSTREAM_BYTES - remaining + n as u64;
+                Err(Error::KeystreamExhausted(end).into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Move the keystream out to decrypt elsewhere
+    ///
+    /// The whole nonce must have been collected; the state stays unusable until [`Self::put_keystream`].
+    pub(crate) fn take_keystream(&mut self) -> UserDataCursor {
+        let Some(WriteCursorState::UserData(c)) = self.cursor.take() else {
+            unreachable!();
+        };
+        c
+    }
+
+    pub(crate) fn put_keystream(&mut self, c: UserDataCursor) {
+        self.cursor = Some(WriteCursorState::UserData(c));
+    }
+
+    /// Collect the whole nonce from `read`
+    ///
+    /// Return `false` if the reader hits EOF before the nonce is complete.
+    pub fn poll_nonce(
+        &mut self,
+        mut read: impl FnMut(&mut [u8]) -> Poll<io::Result<usize>>,
+    ) -> Poll<io::Result<bool>> {
         // Loop for state transitions from `Nonce` to `UserData`
         loop {
             match self.cursor.take().unwrap() {
                 WriteCursorState::Nonce(c) => {
                     assert!(c.remaining_nonce_size() > 0);
 
-                    // let mut buf = vec![0; self.remaining_nonce_size()];
-                    let mut buf = ArrayVec::<u8, 12>::from_iter(
-                        std::iter::repeat(0).take(c.remaining_nonce_size()),
-                    );
-                    let mut buf = ReadBuf::new(&mut buf);
+                    let mut nonce = [0; X_NONCE_BYTES];
+                    let nonce = &mut nonce[..c.remaining_nonce_size()];
 
-                    // Collect nonce from `r`
-                    let filled_len = buf.filled().len();
-                    let ready = Pin::new(&mut self.r).poll_read(cx, &mut buf);
+                    // Collect nonce from the reader
+                    let ready = read(nonce);
 
                     // Write nonce segments to the cursor
-                    let mut rdr = io::Cursor::new(buf.filled());
-                    let c = c.collect_nonce_from(&mut rdr);
-                    assert_eq!(rdr.position() as usize, rdr.get_ref().len());
+                    let n = match &ready {
+                        Poll::Ready(Ok(n)) => *n,
+                        _ => 0,
+                    };
+                    let key = *c.key();
+                    let (mut c, consumed) = c.collect_nonce(&nonce[..n]);
+                    assert_eq!(consumed, n);
+                    self.nonce.try_extend_from_slice(&nonce[..n]).unwrap();
+                    if let (WriteCursorState::UserData(c), true) = (&mut c, self.subkey) {
+                        c.rekey(derive_subkey(&key, &self.nonce, STREAM_SUBKEY_INFO));
+                    }
                     self.cursor = Some(c);
+                    self.apply_parallelism();
 
-                    ready!(ready)?;
-
-                    if buf.filled().len() == filled_len {
-                        // `r` hits EOF
-                        return Ok(()).into();
+                    if ready!(ready)? == 0 {
+                        // The reader hits EOF
+                        return Ok(false).into();
                     }
                 }
-                WriteCursorState::UserData(mut c) => {
-                    // Read data from the `r`
-                    let ready = Pin::new(&mut self.r).poll_read(cx, buf);
+                WriteCursorState::UserData(c) => {
+                    self.cursor = Some(WriteCursorState::UserData(c));
+                    return Ok(true).into();
+                }
+            }
+        }
+    }
+
+    /// Fill `buf` with plaintext decrypted from the ciphertext read by `read`
+    ///
+    /// Return the amount of bytes written to `buf`; `0` means EOF.
+    ///
+    /// [`Error::NonceTruncated`] if the reader hits EOF after part of the nonce.
+    pub fn poll(
+        &mut self,
+        buf: &mut [u8],
+        mut read: impl FnMut(&mut [u8]) -> Poll<io::Result<usize>>,
+    ) -> Poll<io::Result<usize>> {
+        if !ready!(self.poll_nonce(&mut read))? {
+            return match self.nonce.is_empty() {
+                true => Ok(0).into(),
+                false => Err(Error::NonceTruncated.into()).into(),
+            };
+        }
+        // Read data from the reader
+        let limit = self.read_limit(buf.len());
+        let n = ready!(read(&mut buf[..limit]))?;
+        self.check_read(n)?;
+        let Some(WriteCursorState::UserData(c)) = &mut self.cursor else {
+            unreachable!();
+        };
 
-                    // Decrypt the read user data in place
-                    c.xor(buf.filled_mut());
+        // Decrypt the read user data in place
+        c.xor(&mut buf[..n]);
+        Ok(n).into()
+    }
 
-                    self.cursor = Some(WriteCursorState::UserData(c));
-                    return ready;
+    /// [`Self::poll`] filling the unfilled part of `buf` without zeroing it first
+    pub fn poll_into(
+        &mut self,
+        buf: &mut ReadBuf<'_>,
+        mut read: impl FnMut(&mut ReadBuf<'_>) -> Poll<io::Result<()>>,
+    ) -> Poll<io::Result<()>> {
+        let complete = ready!(self.poll_nonce(|b| {
+            let mut b = ReadBuf::new(b);
+            ready!(read(&mut b))?;
+            Ok(b.filled().len()).into()
+        }))?;
+        if !complete {
+            return match self.nonce.is_empty() {
+                true => Ok(()).into(),
+                false => Err(Error::NonceTruncated.into()).into(),
+            };
+        }
+
+        // Read data from the reader straight into the possibly uninitialized memory
+        let mut b = buf.take(self.read_limit(buf.remaining()));
+        let ptr = b.filled().as_ptr();
+        ready!(read(&mut b))?;
+        assert_eq!(b.filled().as_ptr(), ptr, "the reader swapped the buffer");
+        let n = b.filled().len();
+        self.check_read(n)?;
+        // SAFETY: `read` has initialized the first `n` bytes of the unfilled part
+        unsafe { buf.assume_init(n) };
+        let start = buf.filled().len();
+        buf.advance(n);
+        let Some(WriteCursorState::UserData(c)) = &mut self.cursor else {
+            unreachable!();
+        };
+
+        // Decrypt the read user data in place
+        c.xor(&mut buf.filled_mut()[start..]);
+        Ok(()).into()
+    }
+}
+
+const DEFAULT_BUF_BYTES: usize = 8 * 1024;
+
+#[pin_project]
+#[derive(Debug)]
+pub struct ReadHalf<R> {
+    state: ReadState,
+    #[pin]
+    r: R,
+    /// Decrypted data for `AsyncBufRead`
+    buf: Vec<u8>,
+    pos: usize,
+    filled: usize,
+    pool: Option<BufferPool>,
+    offload_threshold: Option<usize>,
+    job: Option<offload::Job>,
+}
+impl<R> ReadHalf<R> {
+    pub fn new(key: [u8; KEY_BYTES], r: R) -> Self {
+        Self::from_state(ReadState::new(key), r)
+    }
+    pub fn new_x(key: [u8; KEY_BYTES], r: R) -> Self {
+        Self::from_state(ReadState::new_x(key), r)
+    }
+    /// Resume from a checkpointed `state`
+    pub fn from_state(state: ReadState, r: R) -> Self {
+        Self {
+            state,
+            r,
+            buf: vec![],
+            pos: 0,
+            filled: 0,
+            pool: None,
+            offload_threshold: None,
+            job: None,
+        }
+    }
+
+    /// Check the `AsyncBufRead` buffer out of `pool` only while it holds data
+    pub fn with_buffer_pool(mut self, pool: BufferPool) -> Self {
+        pool.put(core::mem::take(&mut self.buf));
+        self.pool = Some(pool);
+        self
+    }
+
+    /// Tune how large reads are decrypted in parallel
+    #[cfg(feature = "parallel")]
+    pub fn with_parallelism(mut self, par: ParallelismConfig) -> Self {
+        self.state = self.state.with_parallelism(par);
+        self
+    }
+
+    /// Decrypt reads asking for more than `bytes` on the blocking thread pool of tokio
+    ///
+    /// Only the tokio `AsyncRead` impl offloads.
+    pub fn with_offload_threshold(mut self, bytes: usize) -> Self {
+        self.offload_threshold = Some(bytes);
+        self
+    }
+
+    /// Wait for the offloaded decryption to hand the plaintext over to the inner buffer
+    pub(crate) fn poll_offloaded(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        if let Some((c, buf)) = ready!(offload::poll_job(this.job, cx))? {
+            this.state.put_keystream(c);
+            *this.pos = 0;
+            *this.filled = buf.len();
+            *this.buf = buf;
+        }
+        Ok(()).into()
+    }
+
+    /// The nonce prefixing the ciphertext once it has been read
+    pub fn nonce(&self) -> Option<&[u8]> {
+        self.state.nonce()
+    }
+
+    /// Checkpoint of the decryption progress
+    ///
+    /// Excludes the plaintext held for `AsyncBufRead` and is incomplete while an offloaded job is in flight.
+    pub fn state(&self) -> &ReadState {
+        &self.state
+    }
+
+    pub(crate) fn parts_mut(self: Pin<&mut Self>) -> (&mut ReadState, Pin<&mut R>) {
+        let this = self.project();
+        (this.state, this.r)
+    }
+
+    /// Decrypt into the spare capacity of `buf`, e.g. a `BytesMut`, without zeroing it first
+    ///
+    /// Return the amount of bytes appended to `buf`; `0` means EOF.
+    pub fn poll_read_buf(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut impl BufMut,
+    ) -> Poll<io::Result<usize>>
+    where
+        R: AsyncRead,
+    {
+        tokio_util::io::poll_read_buf(self, cx, buf)
+    }
+
+    /// Wait for the whole nonce to arrive
+    pub async fn read_nonce(&mut self) -> io::Result<()>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let (state, mut r) = Pin::new(self).parts_mut();
+        let complete = std::future::poll_fn(|cx| {
+            state.poll_nonce(|b| {
+                let mut b = ReadBuf::new(b);
+                ready!(r.as_mut().poll_read(cx, &mut b))?;
+                Ok(b.filled().len()).into()
+            })
+        })
+        .await?;
+        match complete {
+            true => Ok(()),
+            false => Err(Error::NonceTruncated.into()),
+        }
+    }
+
+    /// Decrypted data not yet consumed by `AsyncBufRead::consume`
+    pub fn buffer(&self) -> &[u8] {
+        &self.buf[self.pos..self.filled]
+    }
+
+    fn consume_buffer(self: Pin<&mut Self>, amt: usize) {
+        let this = self.project();
+        *this.pos = (*this.pos + amt).min(*this.filled);
+        if this.pos == this.filled {
+            *this.pos = 0;
+            *this.filled = 0;
+            release_buffer(this.buf, this.pool.as_ref());
+        }
+    }
+
+    /// Move buffered data to `buf` before reading anything new
+    pub(crate) fn read_buffered(self: Pin<&mut Self>, buf: &mut [u8]) -> usize {
+        let n = self.buffer().len().min(buf.len());
+        buf[..n].copy_from_slice(&self.buffer()[..n]);
+        self.consume_buffer(n);
+        n
+    }
+}
+impl<R: AsyncRead> AsyncRead for ReadHalf<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_offloaded(cx))?;
+        if self.buffer().is_empty() {
+            let this = self.as_mut().project();
+            match *this.offload_threshold {
+                Some(threshold) if threshold < buf.remaining() && this.state.nonce_complete() => {
+                    // Read the ciphertext into the inner buffer
+                    let mut ciphertext = core::mem::take(this.buf);
+                    if let (0, Some(pool)) = (ciphertext.capacity(), this.pool.as_ref()) {
+                        ciphertext = pool.get();
+                    }
+                    ciphertext.resize(this.state.read_limit(buf.remaining()), 0);
+                    let mut b = ReadBuf::new(&mut ciphertext);
+                    let res = this.r.poll_read(cx, &mut b);
+                    let n = b.filled().len();
+                    let checked = this.state.check_read(n);
+                    if !matches!(res, Poll::Ready(Ok(()))) || n == 0 || checked.is_err() {
+                        *this.buf = ciphertext;
+                        *this.pos = 0;
+                        *this.filled = 0;
+                        release_buffer(this.buf, this.pool.as_ref());
+                        return match checked {
+                            Ok(()) => res,
+                            Err(e) => Err(e).into(),
+                        };
+                    }
+
+                    // Decrypt it on the blocking thread pool
+                    ciphertext.truncate(n);
+                    let c = this.state.take_keystream();
+                    *this.job = Some(offload::spawn(c, ciphertext));
+                    ready!(self.as_mut().poll_offloaded(cx))?;
                 }
+                _ => (),
             }
         }
+        if !self.buffer().is_empty() {
+            let n = self.buffer().len().min(buf.remaining());
+            let n = self.read_buffered(buf.initialize_unfilled_to(n));
+            buf.advance(n);
+            return Ok(()).into();
+        }
+
+        // Bypass the inner buffer to avoid double buffering
+        let (state, mut r) = self.parts_mut();
+        state.poll_into(buf, |b| r.as_mut().poll_read(cx, b))
+    }
+}
+impl<R: AsyncRead> AsyncBufRead for ReadHalf<R> {
+    fn poll_fill_buf(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        // The plaintext of an offloaded read comes first and the keystream is out until then
+        ready!(self.as_mut().poll_offloaded(cx))?;
+        let this = self.project();
+        if *this.pos == *this.filled {
+            if this.buf.len() < DEFAULT_BUF_BYTES {
+                if let (true, Some(pool)) = (this.buf.is_empty(), this.pool.as_ref()) {
+                    *this.buf = pool.get();
+                }
+                this.buf.resize(DEFAULT_BUF_BYTES, 0);
+            }
+            let mut r = this.r;
+            let res = this.state.poll(this.buf, |b| {
+                let mut b = ReadBuf::new(b);
+                ready!(r.as_mut().poll_read(cx, &mut b))?;
+                Ok(b.filled().len()).into()
+            });
+            let n = match res {
+                Poll::Ready(Ok(n)) => n,
+                _ => {
+                    // Do not hold the buffer while waiting
+                    *this.pos = 0;
+                    *this.filled = 0;
+                    release_buffer(this.buf, this.pool.as_ref());
+                    return res.map_ok(|_| &[][..]);
+                }
+            };
+            *this.pos = 0;
+            *this.filled = n;
+        }
+        Ok(&this.buf[*this.pos..*this.filled]).into()
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        self.consume_buffer(amt);
+    }
+}
+
+/// Plaintext offsets are translated to offsets past the nonce of the inner reader
+///
+/// Call [`ReadHalf::read_nonce`] before seeking.
+impl<R: AsyncSeek> AsyncSeek for ReadHalf<R> {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        if self.job.is_some() {
+            return Err(Error::Busy.into());
+        }
+        if !self.state.nonce_complete() {
+            return Err(Error::NonceNotRead.into());
+        }
+        let nonce_bytes = self.state.nonce_bytes() as u64;
+        let position = match position {
+            SeekFrom::Start(pos) => {
+                SeekFrom::Start(pos.checked_add(nonce_bytes).ok_or(Error::SeekOutOfRange)?)
+            }
+            // The inner reader is ahead of the caller by the buffered data
+            SeekFrom::Current(offset) => SeekFrom::Current(offset - self.buffer().len() as i64),
+            SeekFrom::End(offset) => SeekFrom::End(offset),
+        };
+        let this = self.as_mut().project();
+        this.r.start_seek(position)?;
+        *this.pos = 0;
+        *this.filled = 0;
+        release_buffer(this.buf, this.pool.as_ref());
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        let (state, r) = self.parts_mut();
+        let pos = ready!(r.poll_complete(cx))?;
+        if !state.nonce_complete() {
+            // Nothing has been decrypted yet
+            return Ok(0).into();
+        }
+        let Some(pos) = pos.checked_sub(state.nonce_bytes() as u64) else {
+            return Err(Error::SeekOutOfRange.into()).into();
+        };
+        state.seek(pos)?;
+        Ok(pos).into()
+    }
+}
+
+/// Hand a drained buffer back to `pool`
+fn release_buffer(buf: &mut Vec<u8>, pool: Option<&BufferPool>) {
+    if let Some(pool) = pool {
+        pool.put(core::mem::take(buf));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+    use crate::{
+        config::tests::create_random_config,
+        stream::{WriteHalf, WriteState},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_buf_read() {
+        let config = create_random_config();
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = WriteHalf::new(*config.key(), client);
+        let mut server = ReadHalf::new(*config.key(), server);
+
+        client.write_all(b"Hello\nworld\n!").await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let mut line = String::new();
+        server.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "Hello\n");
+        let mut buf = [0; 3];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"wor");
+        let mut rest = vec![];
+        server.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"ld\n!");
+    }
+
+    #[tokio::test]
+    async fn test_x_nonce() {
+        let config = create_random_config();
+
+        // The 24-byte nonce arrives in pieces
+        let (client, server) = tokio::io::duplex(5);
+        let mut client = WriteHalf::new_x(*config.key(), client);
+        let mut server = ReadHalf::new_x(*config.key(), server);
+
+        let write = tokio::spawn(async move {
+            client.write_all(b"Hello, world!").await.unwrap();
+            client.shutdown().await.unwrap();
+        });
+        let mut buf = vec![];
+        server.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"Hello, world!");
+        write.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_buf() {
+        let config = create_random_config();
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = WriteHalf::new(*config.key(), client);
+        let mut server = ReadHalf::new(*config.key(), server);
+
+        let msg: Vec<u8> = (0..=u8::MAX).cycle().take(3000).collect();
+        let send = tokio::spawn(async move {
+            client.write_all(&msg).await.unwrap();
+            client.shutdown().await.unwrap();
+            msg
+        });
+        let mut buf = bytes::BytesMut::with_capacity(64);
+        loop {
+            buf.reserve(64);
+            let n = std::future::poll_fn(|cx| Pin::new(&mut server).poll_read_buf(cx, &mut buf))
+                .await
+                .unwrap();
+            if n == 0 {
+                break;
+            }
+        }
+        assert_eq!(buf, send.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_subkey() {
+        let config = create_random_config();
+        let key = *config.key();
+        let msg = b"Hello world!";
+
+        for x in [false, true] {
+            let (w, r) = match x {
+                false => (WriteState::new(key), ReadState::new(key)),
+                true => (WriteState::new_x(key), ReadState::new_x(key)),
+            };
+            let mut ciphertext = vec![];
+            let mut client = WriteHalf::from_state(w.with_subkey(), &mut ciphertext);
+            client.write_all(msg).await.unwrap();
+            client.flush().await.unwrap();
+
+            let mut server = ReadHalf::from_state(r.clone().with_subkey(), ciphertext.as_slice());
+            let mut buf = vec![];
+            server.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, msg);
+
+            // The long-term key alone does not decrypt it
+            let mut server = ReadHalf::from_state(r, ciphertext.as_slice());
+            let mut buf = vec![];
+            server.read_to_end(&mut buf).await.unwrap();
+            assert_ne!(buf, msg);
+        }
+    }
+
+    #[test]
+    fn test_keystream_exhausted() {
+        let config = create_random_config();
+        let mut state = ReadState::new(*config.key());
+        let nonce = [0; NONCE_BYTES];
+        let Poll::Ready(Ok(true)) = state.poll_nonce(|b| {
+            b.copy_from_slice(&nonce[..b.len()]);
+            Poll::Ready(Ok(b.len()))
+        }) else {
+            unreachable!();
+        };
+        state.seek(MAX_KEYSTREAM_BYTES - 3).unwrap();
+
+        // Data past the end of the keystream is never decrypted
+        let mut read = |b: &mut [u8]| Poll::Ready(Ok(b.len()));
+        let mut buf = [0; 10];
+        let Poll::Ready(res) = state.poll(&mut buf, &mut read) else {
+            unreachable!();
+        };
+        assert_eq!(res.unwrap(), 3);
+        let Poll::Ready(res) = state.poll(&mut buf, &mut read) else {
+            unreachable!();
+        };
+        let e = res.unwrap_err();
+        assert_eq!(
+            Error::from_io(&e),
+            Some(&Error::KeystreamExhausted(MAX_KEYSTREAM_BYTES + 1))
+        );
+        let Poll::Ready(res) = state.poll(&mut buf, |_| Poll::Ready(Ok(0))) else {
+            unreachable!();
+        };
+        assert_eq!(res.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_seek() {
+        let config = create_random_config();
+
+        let data: Vec<u8> = (0..=u8::MAX).cycle().take(1000).collect();
+        let mut w = WriteHalf::new_x(*config.key(), vec![]);
+        w.write_all(&data).await.unwrap();
+        let (_, ciphertext) = Pin::new(&mut w).parts_mut();
+        let ciphertext = std::mem::take(ciphertext.get_mut());
+
+        let mut r = ReadHalf::new_x(*config.key(), io::Cursor::new(ciphertext));
+        let err = r.seek(SeekFrom::Start(0)).await.unwrap_err();
+        assert_eq!(Error::from_io(&err), Some(&Error::NonceNotRead));
+        r.read_nonce().await.unwrap();
+        let err = r.state.seek(MAX_KEYSTREAM_BYTES + 1).unwrap_err();
+        assert_eq!(
+            Error::from_io(&err),
+            Some(&Error::KeystreamExhausted(MAX_KEYSTREAM_BYTES + 1))
+        );
+
+        let mut buf = [0; 10];
+        assert_eq!(r.seek(SeekFrom::Start(130)).await.unwrap(), 130);
+        r.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, data[130..140]);
+
+        // Leave data in the inner buffer
+        assert_eq!(r.fill_buf().await.unwrap(), &data[140..]);
+        r.consume(5);
+        assert_eq!(r.stream_position().await.unwrap(), 145);
+        assert_eq!(r.seek(SeekFrom::Current(-100)).await.unwrap(), 45);
+        r.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, data[45..55]);
+
+        assert_eq!(r.seek(SeekFrom::End(-10)).await.unwrap(), 990);
+        r.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, data[990..]);
+    }
+
+    #[tokio::test]
+    async fn test_offload() {
+        let config = create_random_config();
+
+        let data: Vec<u8> = (0..=u8::MAX).cycle().take(64 * 1024 + 1).collect();
+        let mut w = WriteHalf::new(*config.key(), vec![]);
+        w.write_all(&data).await.unwrap();
+        w.flush().await.unwrap();
+        let (_, ciphertext) = Pin::new(&mut w).parts_mut();
+        let ciphertext = std::mem::take(ciphertext.get_mut());
+
+        let mut r =
+            ReadHalf::new(*config.key(), ciphertext.as_slice()).with_offload_threshold(1024);
+        let mut plaintext = vec![0; 2048];
+        r.read_exact(&mut plaintext[..1]).await.unwrap();
+        r.read_exact(&mut plaintext[1..]).await.unwrap();
+        assert_eq!(plaintext, data[..2048]);
+        r.read_to_end(&mut plaintext).await.unwrap();
+        assert_eq!(plaintext, data);
+    }
+
+    #[tokio::test]
+    async fn test_fill_buf_after_offload() {
+        let config = create_random_config();
+
+        let data: Vec<u8> = (0..=u8::MAX).cycle().take(4096).collect();
+        let mut w = WriteHalf::new(*config.key(), vec![]);
+        w.write_all(&data).await.unwrap();
+        w.flush().await.unwrap();
+        let (_, ciphertext) = Pin::new(&mut w).parts_mut();
+        let ciphertext = std::mem::take(ciphertext.get_mut());
+
+        let mut r =
+            ReadHalf::new(*config.key(), ciphertext.as_slice()).with_offload_threshold(1024);
+        let mut plaintext = vec![0; 2048];
+        r.read_exact(&mut plaintext[..1]).await.unwrap();
+
+        // Leave the decryption in flight
+        let mut b = ReadBuf::new(&mut plaintext[1..]);
+        let polled =
+            std::future::poll_fn(|cx| Poll::Ready(Pin::new(&mut r).poll_read(cx, &mut b))).await;
+        let n = match polled {
+            Poll::Ready(res) => {
+                res.unwrap();
+                b.filled().len()
+            }
+            Poll::Pending => 0,
+        };
+
+        let buffered = r.fill_buf().await.unwrap().to_vec();
+        assert!(!buffered.is_empty());
+        assert_eq!(buffered, data[1 + n..1 + n + buffered.len()]);
+        let consumed = 1 + n + buffered.len();
+        r.consume(buffered.len());
+        let mut rest = vec![];
+        r.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, data[consumed..]);
     }
 }
@@ -0,0 +1,105 @@
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::config::Config;
+
+use super::{pair, subkey_pair, WholeStream};
+
+/// Encrypt data from `plain` to `encrypted` and decrypt data from `encrypted` to `plain` until both directions hit EOF
+///
+/// Hitting EOF in one direction shuts down the writer of the other side.
+///
+/// Return the amount of plaintext bytes relayed in each direction: `(plain -> encrypted, encrypted -> plain)`.
+pub async fn relay<P, E>(
+    plain: &mut P,
+    encrypted: &mut E,
+    config: &Config,
+) -> io::Result<(u64, u64)>
+where
+    P: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    E: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let (r, w) = tokio::io::split(encrypted);
+    let (r, w) = pair(*config.key(), r, w);
+    let mut encrypted = WholeStream::new(r, w);
+    tokio::io::copy_bidirectional(plain, &mut encrypted).await
+}
+
+/// [`relay`] encrypting under subkeys of the key of `config` and each nonce
+///
+/// The peer has to decrypt with [`subkey_pair`] as well; a peer on the plain key sees garbage.
+pub async fn subkey_relay<P, E>(
+    plain: &mut P,
+    encrypted: &mut E,
+    config: &Config,
+) -> io::Result<(u64, u64)>
+where
+    P: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    E: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let (r, w) = tokio::io::split(encrypted);
+    let (r, w) = subkey_pair(*config.key(), r, w);
+    let mut encrypted = WholeStream::new(r, w);
+    tokio::io::copy_bidirectional(plain, &mut encrypted).await
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::config::tests::create_random_config;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_relay() {
+        run_relay(false).await;
+    }
+
+    #[tokio::test]
+    async fn test_subkey_relay() {
+        run_relay(true).await;
+    }
+
+    async fn run_relay(subkey: bool) {
+        let config = create_random_config();
+
+        let (mut app, mut plain) = tokio::io::duplex(1024);
+        let (mut encrypted, peer) = tokio::io::duplex(1024);
+        let relay = {
+            let config = config.clone();
+            tokio::spawn(async move {
+                match subkey {
+                    true => subkey_relay(&mut plain, &mut encrypted, &config).await,
+                    false => relay(&mut plain, &mut encrypted, &config).await,
+                }
+            })
+        };
+        let (r, w) = tokio::io::split(peer);
+        let (r, w) = match subkey {
+            true => subkey_pair(*config.key(), r, w),
+            false => pair(*config.key(), r, w),
+        };
+        let mut peer = WholeStream::new(r, w);
+
+        let data = b"Hello, world!";
+        let mut buf = [0u8; 13];
+        app.write_all(data).await.unwrap();
+        peer.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, data);
+        peer.write_all(data).await.unwrap();
+        peer.flush().await.unwrap();
+        app.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, data);
+
+        app.shutdown().await.unwrap();
+        assert_eq!(peer.read(&mut buf).await.unwrap(), 0);
+        peer.shutdown().await.unwrap();
+        assert_eq!(app.read(&mut buf).await.unwrap(), 0);
+
+        let (a_to_b, b_to_a) = relay.await.unwrap().unwrap();
+        assert_eq!(a_to_b, data.len() as u64);
+        assert_eq!(b_to_a, data.len() as u64);
+    }
+}
@@ -0,0 +1,451 @@
+use std::{
+    fmt, io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use pin_project::pin_project;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::{
+    cursor::{CursorError, DecryptCursor, EncryptCursor},
+    key::{KeyVersion, SecretKey},
+    mac::verify_tag,
+    KEY_BYTES, TAG_BYTES, X_NONCE_BYTES,
+};
+
+use super::Error;
+
+const DEFAULT_BUF_BYTES: usize = 64 * 1024;
+
+/// Authenticated messages back to back on one transport
+///
+/// Wire format of each message: `[key version] || nonce || ciphertext || tag`,
+/// the tag being AEAD_CHACHA20_POLY1305 from RFC 8439 over the key version if any.
+/// Write the plaintext of a message through `AsyncWrite` and end it with [`Self::finalize`].
+/// [`TagReader`] reads them back given the length of each message.
+#[pin_project]
+pub struct NonceCiphertextTagWriter<W> {
+    key: SecretKey,
+    x: bool,
+    key_version: Option<KeyVersion>,
+    /// Cursor of the current message
+    en: EncryptCursor,
+    /// Output not yet accepted by `w`
+    buf: Vec<u8>,
+    pos: usize,
+    buf_capacity: usize,
+    #[pin]
+    w: W,
+}
+impl<W> NonceCiphertextTagWriter<W> {
+    pub fn new(key: [u8; KEY_BYTES], w: W) -> Self {
+        Self::with_nonce_size(key, false, w)
+    }
+    pub fn new_x(key: [u8; KEY_BYTES], w: W) -> Self {
+        Self::with_nonce_size(key, true, w)
+    }
+    fn with_nonce_size(key: [u8; KEY_BYTES], x: bool, w: W) -> Self {
+        Self {
+            en: message_cursor(key, x, None),
+            key: key.into(),
+            x,
+            key_version: None,
+            buf: Vec::new(),
+            pos: 0,
+            buf_capacity: DEFAULT_BUF_BYTES,
+            w,
+        }
+    }
+
+    /// Cap the plaintext claimed by one write to `bytes`
+    ///
+    /// The allocation of the inner buffer is reused across writes and never grows much past `bytes`.
+    pub fn with_buf_capacity(mut self, bytes: usize) -> Self {
+        assert!(bytes > 0);
+        self.buf_capacity = bytes;
+        self
+    }
+
+    /// Prefix each message with `version`, authenticated by its tag
+    ///
+    /// Must be called before any message is written.
+    pub fn with_key_version(mut self, version: KeyVersion) -> Self {
+        self.key_version = Some(version);
+        self.en = message_cursor(*self.key.expose_secret(), self.x, self.key_version);
+        self
+    }
+
+    /// The message not finalized yet is dropped
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+}
+impl<W: AsyncWrite> NonceCiphertextTagWriter<W> {
+    /// Pass everything encrypted so far to `w`
+    fn poll_drain(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+        while *this.pos < this.buf.len() {
+            let n = ready!(this.w.as_mut().poll_write(cx, &this.buf[*this.pos..]))?;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into()).into();
+            }
+            *this.pos += n;
+        }
+        this.buf.clear();
+        *this.pos = 0;
+        Ok(()).into()
+    }
+
+    /// End the current message with its tag and start the next one under a fresh nonce
+    ///
+    /// The transport is flushed but not shut down, so more messages can follow.
+    pub async fn finalize(&mut self) -> io::Result<()>
+    where
+        W: Unpin,
+    {
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_drain(cx)).await?;
+
+        // Even an empty message carries its header
+        let mut nonce = [0; 1 + X_NONCE_BYTES];
+        loop {
+            let (_, n) = self.en.encrypt(&[], &mut nonce).map_err(Error::from)?;
+            if n == 0 {
+                break;
+            }
+            self.buf.extend_from_slice(&nonce[..n]);
+        }
+        let tag = self
+            .en
+            .tag()
+            .ok_or(CursorError::WrongState)
+            .map_err(Error::from)?;
+        self.buf.extend_from_slice(&tag);
+        self.en = message_cursor(*self.key.expose_secret(), self.x, self.key_version);
+
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_drain(cx)).await?;
+        self.w.flush().await
+    }
+}
+impl<W: AsyncWrite> AsyncWrite for NonceCiphertextTagWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.as_mut().poll_drain(cx))?;
+        let this = self.as_mut().project();
+        let buf = &buf[..buf.len().min(*this.buf_capacity)];
+        this.buf.resize(1 + X_NONCE_BYTES + buf.len(), 0);
+        let (n, produced) = this.en.encrypt(buf, this.buf).map_err(Error::from)?;
+        this.buf.truncate(produced);
+
+        // Send as much as `w` accepts right away
+        let _ = self.poll_drain(cx);
+        Ok(n).into()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_drain(cx))?;
+        self.project().w.poll_flush(cx)
+    }
+
+    /// Shut down the transport without finalizing the current message
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_drain(cx))?;
+        self.project().w.poll_shutdown(cx)
+    }
+}
+impl<W: fmt::Debug> fmt::Debug for NonceCiphertextTagWriter<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NonceCiphertextTagWriter")
+            .field("x", &self.x)
+            .field("key_version", &self.key_version)
+            .field("w", &self.w)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Messages of [`NonceCiphertextTagWriter`] whose lengths are known
+///
+/// Announce each message by [`Self::start_message`], read its plaintext through `AsyncRead` up to EOF,
+/// then check it by [`Self::verify`] before acting on it.
+#[pin_project]
+pub struct TagReader<R> {
+    key: SecretKey,
+    x: bool,
+    /// Expect a key version ahead of each nonce
+    key_version: bool,
+    /// Cursor of the current message
+    de: DecryptCursor,
+    /// Ciphertext of the current message not read yet; `None` between messages
+    remaining: Option<usize>,
+    #[pin]
+    r: R,
+}
+impl<R> TagReader<R> {
+    pub fn new(key: [u8; KEY_BYTES], r: R) -> Self {
+        Self::with_nonce_size(key, false, r)
+    }
+    pub fn new_x(key: [u8; KEY_BYTES], r: R) -> Self {
+        Self::with_nonce_size(key, true, r)
+    }
+    fn with_nonce_size(key: [u8; KEY_BYTES], x: bool, r: R) -> Self {
+        Self {
+            de: message_decrypt_cursor(key, x, false),
+            key: key.into(),
+            x,
+            key_version: false,
+            remaining: None,
+            r,
+        }
+    }
+
+    /// Expect each message to start with the key version of [`NonceCiphertextTagWriter::with_key_version`]
+    pub fn with_key_version(mut self) -> Self {
+        self.key_version = true;
+        self
+    }
+
+    /// Expect a message of `len` bytes of plaintext next
+    ///
+    /// [`Error::MessageUnverified`] until [`Self::verify`] has consumed the current message and its tag.
+    pub fn start_message(&mut self, len: usize) -> Result<(), Error> {
+        if self.remaining.is_some() {
+            return Err(Error::MessageUnverified);
+        }
+        self.de = message_decrypt_cursor(*self.key.expose_secret(), self.x, self.key_version);
+        self.remaining = Some(len);
+        Ok(())
+    }
+
+    /// The key version of the current message once its header is in
+    ///
+    /// Only trust it once [`Self::verify`] succeeds.
+    pub fn key_version(&self) -> Option<KeyVersion> {
+        self.de.key_version()
+    }
+
+    pub fn into_inner(self) -> R {
+        self.r
+    }
+}
+impl<R: AsyncRead + Unpin> TagReader<R> {
+    /// Read the rest of the current message and its tag, and check the tag in constant time
+    ///
+    /// [`Error::TagMismatch`] if the message was forged, corrupted or sealed under another key.
+    pub async fn verify(&mut self) -> io::Result<()> {
+        if self.remaining.is_none() {
+            return Err(Error::NoMessage.into());
+        }
+        // The tag covers the whole ciphertext
+        tokio::io::copy(self, &mut tokio::io::sink()).await?;
+        self.remaining = None;
+
+        let mut tag = [0; TAG_BYTES];
+        self.r
+            .read_exact(&mut tag)
+            .await
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => Error::TagTruncated.into(),
+                _ => e,
+            })?;
+        let expected = self
+            .de
+            .tag()
+            .ok_or(CursorError::WrongState)
+            .map_err(Error::from)?;
+        verify_tag(&expected, &tag).map_err(Error::from)?;
+        Ok(())
+    }
+}
+impl<R: AsyncRead> AsyncRead for TagReader<R> {
+    /// EOF at the end of the current message, with the plaintext not verified yet
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+        let Some(remaining) = this.remaining else {
+            return Ok(()).into();
+        };
+        loop {
+            let version_bytes = usize::from(*this.key_version && this.de.key_version().is_none());
+            let header_bytes =
+                version_bytes + this.de.remaining_nonce_size().map_err(Error::from)?;
+            if header_bytes == 0 {
+                break;
+            }
+            let mut nonce = [0; 1 + X_NONCE_BYTES];
+            let mut b = ReadBuf::new(&mut nonce[..header_bytes]);
+            ready!(this.r.as_mut().poll_read(cx, &mut b))?;
+            let n = b.filled().len();
+            if n == 0 {
+                return Err(Error::NonceTruncated.into()).into();
+            }
+            this.de.decrypt(&mut nonce[..n]).map_err(Error::from)?;
+        }
+
+        let limit = (*remaining).min(buf.remaining());
+        if limit == 0 {
+            return Ok(()).into();
+        }
+        let unfilled = buf.initialize_unfilled_to(limit);
+        let mut b = ReadBuf::new(unfilled);
+        ready!(this.r.as_mut().poll_read(cx, &mut b))?;
+        let n = b.filled().len();
+        if n == 0 {
+            return Err(Error::PayloadTruncated.into()).into();
+        }
+        this.de.decrypt(&mut unfilled[..n]).map_err(Error::from)?;
+        *remaining -= n;
+        buf.advance(n);
+        Ok(()).into()
+    }
+}
+impl<R: fmt::Debug> fmt::Debug for TagReader<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TagReader")
+            .field("x", &self.x)
+            .field("key_version", &self.key_version)
+            .field("remaining", &self.remaining)
+            .field("r", &self.r)
+            .finish_non_exhaustive()
+    }
+}
+
+fn message_decrypt_cursor(key: [u8; KEY_BYTES], x: bool, key_version: bool) -> DecryptCursor {
+    let de = match x {
+        true => DecryptCursor::new_x(key),
+        false => DecryptCursor::new(key),
+    };
+    let de = de.with_aad(&[]);
+    match key_version {
+        true => de.with_key_version(),
+        false => de,
+    }
+}
+
+fn message_cursor(key: [u8; KEY_BYTES], x: bool, key_version: Option<KeyVersion>) -> EncryptCursor {
+    let en = match x {
+        true => EncryptCursor::new_x(key),
+        false => EncryptCursor::new(key),
+    };
+    let en = en.with_aad(&[]);
+    match key_version {
+        Some(version) => en.with_key_version(version),
+        None => en,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{config::tests::create_random_config, cursor::TaggedDecryptCursor};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_finalize() {
+        let config = create_random_config();
+
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut client = NonceCiphertextTagWriter::new_x(*config.key(), client);
+        let msgs: [&[u8]; 3] = [b"Hello", b"", b", world!"];
+        for msg in msgs {
+            client.write_all(msg).await.unwrap();
+            client.finalize().await.unwrap();
+        }
+
+        for msg in msgs {
+            let mut wire = vec![0; X_NONCE_BYTES + msg.len() + TAG_BYTES];
+            server.read_exact(&mut wire).await.unwrap();
+            let mut de = TaggedDecryptCursor::new_x(*config.key(), msg.len()).with_aad(&[]);
+            assert_eq!(de.consume(&wire), Ok(wire.len()));
+            assert_eq!(de.verify(), Ok(Some(msg)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_buf_capacity() {
+        let config = create_random_config();
+
+        let (client, mut server) = tokio::io::duplex(1 << 16);
+        let mut client = NonceCiphertextTagWriter::new(*config.key(), client).with_buf_capacity(16);
+        let msg = [7; 100];
+        assert_eq!(client.write(&msg).await.unwrap(), 16);
+        client.write_all(&msg[16..]).await.unwrap();
+        client.finalize().await.unwrap();
+        assert!(client.buf.capacity() < msg.len());
+
+        let mut reader = TagReader::new(*config.key(), &mut server);
+        reader.start_message(msg.len()).unwrap();
+        let mut buf = vec![];
+        reader.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, msg);
+        reader.verify().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tag_reader() {
+        let config = create_random_config();
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = NonceCiphertextTagWriter::new(*config.key(), client);
+        let mut server = TagReader::new(*config.key(), server);
+        let e = server.verify().await.unwrap_err();
+        assert_eq!(Error::from_io(&e), Some(&Error::NoMessage));
+        let msgs: [&[u8]; 3] = [b"Hello", b"", b", world!"];
+        for msg in msgs {
+            client.write_all(msg).await.unwrap();
+            client.finalize().await.unwrap();
+        }
+
+        for msg in msgs {
+            server.start_message(msg.len()).unwrap();
+            let mut buf = vec![];
+            server.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, msg);
+            assert_eq!(server.start_message(1), Err(Error::MessageUnverified));
+            server.verify().await.unwrap();
+        }
+
+        // A message of another length leaves the tag off
+        client.write_all(b"Hello").await.unwrap();
+        client.finalize().await.unwrap();
+        server.start_message(4).unwrap();
+        let e = server.verify().await.unwrap_err();
+        assert_eq!(Error::from_io(&e), Some(&Error::TagMismatch));
+    }
+
+    #[tokio::test]
+    async fn test_key_version() {
+        let config = create_random_config();
+
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut client = NonceCiphertextTagWriter::new(*config.key(), client).with_key_version(3);
+        for _ in 0..2 {
+            client.write_all(b"Hello").await.unwrap();
+            client.finalize().await.unwrap();
+        }
+
+        let mut reader = TagReader::new(*config.key(), &mut server).with_key_version();
+        reader.start_message(5).unwrap();
+        let mut buf = vec![];
+        reader.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"Hello");
+        reader.verify().await.unwrap();
+        assert_eq!(reader.key_version(), Some(3));
+
+        // A forged version fails the tag
+        let mut wire = [0; 1 + X_NONCE_BYTES + 5 + TAG_BYTES];
+        let n = 1 + crate::NONCE_BYTES + 5 + TAG_BYTES;
+        server.read_exact(&mut wire[..n]).await.unwrap();
+        wire[0] = 4;
+        let mut reader = TagReader::new(*config.key(), &wire[..n]).with_key_version();
+        reader.start_message(5).unwrap();
+        let e = reader.verify().await.unwrap_err();
+        assert_eq!(reader.key_version(), Some(4));
+        assert_eq!(Error::from_io(&e), Some(&Error::TagMismatch));
+    }
+}
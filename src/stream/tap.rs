@@ -0,0 +1,155 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use pin_project::pin_project;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Read,
+    Write,
+}
+
+/// Hand every chunk read from or written to `T` to `f`
+///
+/// Wrap the inner stream to see the ciphertext or wrap a [`super::ReadHalf`]/[`super::WriteHalf`] to see the plaintext.
+#[pin_project]
+#[derive(Debug)]
+pub struct Tap<T, F> {
+    #[pin]
+    inner: T,
+    f: F,
+}
+impl<T, F> Tap<T, F>
+where
+    F: FnMut(Direction, &[u8]),
+{
+    pub fn new(inner: T, f: F) -> Self {
+        Self { inner, f }
+    }
+}
+impl<T, F> Tap<T, F> {
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+impl<T, F> AsyncRead for Tap<T, F>
+where
+    T: AsyncRead,
+    F: FnMut(Direction, &[u8]),
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let start = buf.filled().len();
+        ready!(this.inner.poll_read(cx, buf))?;
+        let chunk = &buf.filled()[start..];
+        if !chunk.is_empty() {
+            (this.f)(Direction::Read, chunk);
+        }
+        Ok(()).into()
+    }
+}
+impl<T, F> AsyncWrite for Tap<T, F>
+where
+    T: AsyncWrite,
+    F: FnMut(Direction, &[u8]),
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let n = ready!(this.inner.poll_write(cx, buf))?;
+        if n != 0 {
+            (this.f)(Direction::Write, &buf[..n]);
+        }
+        Ok(n).into()
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let n = ready!(this.inner.poll_write_vectored(cx, bufs))?;
+        let mut rest = n;
+        for buf in bufs {
+            if rest == 0 {
+                break;
+            }
+            let len = buf.len().min(rest);
+            if len != 0 {
+                (this.f)(Direction::Write, &buf[..len]);
+            }
+            rest -= len;
+        }
+        Ok(n).into()
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{
+        config::tests::create_random_config,
+        stream::{ReadHalf, WriteHalf},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_tap() {
+        let config = create_random_config();
+        let msg = b"hello world";
+
+        let mut ciphertext: Vec<u8> = vec![];
+        let mut plaintext: Vec<u8> = vec![];
+        let (client, server) = tokio::io::duplex(1024);
+        {
+            let client = Tap::new(client, |d, b: &[u8]| {
+                assert_eq!(d, Direction::Write);
+                ciphertext.extend(b);
+            });
+            let mut client = WriteHalf::new(*config.key(), client);
+            client.write_all(msg).await.unwrap();
+            client.flush().await.unwrap();
+        }
+
+        let server = ReadHalf::new(*config.key(), server);
+        let mut server = Tap::new(server, |d, b: &[u8]| {
+            assert_eq!(d, Direction::Read);
+            plaintext.extend(b);
+        });
+        let mut buf = [0; 11];
+        server.read_exact(&mut buf).await.unwrap();
+
+        assert_eq!(ciphertext.len(), 12 + msg.len());
+        assert_ne!(&ciphertext[12..], msg);
+        assert_eq!(plaintext, msg);
+    }
+}
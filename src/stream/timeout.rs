@@ -0,0 +1,198 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use pin_project::pin_project;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Instant, Sleep},
+};
+
+use super::Error;
+
+/// Fail reads with [`io::ErrorKind::TimedOut`] once `R` makes no progress for a while
+///
+/// Wrap either the inner stream or a [`super::ReadHalf`].
+#[pin_project]
+#[derive(Debug)]
+pub struct TimeoutReader<R> {
+    #[pin]
+    r: R,
+    idle: Idle,
+}
+impl<R> TimeoutReader<R> {
+    pub fn new(r: R, timeout: Duration) -> Self {
+        Self {
+            r,
+            idle: Idle::new(timeout),
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.idle.timeout
+    }
+    pub fn get_ref(&self) -> &R {
+        &self.r
+    }
+    pub fn into_inner(self) -> R {
+        self.r
+    }
+}
+impl<R: AsyncRead> AsyncRead for TimeoutReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let res = this.r.poll_read(cx, buf);
+        this.idle.poll(cx, res)
+    }
+}
+
+/// Fail writes, flushes and shutdowns with [`io::ErrorKind::TimedOut`] once `W` makes no progress for a while
+///
+/// Wrap either the inner stream or a [`super::WriteHalf`].
+#[pin_project]
+#[derive(Debug)]
+pub struct TimeoutWriter<W> {
+    #[pin]
+    w: W,
+    idle: Idle,
+}
+impl<W> TimeoutWriter<W> {
+    pub fn new(w: W, timeout: Duration) -> Self {
+        Self {
+            w,
+            idle: Idle::new(timeout),
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.idle.timeout
+    }
+    pub fn get_ref(&self) -> &W {
+        &self.w
+    }
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+}
+impl<W: AsyncWrite> AsyncWrite for TimeoutWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let res = this.w.poll_write(cx, buf);
+        this.idle.poll(cx, res)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let res = this.w.poll_write_vectored(cx, bufs);
+        this.idle.poll(cx, res)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.w.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let res = this.w.poll_flush(cx);
+        this.idle.poll(cx, res)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let res = this.w.poll_shutdown(cx);
+        this.idle.poll(cx, res)
+    }
+}
+
+/// The timer is armed only while the inner stream is pending
+#[derive(Debug)]
+struct Idle {
+    timeout: Duration,
+    /// Boxed to keep the wrappers `Unpin`
+    sleep: Pin<Box<Sleep>>,
+    armed: bool,
+}
+impl Idle {
+    fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            sleep: Box::pin(tokio::time::sleep(timeout)),
+            armed: false,
+        }
+    }
+
+    fn poll<T>(&mut self, cx: &mut Context<'_>, res: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        if res.is_ready() {
+            self.armed = false;
+            return res;
+        }
+        if !self.armed {
+            self.sleep.as_mut().reset(Instant::now() + self.timeout);
+            self.armed = true;
+        }
+        if self.sleep.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        self.armed = false;
+        Poll::Ready(Err(Error::Timeout.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{
+        config::tests::create_random_config,
+        stream::{ReadHalf, WriteHalf},
+    };
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timeout() {
+        let config = create_random_config();
+        let timeout = Duration::from_secs(1);
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = TimeoutWriter::new(WriteHalf::new(*config.key(), client), timeout);
+        let mut server = TimeoutReader::new(ReadHalf::new(*config.key(), server), timeout);
+
+        let msg = b"hello";
+        client.write_all(msg).await.unwrap();
+        client.flush().await.unwrap();
+        let mut buf = [0; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, msg);
+
+        // Progress keeps the stream alive
+        tokio::time::sleep(timeout * 2).await;
+        client.write_all(msg).await.unwrap();
+        client.flush().await.unwrap();
+        server.read_exact(&mut buf).await.unwrap();
+
+        let err = server.read_exact(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // The peer never reads
+        client.write_all(&[0; 2048]).await.unwrap();
+        let err = client.flush().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
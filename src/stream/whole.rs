@@ -1,14 +1,31 @@
-use std::pin::Pin;
+use std::{io, pin::Pin};
 
-use tokio::io::{AsyncRead, AsyncWrite};
+use pin_project::pin_project;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 
 use crate::KEY_BYTES;
 
-use super::{read::ReadHalf, write::WriteHalf};
+use super::{read::ReadHalf, write::WriteHalf, ReadState, WriteState};
 
+/// A matching reader and writer of `key`, each with its own random nonce
+pub fn pair<R, W>(key: [u8; KEY_BYTES], r: R, w: W) -> (ReadHalf<R>, WriteHalf<W>) {
+    (ReadHalf::new(key, r), WriteHalf::new(key, w))
+}
+
+/// [`pair`] encrypting under subkeys of `key` and each nonce; see [`WriteState::with_subkey`]
+pub fn subkey_pair<R, W>(key: [u8; KEY_BYTES], r: R, w: W) -> (ReadHalf<R>, WriteHalf<W>) {
+    (
+        ReadHalf::from_state(ReadState::new(key).with_subkey(), r),
+        WriteHalf::from_state(WriteState::new(key).with_subkey(), w),
+    )
+}
+
+#[pin_project]
 #[derive(Debug)]
 pub struct WholeStream<R, W> {
+    #[pin]
     r: ReadHalf<R>,
+    #[pin]
     w: WriteHalf<W>,
 }
 impl<R, W> WholeStream<R, W> {
@@ -17,40 +34,93 @@ impl<R, W> WholeStream<R, W> {
     }
 
     pub fn from_key_halves(key: [u8; KEY_BYTES], r: R, w: W) -> Self {
-        let r = ReadHalf::new(key, r);
-        let w = WriteHalf::new(key, w);
+        let (r, w) = pair(key, r, w);
         Self { r, w }
     }
+
+    pub fn into_split(self) -> (ReadHalf<R>, WriteHalf<W>) {
+        (self.r, self.w)
+    }
+
+    pub fn split(&mut self) -> (&mut ReadHalf<R>, &mut WriteHalf<W>) {
+        (&mut self.r, &mut self.w)
+    }
+
+    pub(crate) fn read_half_mut(self: Pin<&mut Self>) -> Pin<&mut ReadHalf<R>> {
+        self.project().r
+    }
+    pub(crate) fn write_half_mut(self: Pin<&mut Self>) -> Pin<&mut WriteHalf<W>> {
+        self.project().w
+    }
 }
-impl<R: AsyncRead + Unpin, W: Unpin> AsyncRead for WholeStream<R, W> {
+impl<S> WholeStream<tokio::io::ReadHalf<S>, tokio::io::WriteHalf<S>>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    /// Split `stream` and exchange 12-byte nonces with a peer doing the same
+    pub async fn from_stream(key: [u8; KEY_BYTES], stream: S) -> io::Result<Self> {
+        let (r, w) = tokio::io::split(stream);
+        Self::exchange_nonces(ReadHalf::new(key, r), WriteHalf::new(key, w)).await
+    }
+
+    /// Split `stream` and exchange 24-byte nonces with a peer doing the same
+    pub async fn from_stream_x(key: [u8; KEY_BYTES], stream: S) -> io::Result<Self> {
+        let (r, w) = tokio::io::split(stream);
+        Self::exchange_nonces(ReadHalf::new_x(key, r), WriteHalf::new_x(key, w)).await
+    }
+
+    /// Both sides send their nonce before reading the peer's so neither waits on the other
+    async fn exchange_nonces(
+        mut r: ReadHalf<tokio::io::ReadHalf<S>>,
+        mut w: WriteHalf<tokio::io::WriteHalf<S>>,
+    ) -> io::Result<Self> {
+        w.write_nonce().await?;
+        w.flush().await?;
+        r.read_nonce().await?;
+        Ok(Self::new(r, w))
+    }
+}
+impl<R: AsyncRead, W> AsyncRead for WholeStream<R, W> {
     fn poll_read(
-        mut self: std::pin::Pin<&mut Self>,
+        self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> std::task::Poll<std::io::Result<()>> {
-        Pin::new(&mut self.r).poll_read(cx, buf)
+        self.read_half_mut().poll_read(cx, buf)
     }
 }
-impl<R: Unpin, W: AsyncWrite + Unpin> AsyncWrite for WholeStream<R, W> {
+impl<R, W: AsyncWrite> AsyncWrite for WholeStream<R, W> {
     fn poll_write(
-        mut self: Pin<&mut Self>,
+        self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
         buf: &[u8],
     ) -> std::task::Poll<Result<usize, std::io::Error>> {
-        Pin::new(&mut self.w).poll_write(cx, buf)
+        self.write_half_mut().poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> std::task::Poll<Result<usize, std::io::Error>> {
+        self.write_half_mut().poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.w.is_write_vectored()
     }
 
     fn poll_flush(
-        mut self: Pin<&mut Self>,
+        self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), std::io::Error>> {
-        Pin::new(&mut self.w).poll_flush(cx)
+        self.write_half_mut().poll_flush(cx)
     }
 
     fn poll_shutdown(
-        mut self: Pin<&mut Self>,
+        self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), std::io::Error>> {
-        Pin::new(&mut self.w).poll_shutdown(cx)
+        self.write_half_mut().poll_shutdown(cx)
     }
 }
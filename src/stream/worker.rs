@@ -0,0 +1,257 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use bytes::{Buf, Bytes, BytesMut};
+use futures_sink::Sink;
+use pin_project::pin_project;
+use tokio::{io::AsyncWrite, sync::mpsc};
+use tokio_util::{
+    codec::{Encoder, LengthDelimitedCodec},
+    sync::PollSender,
+};
+
+use crate::{cursor::EncryptCursor, KEY_BYTES, X_NONCE_BYTES};
+
+use super::Error;
+
+/// Messages that can wait on the worker in each direction
+const DEFAULT_CHANNEL_CAPACITY: usize = 16;
+
+/// [`MessageSink`](super::MessageSink) that encrypts in a worker task
+///
+/// Messages go to the worker and come back as ciphertext over bounded channels.
+/// The worker hands whatever messages are queued to the blocking thread pool of tokio,
+/// so the keystream of one hot connection is computed off the core driving its IO
+/// while an idle connection holds no thread.
+/// The wire format is the same, so [`MessageStream`](super::MessageStream) reads it.
+#[pin_project]
+#[derive(Debug)]
+pub struct WorkerMessageSink<W> {
+    plaintext: PollSender<Bytes>,
+    /// Ciphertext of each message or the error that stopped the worker
+    ciphertext: mpsc::Receiver<Result<Bytes, Error>>,
+    /// Ciphertext partially written to `w`
+    writing: Bytes,
+    /// Messages sent to the worker whose ciphertext has not been taken yet
+    in_flight: usize,
+    max_frame_length: usize,
+    #[pin]
+    w: W,
+}
+impl<W: AsyncWrite> WorkerMessageSink<W> {
+    /// Fail outside of a tokio runtime
+    pub fn new(key: [u8; KEY_BYTES], w: W) -> io::Result<Self> {
+        Self::with_cursor(EncryptCursor::new(key), w, DEFAULT_CHANNEL_CAPACITY)
+    }
+    /// Fail outside of a tokio runtime
+    pub fn new_x(key: [u8; KEY_BYTES], w: W) -> io::Result<Self> {
+        Self::with_cursor(EncryptCursor::new_x(key), w, DEFAULT_CHANNEL_CAPACITY)
+    }
+
+    /// `capacity`: Messages that can wait on the worker in each direction
+    ///
+    /// Fail outside of a tokio runtime or with [`Error::Cursor`] if `en` cannot emit its header.
+    pub fn with_cursor(mut en: EncryptCursor, w: W, capacity: usize) -> io::Result<Self> {
+        let runtime = tokio::runtime::Handle::try_current().map_err(io::Error::other)?;
+
+        // The prefix and the nonce go in front of the first message
+        let mut header = BytesMut::new();
+        let mut buf = [0; X_NONCE_BYTES];
+        loop {
+            let (_, n) = en.encrypt(&[], &mut buf).map_err(Error::Cursor)?;
+            if n == 0 {
+                break;
+            }
+            header.extend_from_slice(&buf[..n]);
+        }
+
+        let codec = LengthDelimitedCodec::new();
+        let max_frame_length = codec.max_frame_length();
+        let (plaintext, plaintext_rx) = mpsc::channel(capacity);
+        let (ciphertext_tx, ciphertext) = mpsc::channel(capacity);
+        runtime.spawn(encrypt_worker(
+            en,
+            header,
+            codec,
+            plaintext_rx,
+            ciphertext_tx,
+        ));
+        Ok(Self {
+            plaintext: PollSender::new(plaintext),
+            ciphertext,
+            writing: Bytes::new(),
+            in_flight: 0,
+            max_frame_length,
+            w,
+        })
+    }
+
+    /// Write out the ciphertext of every message sent so far
+    fn poll_drain(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+        loop {
+            while !this.writing.is_empty() {
+                let n = ready!(this.w.as_mut().poll_write(cx, this.writing))?;
+                if n == 0 {
+                    return Err(io::ErrorKind::WriteZero.into()).into();
+                }
+                this.writing.advance(n);
+            }
+            if *this.in_flight == 0 {
+                return Ok(()).into();
+            }
+            let Some(ciphertext) = ready!(this.ciphertext.poll_recv(cx)) else {
+                return Err(Error::WorkerStopped.into()).into();
+            };
+            *this.writing = ciphertext?;
+            *this.in_flight -= 1;
+        }
+    }
+}
+impl<W> WorkerMessageSink<W> {
+    /// Ciphertext still held by the worker is dropped
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+}
+impl<W: AsyncWrite> Sink<Bytes> for WorkerMessageSink<W> {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // Keep the ciphertext flowing so that the worker never waits on a full channel
+        if let Poll::Ready(Err(e)) = self.as_mut().poll_drain(cx) {
+            return Err(e).into();
+        }
+        ready!(self.project().plaintext.poll_reserve(cx)).map_err(|_| Error::WorkerStopped)?;
+        Ok(()).into()
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Bytes) -> Result<(), Self::Error> {
+        let this = self.project();
+        if *this.max_frame_length < item.len() {
+            return Err(Error::FrameTooLarge(item.len()).into());
+        }
+        this.plaintext
+            .send_item(item)
+            .map_err(|_| Error::WorkerStopped)?;
+        *this.in_flight += 1;
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.as_mut().poll_drain(cx))?;
+        self.project().w.poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        let this = self.project();
+        this.plaintext.close();
+        this.w.poll_shutdown(cx)
+    }
+}
+
+/// Frame and encrypt messages until either side hangs up or the cursor fails
+///
+/// `frame`: The header of `en` that goes in front of the first message
+async fn encrypt_worker(
+    mut en: EncryptCursor,
+    mut frame: BytesMut,
+    mut codec: LengthDelimitedCodec,
+    mut plaintext: mpsc::Receiver<Bytes>,
+    ciphertext: mpsc::Sender<Result<Bytes, Error>>,
+) {
+    let mut msgs = Vec::new();
+    while plaintext
+        .recv_many(&mut msgs, plaintext.max_capacity())
+        .await
+        != 0
+    {
+        // The cursor travels with the job so that messages keep their order
+        let job = tokio::task::spawn_blocking(move || {
+            let mut encrypted = Vec::with_capacity(msgs.len());
+            for msg in msgs.drain(..) {
+                let start = frame.len();
+                codec
+                    .encode(msg, &mut frame)
+                    .expect("frame size checked by `start_send`");
+                if let Err(e) = en.encrypt_in_place(&mut frame[start..]) {
+                    encrypted.push(Err(Error::Cursor(e)));
+                    break;
+                }
+                encrypted.push(Ok(frame.split().freeze()));
+            }
+            (en, codec, frame, msgs, encrypted)
+        });
+        // A failed job drops the channels and the sink reports the worker as stopped
+        let Ok(state) = job.await else {
+            return;
+        };
+        let encrypted;
+        (en, codec, frame, msgs, encrypted) = state;
+        for c in encrypted {
+            let failed = c.is_err();
+            if ciphertext.send(c).await.is_err() || failed {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{SinkExt, StreamExt};
+
+    use crate::{config::tests::create_random_config, cursor::CursorError, stream::ReadHalf};
+
+    use super::{super::MessageStream, *};
+
+    #[tokio::test]
+    async fn test_worker_messages() {
+        let config = create_random_config();
+
+        // Smaller than a batch of messages so that both channels fill up
+        let (client, server) = tokio::io::duplex(64);
+        let sink =
+            WorkerMessageSink::with_cursor(EncryptCursor::new(*config.key()), client, 2).unwrap();
+        let mut stream = MessageStream::new(ReadHalf::new(*config.key(), server));
+
+        let msgs: Vec<Bytes> = (0..64).map(|i| Bytes::from(vec![i as u8; i * 7])).collect();
+        let send = tokio::spawn({
+            let msgs = msgs.clone();
+            async move {
+                let mut sink = sink;
+                for msg in msgs {
+                    sink.feed(msg).await.unwrap();
+                }
+                sink.close().await.unwrap();
+            }
+        });
+        for msg in msgs {
+            assert_eq!(stream.next().await.unwrap().unwrap(), msg);
+        }
+        assert!(stream.next().await.is_none());
+        send.await.unwrap();
+    }
+
+    #[test]
+    fn test_no_runtime() {
+        let config = create_random_config();
+        let sink = WorkerMessageSink::new(*config.key(), tokio::io::sink());
+        assert!(sink.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_header_error() {
+        let config = create_random_config();
+        let en = EncryptCursor::new(*config.key()).with_key_version(1);
+        let err = WorkerMessageSink::with_cursor(en, tokio::io::sink(), 2).unwrap_err();
+        assert_eq!(
+            Error::from_io(&err),
+            Some(&Error::Cursor(CursorError::KeyVersionWithoutAad))
+        );
+    }
+}
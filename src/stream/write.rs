@@ -1,47 +1,160 @@
 use std::{
+    io::{self, IoSlice},
     pin::Pin,
-    task::{ready, Poll},
+    task::{ready, Context, Poll},
 };
 
+use arrayvec::ArrayVec;
+use pin_project::pin_project;
 use tokio::io::AsyncWrite;
 
+#[cfg(feature = "parallel")]
+use crate::cipher::ParallelismConfig;
 use crate::{
-    cursor::{NonceReadCursor, ReadCursorState},
-    KEY_BYTES,
+    cipher::MAX_KEYSTREAM_BYTES,
+    config::{derive_subkey, STREAM_SUBKEY_INFO},
+    cursor::{NonceReadCursor, ReadCursorState, UserDataCursor},
+    KEY_BYTES, X_NONCE_BYTES,
 };
 
+use super::{offload, BufferPool, Error, ReadState};
+
+const DEFAULT_BUF_BYTES: usize = 64 * 1024;
+
+/// IO-agnostic state machine of an encrypting writer
 #[derive(Debug)]
-pub struct WriteHalf<W> {
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WriteState {
     cursor: Option<ReadCursorState>,
-    w: W,
-    buf: Option<Vec<u8>>,
+    nonce: ArrayVec<u8, X_NONCE_BYTES>,
+    /// Ciphertext not yet accepted by the writer
+    buf: Vec<u8>,
+    pos: usize,
+    buf_capacity: usize,
+    /// Shut down so that nothing can follow the ciphertext already sent
+    #[cfg_attr(feature = "serde", serde(default))]
+    closed: bool,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pool: Option<BufferPool>,
+    #[cfg(feature = "parallel")]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    par: Option<ParallelismConfig>,
 }
-impl<W> WriteHalf<W> {
-    pub fn new(key: [u8; KEY_BYTES], w: W) -> Self {
+impl WriteState {
+    pub fn new(key: [u8; KEY_BYTES]) -> Self {
         let cursor = NonceReadCursor::new(key);
-        let cursor = Some(ReadCursorState::Nonce(cursor));
-        let buf = Some(vec![]);
-        Self { cursor, w, buf }
+        Self::from_cursor(cursor)
     }
-    pub fn new_x(key: [u8; KEY_BYTES], w: W) -> Self {
+    pub fn new_x(key: [u8; KEY_BYTES]) -> Self {
         let cursor = NonceReadCursor::new_x(key);
-        let cursor = Some(ReadCursorState::Nonce(cursor));
-        let buf = Some(vec![]);
-        Self { cursor, w, buf }
+        Self::from_cursor(cursor)
     }
-}
-impl<W: AsyncWrite + Unpin> AsyncWrite for WriteHalf<W> {
-    fn poll_write(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
+    fn from_cursor(cursor: NonceReadCursor) -> Self {
+        #[cfg(feature = "nonce-tracking")]
+        crate::nonce_tracker::record(cursor.key(), cursor.nonce());
+        Self {
+            nonce: cursor.nonce().try_into().unwrap(),
+            cursor: Some(ReadCursorState::Nonce(cursor)),
+            buf: vec![],
+            pos: 0,
+            buf_capacity: DEFAULT_BUF_BYTES,
+            closed: false,
+            pool: None,
+            #[cfg(feature = "parallel")]
+            par: None,
+        }
+    }
+
+    /// Encrypt with a subkey derived from the key and the nonce so that the key itself never touches user data
+    ///
+    /// The reader has to call [`ReadState::with_subkey`](super::ReadState::with_subkey) as well.
+    pub fn with_subkey(mut self) -> Self {
+        if let Some(ReadCursorState::Nonce(c)) = &mut self.cursor {
+            let subkey = derive_subkey(c.key(), c.nonce(), STREAM_SUBKEY_INFO);
+            c.set_key(subkey);
+        }
+        self
+    }
+
+    /// Cap the plaintext claimed by one write to `bytes`
+    ///
+    /// The allocation of the inner buffer is reused across writes and never grows past `bytes`.
+    pub fn with_buf_capacity(mut self, bytes: usize) -> Self {
+        assert!(bytes > 0);
+        self.buf_capacity = bytes;
+        self
+    }
+
+    /// Tune how large writes are encrypted in parallel
+    #[cfg(feature = "parallel")]
+    pub fn with_parallelism(mut self, par: ParallelismConfig) -> Self {
+        self.par = Some(par);
+        self.apply_parallelism();
+        self
+    }
+    fn apply_parallelism(&mut self) {
+        #[cfg(feature = "parallel")]
+        if let (Some(ReadCursorState::UserData(c)), Some(par)) = (&mut self.cursor, &self.par) {
+            c.set_parallelism(par.clone());
+        }
+    }
+
+    /// Check the inner buffer out of `pool` only while it holds ciphertext
+    pub fn with_buffer_pool(mut self, pool: BufferPool) -> Self {
+        pool.put(core::mem::take(&mut self.buf));
+        self.pool = Some(pool);
+        self
+    }
+
+    /// The nonce prefixing the ciphertext
+    pub fn nonce(&self) -> &[u8] {
+        &self.nonce
+    }
+
+    /// A reader of the ciphertext encrypted after this point
+    ///
+    /// Ciphertext already held by the state is not covered.
+    /// The state is not `Clone` since a copy would encrypt with the same keystream, so the fork can only decrypt.
+    /// `None` until the whole nonce is sent and while the keystream is out with an offloaded job.
+    pub fn fork(&self) -> Option<ReadState> {
+        match &self.cursor {
+            Some(ReadCursorState::UserData(c)) => {
+                Some(ReadState::from_keystream(c.clone(), &self.nonce))
+            }
+            _ => None,
+        }
+    }
+
+    /// Keystream bytes left before the block counter wraps once the whole nonce is sent
+    pub fn remaining_keystream(&self) -> Option<u64> {
+        match &self.cursor {
+            Some(ReadCursorState::UserData(c)) => Some(c.remaining()),
+            _ => None,
+        }
+    }
+
+    /// Encrypt `buf` and pass the ciphertext to `write`
+    ///
+    /// Return the amount of bytes consumed from `buf`.
+    pub fn poll(
+        &mut self,
         buf: &[u8],
-    ) -> std::task::Poll<Result<usize, std::io::Error>> {
+        write: impl FnMut(&[u8]) -> Poll<io::Result<usize>>,
+    ) -> Poll<io::Result<usize>> {
+        self.poll_vectored(&[IoSlice::new(buf)], write)
+    }
+
+    /// Pass the whole nonce to `write`
+    pub fn poll_nonce(
+        &mut self,
+        mut write: impl FnMut(&[u8]) -> Poll<io::Result<usize>>,
+    ) -> Poll<io::Result<()>> {
         // Loop for state transitions from `Nonce` to `UserData`
         loop {
             match self.cursor.take().unwrap() {
                 ReadCursorState::Nonce(c) => {
-                    // Write nonce to `w`
-                    let ready = Pin::new(&mut self.w).poll_write(cx, c.remaining_nonce());
+                    // Write nonce to the writer
+                    let ready = write(c.remaining_nonce());
 
                     // Mark part of the nonce as read
                     // And return the cursor
@@ -50,56 +163,550 @@ impl<W: AsyncWrite + Unpin> AsyncWrite for WriteHalf<W> {
                     } else {
                         ReadCursorState::Nonce(c)
                     });
+                    self.apply_parallelism();
 
                     // Raise exception on either `Err` or `Pending`
-                    let _ = ready!(ready)?;
-                }
-                ReadCursorState::UserData(mut c) => {
-                    // Reuse the inner buffer
-                    let mut inner_buf = self.buf.take().unwrap();
-
-                    // Fill the inner buffer with encrypted data if it's empty
-                    if inner_buf.is_empty() {
-                        inner_buf.extend(buf);
-                        c.xor(&mut inner_buf);
+                    if ready!(ready)? == 0 {
+                        return Err(io::ErrorKind::WriteZero.into()).into();
                     }
-
-                    // Return the cursor
+                }
+                ReadCursorState::UserData(c) => {
                     self.cursor = Some(ReadCursorState::UserData(c));
+                    return Ok(()).into();
+                }
+            }
+        }
+    }
 
-                    // Try to write `w` with the inner buffer
-                    let ready = Pin::new(&mut self.w).poll_write(cx, &inner_buf);
+    /// Pass the unsent nonce and the ciphertext left over by previous writes to `write`
+    ///
+    /// Once ready, everything encrypted so far has been accepted by `write`.
+    pub fn poll_drain(
+        &mut self,
+        mut write: impl FnMut(&[u8]) -> Poll<io::Result<usize>>,
+    ) -> Poll<io::Result<()>> {
+        ready!(self.poll_nonce(&mut write))?;
+        while self.pos < self.buf.len() {
+            let amt = ready!(write(&self.buf[self.pos..]))?;
+            if amt == 0 {
+                return Err(io::ErrorKind::WriteZero.into()).into();
+            }
+            self.pos += amt;
+        }
 
-                    // Remove the consumed data from the inner buffer
-                    if let Poll::Ready(Ok(amt)) = ready {
-                        inner_buf.drain(0..amt);
-                    }
+        // Keep the allocation for the next write
+        match &self.pool {
+            Some(pool) => pool.put(core::mem::take(&mut self.buf)),
+            None => self.buf.clear(),
+        }
+        self.pos = 0;
+        Ok(()).into()
+    }
 
-                    // Return the inner buffer
-                    self.buf = Some(inner_buf);
+    /// [`Self::poll_drain`] and refuse any further write with [`Error::Closed`]
+    pub fn poll_shutdown(
+        &mut self,
+        write: impl FnMut(&[u8]) -> Poll<io::Result<usize>>,
+    ) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(write))?;
+        self.closed = true;
+        Ok(()).into()
+    }
 
-                    let _ = ready!(ready)?;
+    /// Whether [`Self::poll_shutdown`] has completed
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
 
-                    // Do not allow caller to switch buffers until the inner buffer is fully consumed
-                    if self.buf.as_ref().unwrap().is_empty() {
-                        return Ok(buf.len()).into();
-                    }
-                }
+    /// Encrypt `bufs` into one contiguous ciphertext and pass it to `write`
+    ///
+    /// Return the amount of bytes consumed from `bufs`, at most the buffer capacity.
+    ///
+    /// Cancel safe: `bufs` is only consumed after the ciphertext of previous writes has been drained.
+    /// Once consumed, the ciphertext `write` does not accept yet stays in the state until the next call or [`Self::poll_drain`];
+    /// errors from `write` at that point are reported by that later call.
+    pub fn poll_vectored(
+        &mut self,
+        bufs: &[IoSlice<'_>],
+        mut write: impl FnMut(&[u8]) -> Poll<io::Result<usize>>,
+    ) -> Poll<io::Result<usize>> {
+        if self.closed {
+            return Err(Error::Closed.into()).into();
+        }
+        ready!(self.poll_drain(&mut write))?;
+        let n = self.claim(bufs);
+        if n == 0 {
+            let len: u64 = bufs.iter().map(|b| b.len() as u64).sum();
+            if len != 0 {
+                let remaining = self.remaining_keystream().unwrap_or(MAX_KEYSTREAM_BYTES);
+                let end = MAX_KEYSTREAM_BYTES - remaining + len;
+                return Err(Error::KeystreamExhausted(end).into()).into();
             }
+            return Ok(0).into();
+        }
+        let Some(ReadCursorState::UserData(c)) = &mut self.cursor else {
+            unreachable!();
+        };
+        c.xor(&mut self.buf);
+
+        // Send as much ciphertext as `write` accepts right away
+        let _ = self.poll_drain(write);
+        Ok(n).into()
+    }
+
+    /// Amount of bytes the next write claims from `bufs`
+    ///
+    /// Never more than the keystream covers.
+    pub(crate) fn claimable(&self, bufs: &[IoSlice<'_>]) -> usize {
+        let n = bufs
+            .iter()
+            .map(|b| b.len())
+            .sum::<usize>()
+            .min(self.buf_capacity);
+        match self.remaining_keystream() {
+            Some(remaining) => n.min(usize::try_from(remaining).unwrap_or(usize::MAX)),
+            None => n,
+        }
+    }
+
+    /// Copy the plaintext the next write claims from `bufs` into the drained inner buffer
+    fn claim(&mut self, bufs: &[IoSlice<'_>]) -> usize {
+        assert!(self.buf.is_empty());
+        let n = self.claimable(bufs);
+        if n == 0 {
+            return 0;
+        }
+        if let Some(pool) = &self.pool {
+            self.buf = pool.get();
+        }
+        self.buf.reserve_exact(n);
+        let mut remaining = n;
+        for b in bufs {
+            let b = &b[..b.len().min(remaining)];
+            self.buf.extend_from_slice(b);
+            remaining -= b.len();
         }
+        n
     }
 
-    fn poll_flush(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Result<(), std::io::Error>> {
-        Pin::new(&mut self.w).poll_flush(cx)
+    /// Move the keystream and the plaintext claimed from `bufs` out to be encrypted elsewhere
+    ///
+    /// The state must be drained and stays unusable until [`Self::put_ciphertext`].
+    pub(crate) fn take_plaintext(
+        &mut self,
+        bufs: &[IoSlice<'_>],
+    ) -> (UserDataCursor, Vec<u8>, usize) {
+        let n = self.claim(bufs);
+        let Some(ReadCursorState::UserData(c)) = self.cursor.take() else {
+            unreachable!();
+        };
+        (c, core::mem::take(&mut self.buf), n)
+    }
+
+    /// Return the keystream and the ciphertext encrypted elsewhere to be drained
+    pub(crate) fn put_ciphertext(&mut self, c: UserDataCursor, buf: Vec<u8>) {
+        self.cursor = Some(ReadCursorState::UserData(c));
+        self.buf = buf;
+        self.pos = 0;
+    }
+}
+
+#[pin_project]
+#[derive(Debug)]
+pub struct WriteHalf<W> {
+    state: WriteState,
+    #[pin]
+    w: W,
+    offload_threshold: Option<usize>,
+    job: Option<offload::Job>,
+}
+impl<W> WriteHalf<W> {
+    pub fn new(key: [u8; KEY_BYTES], w: W) -> Self {
+        Self::from_state(WriteState::new(key), w)
+    }
+    pub fn new_x(key: [u8; KEY_BYTES], w: W) -> Self {
+        Self::from_state(WriteState::new_x(key), w)
+    }
+    /// Resume from a checkpointed `state`
+    pub fn from_state(state: WriteState, w: W) -> Self {
+        Self {
+            state,
+            w,
+            offload_threshold: None,
+            job: None,
+        }
+    }
+
+    /// Cap the plaintext claimed by one write to `bytes`
+    pub fn with_buf_capacity(mut self, bytes: usize) -> Self {
+        self.state = self.state.with_buf_capacity(bytes);
+        self
+    }
+
+    /// Share the inner buffer with other streams through `pool`
+    pub fn with_buffer_pool(mut self, pool: BufferPool) -> Self {
+        self.state = self.state.with_buffer_pool(pool);
+        self
+    }
+
+    /// Tune how large writes are encrypted in parallel
+    #[cfg(feature = "parallel")]
+    pub fn with_parallelism(mut self, par: ParallelismConfig) -> Self {
+        self.state = self.state.with_parallelism(par);
+        self
+    }
+
+    /// Encrypt writes claiming more than `bytes` on the blocking thread pool of tokio
+    ///
+    /// Only the tokio `AsyncWrite` impl offloads.
+    /// A write claims at most the buffer capacity; see [`Self::with_buf_capacity`].
+    pub fn with_offload_threshold(mut self, bytes: usize) -> Self {
+        self.offload_threshold = Some(bytes);
+        self
+    }
+
+    /// Wait for the offloaded encryption to hand the ciphertext back to the state
+    pub(crate) fn poll_offloaded(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        if let Some((c, buf)) = ready!(offload::poll_job(this.job, cx))? {
+            this.state.put_ciphertext(c, buf);
+        }
+        Ok(()).into()
+    }
+
+    /// Send the whole nonce before any user data
+    pub async fn write_nonce(&mut self) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let (state, mut w) = Pin::new(self).parts_mut();
+        std::future::poll_fn(|cx| state.poll_nonce(|b| w.as_mut().poll_write(cx, b))).await
+    }
+
+    /// The nonce prefixing the ciphertext
+    pub fn nonce(&self) -> &[u8] {
+        self.state.nonce()
+    }
+
+    /// Checkpoint of the encryption progress including the ciphertext not yet sent
+    ///
+    /// Incomplete while an offloaded job is in flight.
+    pub fn state(&self) -> &WriteState {
+        &self.state
+    }
+
+    pub(crate) fn parts_mut(self: Pin<&mut Self>) -> (&mut WriteState, Pin<&mut W>) {
+        let this = self.project();
+        (this.state, this.w)
+    }
+}
+impl<W: AsyncWrite> AsyncWrite for WriteHalf<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        self.poll_write_vectored(cx, &[IoSlice::new(buf)])
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize, io::Error>> {
+        ready!(self.as_mut().poll_offloaded(cx))?;
+        let this = self.project();
+        let mut w = this.w;
+        match *this.offload_threshold {
+            Some(threshold)
+                if !this.state.is_closed() && threshold < this.state.claimable(bufs) =>
+            {
+                ready!(this.state.poll_drain(|b| w.as_mut().poll_write(cx, b)))?;
+
+                // `bufs` is claimed as soon as the plaintext is copied out
+                let (c, buf, n) = this.state.take_plaintext(bufs);
+                *this.job = Some(offload::spawn(c, buf));
+                Ok(n).into()
+            }
+            _ => this
+                .state
+                .poll_vectored(bufs, |b| w.as_mut().poll_write(cx, b)),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        ready!(self.as_mut().poll_offloaded(cx))?;
+        let (state, mut w) = self.parts_mut();
+        ready!(state.poll_drain(|b| w.as_mut().poll_write(cx, b)))?;
+        w.poll_flush(cx)
     }
 
     fn poll_shutdown(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Result<(), std::io::Error>> {
-        Pin::new(&mut self.w).poll_shutdown(cx)
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), io::Error>> {
+        ready!(self.as_mut().poll_offloaded(cx))?;
+        let (state, mut w) = self.parts_mut();
+        ready!(state.poll_shutdown(|b| w.as_mut().poll_write(cx, b)))?;
+        w.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{config::tests::create_random_config, stream::ReadHalf, NONCE_BYTES};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_write_vectored() {
+        let config = create_random_config();
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = WriteHalf::new(*config.key(), client);
+        let mut server = ReadHalf::new(*config.key(), server);
+        assert!(client.is_write_vectored());
+
+        let bufs = [
+            IoSlice::new(b"Hello"),
+            IoSlice::new(b", "),
+            IoSlice::new(b"world!"),
+        ];
+        let n = client.write_vectored(&bufs).await.unwrap();
+        assert_eq!(n, 13);
+        client.flush().await.unwrap();
+        let mut buf = [0; 13];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"Hello, world!");
+    }
+
+    #[test]
+    fn test_keystream_exhausted() {
+        let config = create_random_config();
+        let mut state = WriteState::new(*config.key());
+        let mut wire = vec![];
+        let mut write = |b: &[u8]| {
+            wire.extend_from_slice(b);
+            Poll::Ready(Ok(b.len()))
+        };
+        assert!(state.poll_nonce(&mut write).is_ready());
+        let Some(ReadCursorState::UserData(c)) = &mut state.cursor else {
+            unreachable!();
+        };
+        c.seek(MAX_KEYSTREAM_BYTES - 3);
+        assert_eq!(state.remaining_keystream(), Some(3));
+
+        let Poll::Ready(res) = state.poll(b"Hello", &mut write) else {
+            unreachable!();
+        };
+        assert_eq!(res.unwrap(), 3);
+        let Poll::Ready(res) = state.poll(b"lo", &mut write) else {
+            unreachable!();
+        };
+        let e = res.unwrap_err();
+        assert_eq!(
+            Error::from_io(&e),
+            Some(&Error::KeystreamExhausted(MAX_KEYSTREAM_BYTES + 2))
+        );
+    }
+
+    async fn poll_write_once<W: AsyncWrite + Unpin>(
+        w: &mut WriteHalf<W>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        std::future::poll_fn(|cx| Poll::Ready(Pin::new(&mut *w).poll_write(cx, buf))).await
+    }
+
+    #[tokio::test]
+    async fn test_write_after_shutdown() {
+        let config = create_random_config();
+
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut client = WriteHalf::new(*config.key(), client).with_offload_threshold(0);
+        client.write_all(b"Hello").await.unwrap();
+        client.shutdown().await.unwrap();
+        assert!(client.state().is_closed());
+        let e = client.write_all(b"trailing").await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(Error::from_io(&e), Some(&Error::Closed));
+
+        let mut wire = vec![];
+        server.read_to_end(&mut wire).await.unwrap();
+        assert_eq!(wire.len(), NONCE_BYTES + 5);
+    }
+
+    #[tokio::test]
+    async fn test_fork() {
+        let config = create_random_config();
+
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut client = WriteHalf::new(*config.key(), client);
+        assert!(client.state().fork().is_none());
+        client.write_all(b"Hello").await.unwrap();
+        client.flush().await.unwrap();
+        let mut head = [0; NONCE_BYTES + 5];
+        server.read_exact(&mut head).await.unwrap();
+
+        // The mirror picks up right after the ciphertext sent so far
+        let mut mirror = client.state().fork().unwrap();
+        client.write_all(b", world!").await.unwrap();
+        client.flush().await.unwrap();
+        let mut tail = [0; 8];
+        server.read_exact(&mut tail).await.unwrap();
+        let mut ciphertext = &tail[..];
+        let mut buf = [0; 8];
+        let Poll::Ready(Ok(n)) = mirror.poll(&mut buf, |b| {
+            let n = b.len().min(ciphertext.len());
+            b[..n].copy_from_slice(&ciphertext[..n]);
+            ciphertext = &ciphertext[n..];
+            Poll::Ready(Ok(n))
+        }) else {
+            unreachable!();
+        };
+        assert_eq!(&buf[..n], b", world!");
+    }
+
+    #[tokio::test]
+    async fn test_cancel_safe() {
+        let config = create_random_config();
+
+        let (client, mut server) = tokio::io::duplex(16);
+        let mut client = WriteHalf::new(*config.key(), client);
+
+        // The nonce and part of the ciphertext fill up the pipe
+        let res = poll_write_once(&mut client, b"Hello, world!").await;
+        assert_eq!(res.map(Result::unwrap), Poll::Ready(13));
+
+        // A cancelled write must not claim any data
+        assert!(poll_write_once(&mut client, b"ignored").await.is_pending());
+
+        let read = tokio::spawn(async move {
+            let mut buf = [0; 12 + 16];
+            server.read_exact(&mut buf).await.unwrap();
+            buf
+        });
+        client.write_all(b"Bye").await.unwrap();
+        client.flush().await.unwrap();
+
+        let buf = read.await.unwrap();
+        let mut server = ReadHalf::new(*config.key(), buf.as_slice());
+        let mut plaintext = vec![];
+        server.read_to_end(&mut plaintext).await.unwrap();
+        assert_eq!(plaintext, b"Hello, world!Bye");
+    }
+
+    #[tokio::test]
+    async fn test_flush_nonce() {
+        let config = create_random_config();
+
+        let (client, mut server) = tokio::io::duplex(4);
+        let mut client = WriteHalf::new(*config.key(), client);
+        let nonce = client.nonce().to_vec();
+
+        // Only part of the nonce fits in the pipe
+        assert!(poll_write_once(&mut client, b"ignored").await.is_pending());
+
+        let read = tokio::spawn(async move {
+            let mut buf = vec![];
+            server.read_to_end(&mut buf).await.unwrap();
+            buf
+        });
+        client.shutdown().await.unwrap();
+        drop(client);
+
+        let buf = read.await.unwrap();
+        assert_eq!(buf.len(), NONCE_BYTES);
+        assert_eq!(buf, nonce);
+        let mut server = ReadHalf::new(*config.key(), buf.as_slice());
+        assert!(server.nonce().is_none());
+        let mut plaintext = vec![];
+        server.read_to_end(&mut plaintext).await.unwrap();
+        assert!(plaintext.is_empty());
+        assert_eq!(server.nonce(), Some(nonce.as_slice()));
+    }
+
+    #[tokio::test]
+    async fn test_buf_capacity() {
+        let config = create_random_config();
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = WriteHalf::new(*config.key(), client).with_buf_capacity(10);
+        let mut server = ReadHalf::new(*config.key(), server);
+
+        let bufs = [IoSlice::new(b"Hello, "), IoSlice::new(b"world!")];
+        assert_eq!(client.write_vectored(&bufs).await.unwrap(), 10);
+        client.write_all(b"ld!").await.unwrap();
+        client.flush().await.unwrap();
+        assert!(client.state.buf.capacity() <= 10);
+
+        let mut buf = [0; 13];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"Hello, world!");
+    }
+
+    #[tokio::test]
+    async fn test_offload() {
+        let config = create_random_config();
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = WriteHalf::new(*config.key(), client)
+            .with_buf_capacity(1024 * 1024)
+            .with_offload_threshold(1024);
+        let mut server = ReadHalf::new(*config.key(), server);
+
+        let data: Vec<u8> = (0..=u8::MAX).cycle().take(1024 * 1024 + 1).collect();
+        let write = tokio::spawn(async move {
+            client.write_all(&data).await.unwrap();
+            client.shutdown().await.unwrap();
+            data
+        });
+        let mut plaintext = vec![];
+        server.read_to_end(&mut plaintext).await.unwrap();
+        assert_eq!(plaintext, write.await.unwrap());
+    }
+
+    #[cfg(feature = "nonce-tracking")]
+    #[test]
+    #[should_panic(expected = "nonce reused under the same key")]
+    fn test_nonce_reuse() {
+        let config = create_random_config();
+        let nonce = rand::random();
+        let _ = WriteState::from_cursor(NonceReadCursor::from_nonce(*config.key(), nonce));
+        let _ = WriteState::from_cursor(NonceReadCursor::from_nonce(*config.key(), nonce));
+    }
+
+    #[cfg(feature = "serde")]
+    #[tokio::test]
+    async fn test_checkpoint() {
+        let config = create_random_config();
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = WriteHalf::new(*config.key(), client);
+        let mut server = ReadHalf::new(*config.key(), server);
+
+        client.write_all(b"hello ").await.unwrap();
+        client.flush().await.unwrap();
+        let mut buf = [0; 6];
+        server.read_exact(&mut buf).await.unwrap();
+
+        // Resume from a checkpoint over the same transport
+        let checkpoint = serde_json::to_string(client.state()).unwrap();
+        let state: WriteState = serde_json::from_str(&checkpoint).unwrap();
+        let mut client = WriteHalf::from_state(state, client.w);
+
+        client.write_all(b"world").await.unwrap();
+        client.flush().await.unwrap();
+        let mut buf = [0; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
     }
 }
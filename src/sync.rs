@@ -0,0 +1,234 @@
+//! Blocking counterparts of [`crate::stream`] in the same wire format
+
+use std::{
+    io::{self, BufRead, Read, Write},
+    task::Poll,
+};
+
+use crate::{
+    cursor::DecryptCursor,
+    stream::{Error, ReadState, WriteState},
+    KEY_BYTES,
+};
+
+const DEFAULT_BUF_BYTES: usize = 8 * 1024;
+
+#[derive(Debug)]
+pub struct ReadHalf<R> {
+    state: ReadState,
+    r: R,
+}
+impl<R> ReadHalf<R> {
+    pub fn new(key: [u8; KEY_BYTES], r: R) -> Self {
+        let state = ReadState::new(key);
+        Self { state, r }
+    }
+    pub fn new_x(key: [u8; KEY_BYTES], r: R) -> Self {
+        let state = ReadState::new_x(key);
+        Self { state, r }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.r
+    }
+}
+impl<R: Read> Read for ReadHalf<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Self { state, r } = self;
+        match state.poll(buf, |b| Poll::Ready(r.read(b))) {
+            Poll::Ready(res) => res,
+            Poll::Pending => unreachable!(),
+        }
+    }
+}
+
+/// Buffered decrypting reader built on [`DecryptCursor`]
+#[derive(Debug)]
+pub struct BufReadHalf<R> {
+    de: DecryptCursor,
+    r: R,
+    buf: Vec<u8>,
+    pos: usize,
+    filled: usize,
+}
+impl<R> BufReadHalf<R> {
+    pub fn new(key: [u8; KEY_BYTES], r: R) -> Self {
+        Self::from_cursor(DecryptCursor::new(key), r)
+    }
+    pub fn new_x(key: [u8; KEY_BYTES], r: R) -> Self {
+        Self::from_cursor(DecryptCursor::new_x(key), r)
+    }
+    /// E.g. with the cursor of [`Config::decrypt_cursor`](crate::config::Config::decrypt_cursor)
+    pub fn from_cursor(de: DecryptCursor, r: R) -> Self {
+        Self {
+            de,
+            r,
+            buf: vec![0; DEFAULT_BUF_BYTES],
+            pos: 0,
+            filled: 0,
+        }
+    }
+
+    /// Read at most `bytes` of ciphertext from the inner reader at a time
+    pub fn with_buf_capacity(mut self, bytes: usize) -> Self {
+        assert!(bytes > 0);
+        self.buf = vec![0; bytes];
+        self
+    }
+
+    pub fn into_inner(self) -> R {
+        self.r
+    }
+}
+impl<R: Read> BufRead for BufReadHalf<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        while self.pos == self.filled {
+            let n = self.r.read(&mut self.buf)?;
+            if n == 0 {
+                // The reader hits EOF
+                let remaining = self.de.remaining_nonce_size().map_err(Error::Cursor)?;
+                if remaining != 0 && self.de.bytes_consumed() != 0 {
+                    return Err(Error::NonceTruncated.into());
+                }
+                break;
+            }
+            if let Some(i) = self.de.decrypt(&mut self.buf[..n]).map_err(Error::Cursor)? {
+                self.pos = i;
+                self.filled = n;
+            }
+        }
+        Ok(&self.buf[self.pos..self.filled])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.filled);
+    }
+}
+impl<R: Read> Read for BufReadHalf<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+#[derive(Debug)]
+pub struct WriteHalf<W> {
+    state: WriteState,
+    w: W,
+}
+impl<W> WriteHalf<W> {
+    pub fn new(key: [u8; KEY_BYTES], w: W) -> Self {
+        let state = WriteState::new(key);
+        Self { state, w }
+    }
+    pub fn new_x(key: [u8; KEY_BYTES], w: W) -> Self {
+        let state = WriteState::new_x(key);
+        Self { state, w }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+}
+impl<W: Write> Write for WriteHalf<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Self { state, w } = self;
+        match state.poll(buf, |b| Poll::Ready(w.write(b))) {
+            Poll::Ready(res) => res,
+            Poll::Pending => unreachable!(),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let Self { state, w } = self;
+        match state.poll_drain(|b| Poll::Ready(w.write(b))) {
+            Poll::Ready(res) => res?,
+            Poll::Pending => unreachable!(),
+        }
+        w.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::config::tests::create_random_config;
+
+    use super::*;
+
+    #[test]
+    fn test_sync() {
+        let config = create_random_config();
+        let data = b"Hello, world!";
+
+        let mut w = WriteHalf::new_x(*config.key(), vec![]);
+        for _ in 0..64 {
+            w.write_all(data).unwrap();
+        }
+        let ciphertext = w.into_inner();
+
+        let mut r = ReadHalf::new_x(*config.key(), ciphertext.as_slice());
+        let mut plaintext = vec![];
+        r.read_to_end(&mut plaintext).unwrap();
+        assert_eq!(plaintext, data.repeat(64));
+    }
+
+    #[test]
+    fn test_buf_read() {
+        let config = create_random_config();
+        let data = b"Hello, world!\nBye\n";
+
+        let mut w = WriteHalf::new(*config.key(), vec![]);
+        w.write_all(data).unwrap();
+        let ciphertext = w.into_inner();
+
+        // The nonce is split across reads
+        let mut r = BufReadHalf::new(*config.key(), ciphertext.as_slice()).with_buf_capacity(5);
+        let lines: Vec<String> = r.by_ref().lines().map(Result::unwrap).collect();
+        assert_eq!(lines, ["Hello, world!", "Bye"]);
+        assert!(r.fill_buf().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_nonce_truncated() {
+        let config = create_random_config();
+
+        let mut r = ReadHalf::new(*config.key(), &[][..]);
+        assert_eq!(r.read(&mut [0; 16]).unwrap(), 0);
+        let mut r = BufReadHalf::new(*config.key(), &[][..]);
+        assert!(r.fill_buf().unwrap().is_empty());
+
+        let mut r = ReadHalf::new(*config.key(), &[0; 5][..]);
+        let err = r.read(&mut [0; 16]).unwrap_err();
+        assert_eq!(Error::from_io(&err), Some(&Error::NonceTruncated));
+        let mut r = BufReadHalf::new(*config.key(), &[0; 5][..]);
+        let err = r.fill_buf().unwrap_err();
+        assert_eq!(Error::from_io(&err), Some(&Error::NonceTruncated));
+    }
+
+    #[tokio::test]
+    async fn test_interop() {
+        let config = create_random_config();
+        let data = b"Hello, world!";
+
+        let mut w = WriteHalf::new(*config.key(), vec![]);
+        w.write_all(data).unwrap();
+        let ciphertext = w.into_inner();
+        let mut r = crate::stream::ReadHalf::new(*config.key(), ciphertext.as_slice());
+        let mut plaintext = vec![];
+        r.read_to_end(&mut plaintext).await.unwrap();
+        assert_eq!(plaintext, data);
+
+        let mut ciphertext = vec![];
+        let mut w = crate::stream::WriteHalf::new(*config.key(), &mut ciphertext);
+        w.write_all(data).await.unwrap();
+        let mut r = ReadHalf::new(*config.key(), ciphertext.as_slice());
+        let mut plaintext = vec![];
+        Read::read_to_end(&mut r, &mut plaintext).unwrap();
+        assert_eq!(plaintext, data);
+    }
+}